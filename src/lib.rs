@@ -5,8 +5,11 @@ extern crate glib;
 #[macro_use]
 extern crate gstreamer as gst;
 use gst::prelude::*;
+use gst_base::prelude::*;
 extern crate gstreamer_audio as gst_audio;
 extern crate gstreamer_base as gst_base;
+#[cfg(feature = "v1_14")]
+extern crate gstreamer_sys as gst_ffi;
 extern crate gstreamer_video as gst_video;
 
 #[macro_use]
@@ -15,15 +18,28 @@ extern crate byte_slice_cast;
 
 mod ndiaudiosrc;
 pub mod ndisys;
+mod nditest;
+mod ndiutil;
 mod ndivideosrc;
 
 // use gst_plugin::base_src::*;
+use glib::gobject_ffi;
+use glib::translate::{from_glib, ToGlibPtr, ToGlibPtrMut};
 use ndisys::*;
+use nditest::{is_test_source, TEST_SOURCE};
+use std::cmp;
 use std::ffi::{CStr, CString};
-use std::{thread, time};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time;
+use std::{env, fs, mem, ptr};
 
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
 
 use gst::GstObjectExt;
 
@@ -33,14 +49,31 @@ fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
     Ok(())
 }
 
+// A receiver elements capture from, behind NdiReceiver so tests can script it
+type SharedReceiver = Arc<RwLock<Box<dyn NdiReceiver + Send + Sync>>>;
+
 struct ndi_receiver_info {
     stream_name: String,
     ip: String,
     video: bool,
     audio: bool,
-    ndi_instance: NdiInstance,
-    initial_timestamp: u64,
-    id: i8,
+    receive_video: bool,
+    // Created with allow-video-fields, which video elements must agree on
+    allow_video_fields: bool,
+    // Not shared with other elements, e.g. because a frame-sync captures from it
+    exclusive: bool,
+    source_index: i32,
+    // How the source was picked among those sharing its name
+    select_policy: String,
+    // The NDI groups the source was looked for in
+    groups: String,
+    ndi_instance: SharedReceiver,
+    initial_timestamp: Arc<AtomicU64>,
+    // The same for elements with timestamp-mode=timecode
+    initial_timecode: Arc<AtomicU64>,
+    // Manufacturer and model from the sender's ndi_product metadata
+    product: Arc<Mutex<Option<(String, String)>>>,
+    id: usize,
 }
 
 struct Ndi {
@@ -51,24 +84,83 @@ static mut ndi_struct: Ndi = Ndi {
     start_pts: gst::ClockTime(Some(0)),
 };
 
+// Locking order: an element's own settings/state mutexes, then `hashmap_receivers`,
+// then the receiver's `ndi_instance` lock. Never lock `hashmap_receivers` while
+// holding an `ndi_instance` lock: stop_ndi() removes the receiver from the map and
+// then waits on the write lock until every in-flight capture has released its read
+// lock.
 lazy_static! {
-    static ref hashmap_receivers: Mutex<HashMap<i8, ndi_receiver_info>> = {
+    static ref hashmap_receivers: Mutex<HashMap<usize, ndi_receiver_info>> = {
         let m = HashMap::new();
         Mutex::new(m)
     };
 }
 
-static mut id_receiver: i8 = 0;
+// The last receiver id handed out. 0 stands for no receiver.
+static id_receiver: AtomicUsize = AtomicUsize::new(0);
+
+// The NDI library is initialized once for the process and destroyed once, while
+// receivers, source discovery and the test sender come and go independently.
+// Each of them takes a reference with ndi_acquire() and gives it back with
+// ndi_release(), so the library is only torn down after the last one. The
+// `ndi_users` lock is taken last and never held while taking another lock.
+lazy_static! {
+    static ref ndi_users: Mutex<u32> = Mutex::new(0);
+}
+
+fn ndi_acquire() -> bool {
+    let mut users = ndi_users.lock().unwrap_or_else(PoisonError::into_inner);
+    if *users == 0 && unsafe { !NDIlib_initialize() } {
+        return false;
+    }
+    *users += 1;
+    true
+}
+
+fn ndi_release() {
+    let mut users = ndi_users.lock().unwrap_or_else(PoisonError::into_inner);
+    match *users {
+        0 => (),
+        1 => {
+            unsafe { NDIlib_destroy() };
+            *users = 0;
+        }
+        _ => *users -= 1,
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
 fn connect_ndi(
     cat: gst::DebugCategory,
     element: &gst_base::BaseSrc,
     ip: &str,
     stream_name: &str,
-) -> i8 {
+    receiver_name: &str,
+    allow_video_fields: bool,
+    prefer_speed: bool,
+    select_policy: &str,
+    source_index: i32,
+    failover_source: &str,
+    groups: &str,
+    discovery_timeout: u32,
+    bind_address: &str,
+    config_path: &str,
+    receive_video: bool,
+    exclusive: bool,
+) -> usize {
     gst_debug!(cat, obj: element, "Starting NDI connection...");
 
-    let mut receivers = hashmap_receivers.lock().unwrap();
+    // With only an address there's nothing to look up, so NDI connects straight
+    // to it without waiting for discovery
+    let direct = stream_name.is_empty() && !ip.is_empty();
+    let direct_ip;
+    let ip = if direct {
+        direct_ip = direct_source_address(ip);
+        &direct_ip[..]
+    } else {
+        ip
+    };
+
     let mut audio = false;
     let mut video = false;
 
@@ -82,22 +174,72 @@ fn connect_ndi(
         video = true;
     }
 
-    for val in receivers.values_mut() {
-        if val.ip == ip || val.stream_name == stream_name {
-            if (val.audio && val.video) || (val.audio && audio) || (val.video && video) {
-                continue;
+    // Another element connecting to the same source shares its receiver
+    let shared_receiver = |receivers: &mut HashMap<usize, ndi_receiver_info>| {
+        for val in receivers.values_mut() {
+            // Another policy may have picked a different one of the sources
+            // sharing the name
+            let same_source = if source_index >= 0 {
+                source_matches(&val.stream_name, &val.ip, stream_name, ip)
+            } else if select_policy == "match-ip" {
+                val.ip == ip
             } else {
-                if video {
-                    val.video = video;
+                source_matches(&val.stream_name, &val.ip, stream_name, ip)
+                    && val.select_policy == select_policy
+            };
+            if same_source && val.source_index == source_index && !val.exclusive && !exclusive {
+                // One audio and one video element can share a receiver
+                let taken = if video { val.video } else { val.audio };
+                let unfit =
+                    video && (!val.receive_video || val.allow_video_fields != allow_video_fields);
+                if taken || unfit {
+                    continue;
                 } else {
-                    val.audio = audio;
+                    if video {
+                        val.video = video;
+                    } else {
+                        val.audio = audio;
+                    }
+                    return Some(val.id);
                 }
-                return val.id;
             }
         }
+        None
+    };
+    // The receivers aren't locked while the source is discovered, so they're
+    // checked again for an element that connected meanwhile before creating one
+    if let Some(id) = shared_receiver(
+        &mut hashmap_receivers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner),
+    ) {
+        return id;
+    }
+    if !config_path.is_empty() {
+        if let Err(err) = configure_config_path(config_path) {
+            gst_warning!(
+                cat,
+                obj: element,
+                "Can't configure NDI from {}: {}",
+                config_path,
+                err
+            );
+        }
+    }
+    if !bind_address.is_empty() {
+        if let Err(err) = configure_bind_address(cat, element, bind_address) {
+            gst_warning!(
+                cat,
+                obj: element,
+                "Can't bind NDI to {}: {}",
+                bind_address,
+                err
+            );
+        }
     }
+
     unsafe {
-        if !NDIlib_initialize() {
+        if !ndi_acquire() {
             gst_element_error!(
                 element,
                 gst::CoreError::Negotiation,
@@ -106,83 +248,185 @@ fn connect_ndi(
             return 0;
         }
 
-        let NDI_find_create_desc: NDIlib_find_create_t = Default::default();
-        let pNDI_find = NDIlib_find_create_v2(&NDI_find_create_desc);
-        if pNDI_find.is_null() {
-            gst_element_error!(
-                element,
-                gst::CoreError::Negotiation,
-                ["Cannot run NDI: NDIlib_find_create_v2 error"]
+        let direct_address = CString::new(ip).unwrap();
+        let found_name;
+        let found_address;
+        let source = if direct {
+            gst_debug!(
+                cat,
+                obj: element,
+                "Connecting directly to NDI source at '{}'",
+                ip
             );
-            return 0;
-        }
+            // Without a name, NDI connects to the address as it is
+            NDIlib_source_t {
+                p_ndi_name: ptr::null(),
+                p_ip_address: direct_address.as_ptr(),
+            }
+        } else {
+            let is_candidate = |name: &str, address: &str| {
+                if select_policy == "match-ip" {
+                    address == ip
+                } else {
+                    source_matches(name, address, stream_name, ip)
+                }
+            };
+            // The first source found is all the default policy needs
+            let stop_early = select_policy == "first" && source_index < 0;
+            let sources =
+                match discover_sources(groups, discovery_timeout, stop_early, is_candidate) {
+                    Some(sources) => sources,
+                    None => {
+                        gst_element_error!(
+                            element,
+                            gst::CoreError::Negotiation,
+                            ["Cannot run NDI: NDIlib_find_create_v2 error"]
+                        );
+                        ndi_release();
+                        return 0;
+                    }
+                };
 
-        let mut total_sources: u32 = 0;
-        let p_sources;
+            let mut candidates = Vec::new();
+            for (i, (name, address)) in sources.iter().enumerate() {
+                if is_candidate(name, address) {
+                    gst_debug!(
+                        cat,
+                        obj: element,
+                        "Candidate NDI source with name '{}' and address '{}'",
+                        name,
+                        address
+                    );
+                    candidates.push((i, address.clone()));
+                }
+            }
 
-        // TODO Sleep 1s to wait for all sources
-        thread::sleep(time::Duration::from_millis(2000));
-        p_sources = NDIlib_find_get_current_sources(pNDI_find, &mut total_sources as *mut u32);
+            if candidates.is_empty() && !failover_source.is_empty() {
+                gst_warning!(
+                    cat,
+                    obj: element,
+                    "NDI source not found, failing over to '{}'",
+                    failover_source
+                );
+                candidates = sources
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (name, _))| name == failover_source)
+                    .map(|(i, (_, address))| (i, address.clone()))
+                    .collect();
+            }
 
-        // We need at least one source
-        if p_sources.is_null() {
-            gst_element_error!(
-                element,
-                gst::CoreError::Negotiation,
-                ["Error getting NDIlib_find_get_current_sources"]
+            let no_source = if source_index >= 0 {
+                // Sources sharing a name differ in their address, which is
+                // also what sets their URLs apart
+                candidates.sort_by_key(|(_, address)| address.clone());
+                if !candidates.is_empty() && source_index as usize >= candidates.len() {
+                    gst_element_error!(
+                        element,
+                        gst::ResourceError::Settings,
+                        [
+                            "source-index {} is out of range, only {} sources match",
+                            source_index,
+                            candidates.len()
+                        ]
+                    );
+                    ndi_release();
+                    return 0;
+                }
+                candidates.get(source_index as usize).map(|&(i, _)| i)
+            } else {
+                match select_policy {
+                    "lowest-ip" => candidates
+                        .into_iter()
+                        .min_by_key(|(_, address)| {
+                            let source_ip = parse_source_ip(address);
+                            (source_ip.is_none(), source_ip, address.clone())
+                        })
+                        .map(|(i, _)| i),
+                    _ => candidates.into_iter().next().map(|(i, _)| i),
+                }
+            };
+            let no_source = match no_source {
+                Some(no_source) => no_source,
+                None => {
+                    if groups.is_empty() {
+                        gst_element_error!(
+                            element,
+                            gst::ResourceError::OpenRead,
+                            ("Stream not found"),
+                            ["Sources restricted to NDI groups are only found with groups set"]
+                        );
+                    } else {
+                        gst_element_error!(
+                            element,
+                            gst::ResourceError::OpenRead,
+                            ("Stream not found"),
+                            ["Looked in the NDI groups '{}'", groups]
+                        );
+                    }
+                    ndi_release();
+                    return 0;
+                }
+            };
+
+            let (ref name, ref address) = sources[no_source];
+            gst_debug!(
+                cat,
+                obj: element,
+                "Total sources in network {}: Connecting to NDI source with name '{}' and address '{}'",
+                sources.len(),
+                name,
+                address
             );
-            return 0;
-        }
 
-        let mut no_source: isize = -1;
-        for i in 0..total_sources as isize {
-            if CStr::from_ptr((*p_sources.offset(i)).p_ndi_name)
-                .to_string_lossy()
-                .into_owned()
-                == stream_name
-                || CStr::from_ptr((*p_sources.offset(i)).p_ip_address)
-                    .to_string_lossy()
-                    .into_owned()
-                    == ip
-            {
-                no_source = i;
-                break;
+            found_name = CString::new(name.as_str()).unwrap();
+            found_address = CString::new(address.as_str()).unwrap();
+            NDIlib_source_t {
+                p_ndi_name: found_name.as_ptr(),
+                p_ip_address: found_address.as_ptr(),
             }
-        }
-        if no_source == -1 {
-            gst_element_error!(element, gst::ResourceError::OpenRead, ["Stream not found"]);
-            return 0;
-        }
-
-        gst_debug!(
-            cat,
-            obj: element,
-            "Total sources in network {}: Connecting to NDI source with name '{}' and address '{}'",
-            total_sources,
-            CStr::from_ptr((*p_sources.offset(no_source)).p_ndi_name)
-                .to_string_lossy()
-                .into_owned(),
-            CStr::from_ptr((*p_sources.offset(no_source)).p_ip_address)
-                .to_string_lossy()
-                .into_owned()
-        );
-
-        let source = *p_sources.offset(no_source);
+        };
 
         let source_ip = CStr::from_ptr(source.p_ip_address)
             .to_string_lossy()
             .into_owned();
-        let source_name = CStr::from_ptr(source.p_ndi_name)
-            .to_string_lossy()
-            .into_owned();
+        // The name of a source connected to directly isn't known
+        let source_name = if direct {
+            String::new()
+        } else {
+            CStr::from_ptr(source.p_ndi_name)
+                .to_string_lossy()
+                .into_owned()
+        };
 
-        let p_ndi_name = CString::new("Galicaster NDI Receiver").unwrap();
+        let p_ndi_name = CString::new(receiver_name).unwrap();
         let NDI_recv_create_desc = NDIlib_recv_create_v3_t {
             source_to_connect_to: source,
             p_ndi_name: p_ndi_name.as_ptr(),
-            ..Default::default()
+            allow_video_fields,
+            // Fastest lets NDI hand over frames without converting them, best
+            // keeps 16-bit sources in P216/PA16 for us to convert
+            color_format: if prefer_speed {
+                NDIlib_recv_color_format_e::NDIlib_recv_color_format_fastest
+            } else {
+                NDIlib_recv_color_format_e::NDIlib_recv_color_format_best
+            },
+            // NDI can leave out video, but always sends audio along with it
+            bandwidth: if receive_video {
+                NDIlib_recv_bandwidth_e::NDIlib_recv_bandwidth_highest
+            } else {
+                NDIlib_recv_bandwidth_e::NDIlib_recv_bandwidth_audio_only
+            },
         };
 
+        let mut receivers = hashmap_receivers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(id) = shared_receiver(&mut receivers) {
+            ndi_release();
+            return id;
+        }
+
         let pNDI_recv = NDIlib_recv_create_v3(&NDI_recv_create_desc);
         if pNDI_recv.is_null() {
             gst_element_error!(
@@ -190,14 +434,16 @@ fn connect_ndi(
                 gst::CoreError::Negotiation,
                 ["Cannot run NDI: NDIlib_recv_create_v3 error"]
             );
+            ndi_release();
             return 0;
         }
 
-        NDIlib_find_destroy(pNDI_find);
-
         let tally_state: NDIlib_tally_t = Default::default();
         NDIlib_recv_set_tally(pNDI_recv, &tally_state);
 
+        // Keeps watching the source's groups for eos-on-disconnect
+        let finder = FindInstance::new(groups);
+
         let data = CString::new("<ndi_hwaccel enabled=\"true\"/>").unwrap();
         let enable_hw_accel = NDIlib_metadata_frame_t {
             length: data.to_bytes().len() as i32,
@@ -207,48 +453,1234 @@ fn connect_ndi(
 
         NDIlib_recv_send_metadata(pNDI_recv, &enable_hw_accel);
 
-        id_receiver += 1;
-        receivers.insert(
-            id_receiver,
+        let id = add_receiver(
+            &mut receivers,
             ndi_receiver_info {
                 stream_name: source_name.clone(),
                 ip: source_ip.clone(),
                 video,
                 audio,
-                ndi_instance: NdiInstance { recv: pNDI_recv },
-                initial_timestamp: 0,
-                id: id_receiver,
+                receive_video,
+                allow_video_fields,
+                exclusive,
+                source_index,
+                select_policy: select_policy.to_string(),
+                groups: groups.to_string(),
+                ndi_instance: Arc::new(RwLock::new(Box::new(RecvInstance::new(pNDI_recv, finder)))),
+                initial_timestamp: Arc::new(AtomicU64::new(0)),
+                initial_timecode: Arc::new(AtomicU64::new(0)),
+                product: Arc::new(Mutex::new(None)),
+                id: 0,
             },
         );
 
         gst_debug!(cat, obj: element, "Started NDI connection");
-        id_receiver
+        id
     }
 }
 
-fn stop_ndi(cat: gst::DebugCategory, element: &gst_base::BaseSrc, id: i8) -> bool {
-    gst_debug!(cat, obj: element, "Closing NDI connection...");
-    let mut receivers = hashmap_receivers.lock().unwrap();
+// Adds a new receiver to the map and returns the id elements find it with
+fn add_receiver(
+    receivers: &mut HashMap<usize, ndi_receiver_info>,
+    mut receiver: ndi_receiver_info,
+) -> usize {
+    let id = loop {
+        let id = id_receiver.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+        if id != 0 && !receivers.contains_key(&id) {
+            break id;
+        }
+    };
+    receiver.id = id;
+    receivers.insert(id, receiver);
+    id
+}
+
+// Nicks of the select-policy enum values, in the order of their values
+const SELECT_POLICIES: [&str; 3] = ["first", "lowest-ip", "match-ip"];
+
+lazy_static! {
+    // The GEnum type of the select-policy property. glib keeps pointers to the
+    // values for good, so they're leaked.
+    static ref select_policy_type: glib::Type = unsafe {
+        let values = Box::leak(Box::new([
+            gobject_ffi::GEnumValue {
+                value: 0,
+                value_name: b"First source found\0".as_ptr() as *const _,
+                value_nick: b"first\0".as_ptr() as *const _,
+            },
+            gobject_ffi::GEnumValue {
+                value: 1,
+                value_name: b"Source with the lowest IP address\0".as_ptr() as *const _,
+                value_nick: b"lowest-ip\0".as_ptr() as *const _,
+            },
+            gobject_ffi::GEnumValue {
+                value: 2,
+                value_name: b"Source at the ip address, whatever its name\0".as_ptr() as *const _,
+                value_nick: b"match-ip\0".as_ptr() as *const _,
+            },
+            gobject_ffi::GEnumValue {
+                value: 0,
+                value_name: ptr::null(),
+                value_nick: ptr::null(),
+            },
+        ]));
+        from_glib(gobject_ffi::g_enum_register_static(
+            b"GstNdiSelectPolicy\0".as_ptr() as *const _,
+            values.as_ptr(),
+        ))
+    };
+}
+
+fn select_policy_param_spec(blurb: &str) -> glib::ParamSpec {
+    glib::ParamSpec::enum_(
+        "select-policy",
+        "Select Policy",
+        blurb,
+        *select_policy_type,
+        0,
+        glib::ParamFlags::READWRITE,
+    )
+}
+
+// The nick of a select-policy enum value, None for values it doesn't have
+fn select_policy_from_value(value: &glib::Value) -> Option<&'static str> {
+    let value = unsafe { gobject_ffi::g_value_get_enum(value.to_glib_none().0) };
+    SELECT_POLICIES.get(value as usize).cloned()
+}
+
+fn select_policy_to_value(select_policy: &str) -> glib::Value {
+    let index = SELECT_POLICIES
+        .iter()
+        .position(|&nick| nick == select_policy)
+        .unwrap_or(0);
+    let mut value = glib::Value::from_type(*select_policy_type);
+    unsafe { gobject_ffi::g_value_set_enum(value.to_glib_none_mut().0, index as i32) };
+    value
+}
+
+const TIMESTAMP_MODES: [&str; 2] = ["receive-timestamp", "timecode"];
+
+lazy_static! {
+    // The GEnum type of the timestamp-mode property, leaked like the
+    // select-policy one
+    static ref timestamp_mode_type: glib::Type = unsafe {
+        let values = Box::leak(Box::new([
+            gobject_ffi::GEnumValue {
+                value: 0,
+                value_name: b"Time the receiver got the frame\0".as_ptr() as *const _,
+                value_nick: b"receive-timestamp\0".as_ptr() as *const _,
+            },
+            gobject_ffi::GEnumValue {
+                value: 1,
+                value_name: b"Timecode the sender gave the frame\0".as_ptr() as *const _,
+                value_nick: b"timecode\0".as_ptr() as *const _,
+            },
+            gobject_ffi::GEnumValue {
+                value: 0,
+                value_name: ptr::null(),
+                value_nick: ptr::null(),
+            },
+        ]));
+        from_glib(gobject_ffi::g_enum_register_static(
+            b"GstNdiTimestampMode\0".as_ptr() as *const _,
+            values.as_ptr(),
+        ))
+    };
+}
+
+fn timestamp_mode_param_spec(blurb: &str) -> glib::ParamSpec {
+    glib::ParamSpec::enum_(
+        "timestamp-mode",
+        "Timestamp Mode",
+        blurb,
+        *timestamp_mode_type,
+        0,
+        glib::ParamFlags::READWRITE,
+    )
+}
+
+// The nick of a timestamp-mode enum value, None for values it doesn't have
+fn timestamp_mode_from_value(value: &glib::Value) -> Option<&'static str> {
+    let value = unsafe { gobject_ffi::g_value_get_enum(value.to_glib_none().0) };
+    TIMESTAMP_MODES.get(value as usize).cloned()
+}
+
+fn timestamp_mode_to_value(timestamp_mode: &str) -> glib::Value {
+    let index = TIMESTAMP_MODES
+        .iter()
+        .position(|&nick| nick == timestamp_mode)
+        .unwrap_or(0);
+    let mut value = glib::Value::from_type(*timestamp_mode_type);
+    unsafe { gobject_ffi::g_value_set_enum(value.to_glib_none_mut().0, index as i32) };
+    value
+}
+
+// Elements connecting within this many seconds of a discovery reuse its sources
+const DISCOVERY_CACHE_SECS: u64 = 10;
+
+struct DiscoveredSources {
+    time: time::Instant,
+    sources: Vec<(String, String)>,
+}
+
+// The sources last found in each set of NDI groups, "" for the default ones
+lazy_static! {
+    static ref discovered_sources: Mutex<HashMap<String, DiscoveredSources>> =
+        Mutex::new(HashMap::new());
+}
+
+// The groups a discovery is running for, so elements starting together wait
+// for one discovery instead of each doing their own. Nothing else is locked
+// meanwhile, the end of a discovery is signalled on `discovery_done`.
+lazy_static! {
+    static ref discoveries_running: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    static ref discovery_done: Condvar = Condvar::new();
+}
+
+// Drops all discovered sources, for when a source went away
+fn forget_discovered_sources() {
+    discovered_sources
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+}
+
+// Names and addresses of the sources in `groups`. A recent discovery is reused
+// if `wanted` accepts one of its sources, otherwise the finder waits for up to
+// `timeout_in_ms`, and with `stop_early` only until `wanted` accepts a source.
+// None if there's no finder. The caller must hold a reference to NDI.
+unsafe fn discover_sources<F>(
+    groups: &str,
+    timeout_in_ms: u32,
+    stop_early: bool,
+    wanted: F,
+) -> Option<Vec<(String, String)>>
+where
+    F: Fn(&str, &str) -> bool,
+{
+    let is_wanted =
+        |sources: &[(String, String)]| sources.iter().any(|(name, address)| wanted(name, address));
+
+    {
+        let mut running = discoveries_running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        while running.contains(groups) {
+            running = discovery_done
+                .wait(running)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if let Some(cached) = discovered_sources
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(groups)
+        {
+            if cached.time.elapsed() < time::Duration::from_secs(DISCOVERY_CACHE_SECS)
+                && is_wanted(&cached.sources)
+            {
+                return Some(cached.sources.clone());
+            }
+        }
+        running.insert(groups.to_owned());
+    }
+
+    // Sources restricted to groups are only found by looking for them in those
+    // groups
+    let sources = FindInstance::new(groups).map(|finder| {
+        let deadline = time::Instant::now() + time::Duration::from_millis(u64::from(timeout_in_ms));
+        let mut sources = finder.current_sources();
+        while !(stop_early && is_wanted(&sources)) {
+            let now = time::Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let remaining = deadline - now;
+            // Returns as soon as the sources changed
+            finder.wait_for_sources(remaining.as_secs() as u32 * 1000 + remaining.subsec_millis());
+            sources = finder.current_sources();
+        }
+        sources
+    });
+
+    if let Some(ref sources) = sources {
+        discovered_sources
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                groups.to_owned(),
+                DiscoveredSources {
+                    time: time::Instant::now(),
+                    sources: sources.clone(),
+                },
+            );
+    }
+    discoveries_running
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(groups);
+    discovery_done.notify_all();
+    sources
+}
+
+// Also refreshes the discovered sources in the default groups, so a source
+// that appeared or went away is seen by the next element connecting
+fn find_sources() -> Vec<(String, String)> {
+    let mut sources = Vec::new();
+    unsafe {
+        if !ndi_acquire() {
+            return sources;
+        }
+
+        if let Some(finder) = FindInstance::new("") {
+            finder.wait_for_sources(1000);
+            sources = finder.current_sources();
+        }
+        ndi_release();
+    }
+
+    discovered_sources
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(
+            String::new(),
+            DiscoveredSources {
+                time: time::Instant::now(),
+                sources: sources.clone(),
+            },
+        );
+    sources
+}
+
+lazy_static! {
+    // The configuration directories generated for bind addresses, reused when
+    // an element binds to the same address again
+    static ref bind_address_dirs: Mutex<HashMap<String, PathBuf>> = Mutex::new(HashMap::new());
+}
+
+// The SDK picks its network interfaces from the ndi-config.v1.json in
+// NDI_CONFIG_DIR when it's initialized, for the whole process
+fn configure_bind_address(
+    cat: gst::DebugCategory,
+    element: &gst_base::BaseSrc,
+    bind_address: &str,
+) -> Result<(), String> {
+    if bind_address.parse::<IpAddr>().is_err() {
+        return Err(format!("'{}' is not an IP address", bind_address));
+    }
+
+    // NDI_CONFIG_DIR only changes while nobody uses NDI, so the SDK can't be
+    // reading it at the same time
+    let users = ndi_users.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut dirs = bind_address_dirs
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let current = env::var_os("NDI_CONFIG_DIR");
+    if let Some(ref current) = current {
+        if dirs.get(bind_address).map(|dir| dir.as_os_str()) == Some(current.as_os_str()) {
+            return Ok(());
+        }
+        // A configuration of the user's own wins, it may allow the address
+        // already or have reasons not to
+        if !dirs
+            .values()
+            .any(|dir| dir.as_os_str() == current.as_os_str())
+        {
+            gst_warning!(
+                cat,
+                obj: element,
+                "Not binding NDI to {}, NDI_CONFIG_DIR is already set to {}",
+                bind_address,
+                current.to_string_lossy()
+            );
+            return Ok(());
+        }
+    }
+    if *users != 0 {
+        return Err(format!(
+            "NDI is already initialized with {}",
+            current
+                .map(|current| current.to_string_lossy().into_owned())
+                .unwrap_or_else(|| String::from("the default configuration"))
+        ));
+    }
+
+    let dir = match dirs.get(bind_address) {
+        Some(dir) => dir.clone(),
+        None => {
+            let dir = create_private_dir().map_err(|err| err.to_string())?;
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(dir.join("ndi-config.v1.json"))
+                .and_then(|mut file| {
+                    write!(
+                        file,
+                        "{{\"ndi\": {{\"adapters\": {{\"allowed\": [\"{}\"]}}}}}}",
+                        bind_address
+                    )
+                })
+                .map_err(|err| err.to_string())?;
+            dirs.insert(bind_address.to_owned(), dir.clone());
+            dir
+        }
+    };
+    env::set_var("NDI_CONFIG_DIR", &dir);
+    Ok(())
+}
+
+// A new directory in the temporary one that only we can access. Creating it
+// fails if the name is taken, so nobody can have prepared it for us.
+fn create_private_dir() -> io::Result<PathBuf> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
     {
-        let val = receivers.get_mut(&id).unwrap();
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+
+    let mut attempt = 0u32;
+    loop {
+        let nanos = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.subsec_nanos())
+            .unwrap_or(0);
+        let dir = env::temp_dir().join(format!(
+            "gst-ndi-{}-{:08x}",
+            process::id(),
+            nanos.wrapping_add(attempt)
+        ));
+        match builder.create(&dir) {
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => {
+                attempt += 1
+            }
+            result => return result.map(|_| dir),
+        }
+    }
+}
+
+// The SDK reads its configuration once in NDIlib_initialize, so a different
+// one only takes effect after every element has released NDI
+fn configure_config_path(config_path: &str) -> Result<(), String> {
+    let dir = Path::new(config_path);
+    if !dir.join("ndi-config.v1.json").is_file() {
+        return Err(format!("no ndi-config.v1.json in {}", config_path));
+    }
+
+    let users = ndi_users.lock().unwrap_or_else(PoisonError::into_inner);
+    let current = env::var_os("NDI_CONFIG_DIR");
+    if let Some(ref current) = current {
+        if current == dir.as_os_str() {
+            return Ok(());
+        }
+    }
+    if *users != 0 {
+        return Err(format!(
+            "NDI is already initialized with {}",
+            current
+                .map(|current| current.to_string_lossy().into_owned())
+                .unwrap_or_else(|| String::from("the default configuration"))
+        ));
+    }
+
+    env::set_var("NDI_CONFIG_DIR", dir);
+    Ok(())
+}
+
+// NDI senders take connections on port 5961 unless configured otherwise, 5960
+// is their messaging port
+fn direct_source_address(ip: &str) -> String {
+    if ip.parse::<SocketAddr>().is_ok() {
+        return ip.to_owned();
+    }
+    match parse_ip_literal(ip) {
+        // Formatting a SocketAddr brackets IPv6 addresses
+        Some(addr) => SocketAddr::new(addr, 5961).to_string(),
+        // A host name, with or without a port
+        None if ip.contains(':') => ip.to_owned(),
+        None => format!("{}:5961", ip),
+    }
+}
+
+// An IPv4 or IPv6 address without a port, IPv6 optionally in brackets
+fn parse_ip_literal(address: &str) -> Option<IpAddr> {
+    let literal = if address.starts_with('[') && address.ends_with(']') {
+        &address[1..address.len() - 1]
+    } else {
+        address
+    };
+    literal.parse::<IpAddr>().ok()
+}
+
+// A source is selected by name or address, or by both when both are given
+fn source_matches(source_name: &str, source_ip: &str, stream_name: &str, ip: &str) -> bool {
+    if stream_name == TEST_SOURCE {
+        is_test_source(source_name)
+    } else if !stream_name.is_empty() && !ip.is_empty() {
+        source_name == stream_name && source_ip == ip
+    } else {
+        (!stream_name.is_empty() && source_name == stream_name)
+            || (!ip.is_empty() && source_ip == ip)
+    }
+}
+
+fn parse_source_ip(address: &str) -> Option<IpAddr> {
+    address
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .ok()
+        .or_else(|| parse_ip_literal(address))
+}
+
+// Asks the finder the receiver keeps alongside, which already knows whether the
+// source is still there, so the streaming thread doesn't wait for a discovery
+fn source_available(recv: &dyn NdiReceiver, ip: &str, stream_name: &str) -> bool {
+    match recv.finder() {
+        Some(finder) => finder
+            .current_sources()
+            .iter()
+            .any(|(source_name, source_ip)| {
+                source_matches(source_name, source_ip, stream_name, ip)
+            }),
+        None => true,
+    }
+}
+
+fn available_sources() -> String {
+    find_sources()
+        .into_iter()
+        .map(|(source_name, _)| source_name)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn probe_format(ip: &str, stream_name: &str) -> Option<gst::Structure> {
+    let (source_name, source_ip) = find_sources()
+        .into_iter()
+        .find(|(source_name, source_ip)| source_matches(source_name, source_ip, stream_name, ip))?;
+
+    // The receiver needs NDI initialized until it's destroyed
+    if !ndi_acquire() {
+        return None;
+    }
+    let format = unsafe { probe_receiver(source_name, source_ip) };
+    ndi_release();
+    format
+}
+
+// Connects a receiver to the source for a few seconds and reads the format of
+// the first video and audio frame, until the receiver is dropped at the end
+unsafe fn probe_receiver(source_name: String, source_ip: String) -> Option<gst::Structure> {
+    let p_ndi_name = CString::new(source_name.clone()).unwrap();
+    let p_ip_address = CString::new(source_ip.clone()).unwrap();
+    let p_recv_name = CString::new("GStreamer NDI Format Probe").unwrap();
+
+    let NDI_recv_create_desc = NDIlib_recv_create_v3_t {
+        source_to_connect_to: NDIlib_source_t {
+            p_ndi_name: p_ndi_name.as_ptr(),
+            p_ip_address: p_ip_address.as_ptr(),
+        },
+        p_ndi_name: p_recv_name.as_ptr(),
+        ..Default::default()
+    };
+
+    let pNDI_recv = NDIlib_recv_create_v3(&NDI_recv_create_desc);
+    if pNDI_recv.is_null() {
+        return None;
+    }
+
+    let mut s = gst::Structure::new(
+        "ndi/format",
+        &[("stream-name", &source_name), ("ip", &source_ip)],
+    );
+
+    let recv = RecvInstance::new(pNDI_recv, None);
+    let mut video_frame: NDIlib_video_frame_v2_t = Default::default();
+    let mut audio_frame: NDIlib_audio_frame_v2_t = Default::default();
+    let mut have_video = false;
+    let mut have_audio = false;
+
+    // Give up after a few seconds so sources without audio or video still return
+    for _ in 0..10 {
+        if have_video && have_audio {
+            break;
+        }
+
+        match recv.capture(Some(&mut video_frame), Some(&mut audio_frame), None, 500) {
+            NDIlib_frame_type_e::NDIlib_frame_type_video => {
+                if !have_video {
+                    s.set("width", &video_frame.xres);
+                    s.set("height", &video_frame.yres);
+                    s.set(
+                        "framerate",
+                        &gst::Fraction::new(video_frame.frame_rate_N, video_frame.frame_rate_D),
+                    );
+                    have_video = true;
+                }
+                recv.free_video(&video_frame);
+            }
+            NDIlib_frame_type_e::NDIlib_frame_type_audio => {
+                if !have_audio {
+                    s.set("rate", &audio_frame.sample_rate);
+                    s.set("channels", &audio_frame.no_channels);
+                    have_audio = true;
+                }
+                recv.free_audio(&audio_frame);
+            }
+            _ => {}
+        }
+    }
+
+    Some(s)
+}
+
+fn add_connection_metadata(id: usize, data: &str) -> bool {
+    let ndi_instance = match hashmap_receivers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&id)
+    {
+        Some(receiver) => receiver.ndi_instance.clone(),
+        None => return false,
+    };
+    let recv = ndi_instance.read().unwrap_or_else(PoisonError::into_inner);
+    if recv.is_destroyed() {
+        return false;
+    }
+
+    let data = CString::new(data).unwrap();
+    let metadata_frame = NDIlib_metadata_frame_t {
+        length: data.to_bytes().len() as i32,
+        timecode: 0,
+        p_data: data.as_ptr(),
+    };
+
+    unsafe {
+        NDIlib_recv_add_connection_metadata(recv.as_ptr(), &metadata_frame);
+    }
+    true
+}
+
+fn web_control_url(id: usize) -> String {
+    let ndi_instance = match hashmap_receivers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&id)
+    {
+        Some(receiver) => receiver.ndi_instance.clone(),
+        None => return String::new(),
+    };
+    let recv = ndi_instance.read().unwrap_or_else(PoisonError::into_inner);
+    if recv.is_destroyed() {
+        return String::new();
+    }
+
+    unsafe {
+        let p_url = NDIlib_recv_get_web_control(recv.as_ptr());
+        if p_url.is_null() {
+            return String::new();
+        }
+        let url = CStr::from_ptr(p_url).to_string_lossy().into_owned();
+        NDIlib_recv_free_string(recv.as_ptr(), p_url);
+        url
+    }
+}
+
+// Runs `f` on the NDI receiver with this id, the default value if there is none
+fn with_receiver<T, F>(id: usize, f: F) -> T
+where
+    T: Default,
+    F: FnOnce(NDIlib_recv_instance_t) -> T,
+{
+    let ndi_instance = match hashmap_receivers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&id)
+    {
+        Some(receiver) => receiver.ndi_instance.clone(),
+        None => return T::default(),
+    };
+    let recv = ndi_instance.read().unwrap_or_else(PoisonError::into_inner);
+    if recv.is_destroyed() {
+        return T::default();
+    }
+    f(recv.as_ptr())
+}
+
+// Time of an NDI frame in 100 ns units for its PTS: the sender's timecode with
+// timestamp-mode=timecode, the time the receiver got it otherwise. Senders
+// that don't timestamp their frames leave that undefined, so their timecode is
+// used then as well.
+fn ndi_frame_time(timestamp_mode: &str, timecode: i64, timestamp: i64) -> u64 {
+    if timestamp_mode == "timecode" || timestamp_undefined(timestamp_mode, timestamp) {
+        timecode as u64
+    } else {
+        timestamp as u64
+    }
+}
+
+// Whether ndi_frame_time() falls back to the timecode for want of a timestamp
+fn timestamp_undefined(timestamp_mode: &str, timestamp: i64) -> bool {
+    timestamp_mode == "receive-timestamp" && timestamp == NDIlib_recv_timestamp_undefined
+}
+
+// Whether `lost` consecutive captures without a frame are more than
+// loss-threshold tolerates, so the source counts as lost
+fn source_lost(lost: u32, loss_threshold: u32) -> bool {
+    lost > loss_threshold
+}
+
+// The max latency the latency query reports, unlimited for max-latency=-1 and
+// never below the latency itself. Also tells whether max-latency was raised.
+fn max_latency(latency: gst::ClockTime, max_latency: u64) -> (gst::ClockTime, bool) {
+    if max_latency == u64::MAX {
+        (gst::CLOCK_TIME_NONE, false)
+    } else if gst::ClockTime::from(max_latency) < latency {
+        (latency, true)
+    } else {
+        (gst::ClockTime::from(max_latency), false)
+    }
+}
+
+// The caps negotiated on the source pad, changed for a new source format
+fn changed_caps<F: FnOnce(&mut gst::StructureRef)>(
+    element: &gst_base::BaseSrc,
+    change: F,
+) -> Option<gst::Caps> {
+    let mut caps = element.get_static_pad("src")?.get_current_caps()?.copy();
+    change(caps.get_mut()?.get_mut_structure(0)?);
+    Some(caps)
+}
+
+// Switches to the caps of a source that changed its format mid-stream and
+// emits "format-changed" before the first buffer in them. Must be called
+// without any of the element's locks held, set_caps() takes them itself.
+fn renegotiate(cat: gst::DebugCategory, element: &gst_base::BaseSrc, caps: &gst::Caps) -> bool {
+    gst_info!(
+        cat,
+        obj: element,
+        "NDI source changed format, renegotiating to {}",
+        caps
+    );
+    if element.set_caps(caps).is_err() {
+        gst_element_error!(
+            element,
+            gst::CoreError::Negotiation,
+            [
+                "Downstream doesn't accept the new format of the NDI source: {}",
+                caps
+            ]
+        );
+        return false;
+    }
+    let _ = element.emit("format-changed", &[caps]);
+    true
+}
+
+fn receiver_source(id: usize) -> Option<(String, String)> {
+    let receivers = hashmap_receivers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    receivers
+        .get(&id)
+        .map(|receiver| (receiver.stream_name.clone(), receiver.ip.clone()))
+}
+
+// What's known about the receiver with this id, an empty structure if there is
+// none
+fn connection_info(id: usize) -> gst::Structure {
+    let mut info = gst::Structure::new_empty("ndi-connection-info");
+    let receivers = hashmap_receivers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if let Some(receiver) = receivers.get(&id) {
+        info.set("stream-name", &receiver.stream_name);
+        info.set("ip", &receiver.ip);
+        info.set(
+            "url",
+            &source_to_ndi_uri(&receiver.stream_name, &receiver.ip),
+        );
+        info.set(
+            "bandwidth",
+            &if receiver.receive_video {
+                "highest"
+            } else {
+                "audio-only"
+            },
+        );
+        info.set("groups", &receiver.groups);
+    }
+    info
+}
+
+// Context type of the connected source's info shared with the pipeline
+const SOURCE_INFO_CONTEXT: &str = "ndi-source-info";
+
+// Publishes what's known about the receiver with this id as an ndi-source-info
+// context: the connection-info fields, the sender's web-control URL and the
+// connection metadata sent to it. The context is set on the element and posted
+// in a have-context message, so the bins hand it on to the other elements.
+fn publish_source_info(
+    element: &gst_base::BaseSrc,
+    id: usize,
+    connection_metadata: &str,
+) -> gst::Context {
+    let mut context = gst::Context::new(SOURCE_INFO_CONTEXT, false);
+    {
+        let s = context.get_mut().unwrap().get_mut_structure();
+        for (name, value) in connection_info(id).iter() {
+            s.set_value(name, value.clone());
+        }
+        s.set("web-control", &web_control_url(id));
+        s.set("connection-metadata", &connection_metadata);
+    }
+    element.set_context(&context);
+    let _ = element.post_message(
+        &gst::Message::new_have_context(context.clone())
+            .src(Some(element))
+            .build(),
+    );
+    context
+}
+
+fn receiver_tags(id: usize) -> Option<gst::TagList> {
+    let receivers = hashmap_receivers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let receiver = receivers.get(&id)?;
+
+    let mut tags = gst::TagList::new();
+    {
+        let tags = tags.get_mut().unwrap();
+        tags.add::<gst::tags::Title>(&receiver.stream_name.as_str(), gst::TagMergeMode::Replace);
+        tags.add::<gst::tags::Location>(&receiver.ip.as_str(), gst::TagMergeMode::Replace);
+        if let Some((ref vendor, ref model)) = *receiver
+            .product
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+        {
+            tags.add::<gst::tags::Organization>(&vendor.as_str(), gst::TagMergeMode::Replace);
+            tags.add::<gst::tags::DeviceManufacturer>(&vendor.as_str(), gst::TagMergeMode::Replace);
+            tags.add::<gst::tags::DeviceModel>(&model.as_str(), gst::TagMergeMode::Replace);
+        }
+    }
+    Some(tags)
+}
+
+// Lets NDI queue up `queue_depth` video or audio frames on the receiver before
+// the first buffer, so there's that much slack against network jitter. Waits
+// for at most a second per frame, and without any of the element's locks held,
+// so unlock() and property changes get through meanwhile.
+fn wait_for_queue(
+    cat: gst::DebugCategory,
+    element: &gst_base::BaseSrc,
+    id: usize,
+    queue_depth: u32,
+    video: bool,
+    unlocked: &AtomicBool,
+) -> Result<(), gst::FlowError> {
+    let ndi_instance = match hashmap_receivers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&id)
+    {
+        Some(receiver) => receiver.ndi_instance.clone(),
+        None => return Ok(()),
+    };
+    let recv = ndi_instance.read().unwrap_or_else(PoisonError::into_inner);
+    let queued = || {
+        let queue = recv.queue();
+        if video {
+            queue.video_frames as u32
+        } else {
+            queue.audio_frames as u32
+        }
+    };
+
+    let deadline = time::Instant::now() + time::Duration::from_secs(u64::from(queue_depth));
+    while queued() < queue_depth && time::Instant::now() < deadline {
+        if unlocked.load(Ordering::SeqCst) {
+            gst_debug!(cat, obj: element, "Unlocked while queueing frames");
+            return Err(gst::FlowError::Flushing);
+        }
+        thread::sleep(time::Duration::from_millis(5));
+    }
+    gst_debug!(cat, obj: element, "Queued {} frames before starting", queued());
+    Ok(())
+}
+
+// Whether both an ndivideosrc and an ndiaudiosrc capture from the receiver
+fn receiver_shared(id: usize) -> bool {
+    hashmap_receivers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&id)
+        .map(|receiver| receiver.video && receiver.audio)
+        .unwrap_or(false)
+}
+
+// Manufacturer and model of the receiver's source, once it sent them
+fn receiver_product(id: usize) -> Option<(String, String)> {
+    let receivers = hashmap_receivers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let receiver = receivers.get(&id)?;
+    let product = receiver
+        .product
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    product.clone()
+}
+
+// Sends the receiver's tags downstream again when the source's manufacturer and
+// model became known, `sent` being the product the last tags had. Called from
+// the streaming thread without locks held, and only once the segment is out so
+// the tag event isn't ahead of it.
+fn update_receiver_tags(
+    element: &gst_base::BaseSrc,
+    id: usize,
+    sent: &Mutex<Option<(String, String)>>,
+) {
+    let product = receiver_product(id);
+    if product == *sent.lock().unwrap_or_else(PoisonError::into_inner) {
+        return;
+    }
+    let pad = match element.get_static_pad("src") {
+        Some(pad) => pad,
+        None => return,
+    };
+    if pad.get_sticky_event(gst::EventType::Segment, 0).is_none() {
+        return;
+    }
+    if let Some(tags) = receiver_tags(id) {
+        pad.push_event(gst::Event::new_tag(tags).build());
+    }
+    *sent.lock().unwrap_or_else(PoisonError::into_inner) = product;
+}
+
+// Manufacturer and model an NDI sender announces in its ndi_product metadata
+fn metadata_product(data: &str) -> Option<(String, String)> {
+    let vendor = metadata_attribute(data, "ndi_product", "manufacturer")?;
+    let model = metadata_attribute(data, "ndi_product", "model_name").unwrap_or_default();
+    Some((vendor, model))
+}
+
+// NDIlib_recv_capture_v2() also returns metadata and status changes while waiting
+// for a video or audio frame. Metadata is queued in `metadata` for emit_metadata(),
+// status changes are logged, and the wait goes on for the rest of the timeout. The
+// manufacturer and model from ndi_product metadata are kept in `product` for the
+// stream tags.
+#[allow(clippy::too_many_arguments)]
+fn capture_frame(
+    cat: gst::DebugCategory,
+    element: &gst_base::BaseSrc,
+    recv: &dyn NdiReceiver,
+    product: &Mutex<Option<(String, String)>>,
+    metadata: &Mutex<Vec<gst::Structure>>,
+    mut video_frame: Option<&mut NDIlib_video_frame_v2_t>,
+    mut audio_frame: Option<&mut NDIlib_audio_frame_v2_t>,
+    timeout_in_ms: u32,
+) -> NDIlib_frame_type_e {
+    let deadline = time::Instant::now() + time::Duration::from_millis(u64::from(timeout_in_ms));
+    loop {
+        let now = time::Instant::now();
+        let remaining = if now < deadline {
+            let remaining = deadline - now;
+            remaining.as_secs() as u32 * 1000 + remaining.subsec_millis()
+        } else {
+            0
+        };
+
+        let mut metadata_frame: NDIlib_metadata_frame_t = Default::default();
+        let frame_type = recv.capture(
+            video_frame.as_deref_mut(),
+            audio_frame.as_deref_mut(),
+            Some(&mut metadata_frame),
+            remaining,
+        );
+        match frame_type {
+            NDIlib_frame_type_e::NDIlib_frame_type_metadata => {
+                if !metadata_frame.p_data.is_null() {
+                    let data = unsafe { CStr::from_ptr(metadata_frame.p_data) }
+                        .to_string_lossy()
+                        .into_owned();
+                    gst_debug!(cat, obj: element, "Received NDI metadata {}", data);
+                    if let Some(new_product) = metadata_product(&data) {
+                        *product.lock().unwrap_or_else(PoisonError::into_inner) = Some(new_product);
+                    }
+                    metadata
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push(parse_metadata(&data, metadata_frame.timecode));
+                }
+                recv.free_metadata(&metadata_frame);
+            }
+            NDIlib_frame_type_e::NDIlib_frame_type_status_change => {
+                gst_debug!(cat, obj: element, "NDI source status changed");
+            }
+            _ => return frame_type,
+        }
+
+        if remaining == 0 {
+            return NDIlib_frame_type_e::NDIlib_frame_type_none;
+        }
+    }
+}
+
+// Posts the metadata capture_frame() queued as "ndi-metadata" element messages
+// and emits it with the "metadata-received" signal. Handlers may call back
+// into the element, so this is only done once capturing released its locks.
+fn emit_metadata(element: &gst_base::BaseSrc, metadata: &Mutex<Vec<gst::Structure>>) {
+    let metadata = mem::take(&mut *metadata.lock().unwrap_or_else(PoisonError::into_inner));
+    for s in metadata {
+        let _ = element.emit("metadata-received", &[&s]);
+        let _ = element.post_message(&gst::Message::new_element(s).src(Some(element)).build());
+    }
+}
+
+// Picks the commonly used fields out of an NDI metadata XML string, next to the
+// raw data and timecode
+fn parse_metadata(data: &str, timecode: i64) -> gst::Structure {
+    let mut s = gst::Structure::new("ndi-metadata", &[("data", &data), ("timecode", &timecode)]);
+
+    let bool_attribute = |tag: &str, name: &str| {
+        metadata_attribute(data, tag, name).map(|value| value == "true" || value == "1")
+    };
+    let float_attribute = |tag: &str, name: &str| {
+        metadata_attribute(data, tag, name).and_then(|value| value.parse::<f64>().ok())
+    };
+
+    if let Some(on_program) = bool_attribute("ndi_tally_echo", "on_program") {
+        s.set("tally-program", &on_program);
+    }
+    if let Some(on_preview) = bool_attribute("ndi_tally_echo", "on_preview") {
+        s.set("tally-preview", &on_preview);
+    }
+    if let Some(pan) = float_attribute("ntk_ptz_pan_tilt", "pan") {
+        s.set("ptz-pan", &pan);
+    }
+    if let Some(tilt) = float_attribute("ntk_ptz_pan_tilt", "tilt") {
+        s.set("ptz-tilt", &tilt);
+    }
+    if let Some(zoom) = float_attribute("ntk_ptz_zoom", "zoom") {
+        s.set("ptz-zoom", &zoom);
+    }
+    if let Some((vendor, model)) = metadata_product(data) {
+        s.set("vendor", &vendor);
+        s.set("model", &model);
+    }
+    if data.contains("<ntk_record_start") {
+        s.set("recording", &true);
+    } else if data.contains("<ntk_record_stop") {
+        s.set("recording", &false);
+    }
+    s
+}
+
+// Value of the `name` attribute of the first `tag` element in an NDI metadata
+// XML string. NDI metadata is flat enough that this doesn't need a full parser.
+fn metadata_attribute(data: &str, tag: &str, name: &str) -> Option<String> {
+    let start = data.find(&format!("<{}", tag))?;
+    let element = &data[start..];
+    let element = &element[..element.find('>').unwrap_or(element.len())];
+
+    let key = format!(" {}=\"", name);
+    let value = &element[element.find(&key)? + key.len()..];
+    value.find('"').map(|end| value[..end].to_owned())
+}
+
+// Attaches the sender's timecode, in 100ns units, as a reference timestamp
+#[cfg(feature = "v1_14")]
+fn add_timecode_meta(buffer: &mut gst::BufferRef, timecode: i64, duration: gst::ClockTime) {
+    if timecode < 0 || timecode == NDIlib_send_timecode_synthesize {
+        return;
+    }
+
+    let reference = gst::Caps::new_simple("timestamp/x-ndi-timecode", &[]);
+    unsafe {
+        gst_ffi::gst_buffer_add_reference_timestamp_meta(
+            buffer.as_mut_ptr(),
+            reference.as_mut_ptr(),
+            timecode as u64 * 100,
+            duration.nseconds().unwrap_or(gst_ffi::GST_CLOCK_TIME_NONE),
+        );
+    }
+}
+
+#[cfg(not(feature = "v1_14"))]
+fn add_timecode_meta(_buffer: &mut gst::BufferRef, _timecode: i64, _duration: gst::ClockTime) {}
+
+// Keeps the timestamps of an element going on after reconnect(). The frame times
+// of a new receiver start at zero again, so the first frame from it takes the
+// offset to the end of the last buffer before, which then applies to all of its
+// frames.
+#[derive(Debug, Clone, Copy)]
+struct PtsResume {
+    resume_pts: gst::ClockTime,
+    offset: u64,
+}
+
+impl Default for PtsResume {
+    fn default() -> PtsResume {
+        PtsResume {
+            resume_pts: gst::CLOCK_TIME_NONE,
+            offset: 0,
+        }
+    }
+}
+
+impl PtsResume {
+    // Where the next receiver's frames go on from, `next_pts` or where the
+    // previous reconnect wanted to go on from if no frame came since
+    fn reconnected(self, next_pts: gst::ClockTime) -> PtsResume {
+        PtsResume {
+            resume_pts: if next_pts.is_some() {
+                next_pts
+            } else {
+                self.resume_pts
+            },
+            offset: 0,
+        }
+    }
+
+    fn pending(&self) -> gst::ClockTime {
+        self.resume_pts
+    }
+
+    // Moves the resume point on past a buffer output before the first frame
+    fn skip(&mut self, next_pts: gst::ClockTime) {
+        if self.resume_pts.is_some() {
+            self.resume_pts = next_pts;
+        }
+    }
+
+    fn apply(&mut self, pts: gst::ClockTime) -> gst::ClockTime {
+        if let (Some(resume), Some(frame)) = (self.resume_pts.nseconds(), pts.nseconds()) {
+            self.offset = resume.saturating_sub(frame);
+            self.resume_pts = gst::CLOCK_TIME_NONE;
+        }
+        pts + gst::ClockTime::from(self.offset)
+    }
+}
+
+// Number of buffers the clock-drift average settles over
+const CLOCK_DRIFT_WINDOW: i64 = 32;
+
+// Updates the moving average of how far the running time at delivery is ahead
+// of the buffer's PTS
+fn update_clock_drift(
+    element: &gst_base::BaseSrc,
+    buffer: &gst::BufferRef,
+    samples: &mut i64,
+    drift: &AtomicI64,
+) {
+    let running_time = match element.get_clock() {
+        Some(clock) => clock.get_time() - element.get_base_time(),
+        None => return,
+    };
+    let sample = match (running_time.nseconds(), buffer.get_pts().nseconds()) {
+        (Some(running_time), Some(pts)) => running_time as i64 - pts as i64,
+        _ => return,
+    };
+
+    // Averages all buffers until the window is full
+    *samples = cmp::min(*samples + 1, CLOCK_DRIFT_WINDOW);
+    let average = drift.load(Ordering::SeqCst);
+    drift.store(average + (sample - average) / *samples, Ordering::SeqCst);
+}
+
+fn ndi_uri_to_source(uri: &str) -> Result<(String, String), String> {
+    if !uri.starts_with("ndi://") {
+        return Err(format!("Unsupported URI '{}'", uri));
+    }
+
+    let location = match percent_decode(&uri["ndi://".len()..]) {
+        Some(location) => location,
+        None => return Err(format!("Invalid percent-encoding in URI '{}'", uri)),
+    };
+    if location.is_empty() {
+        return Err(format!("No NDI source in URI '{}'", uri));
+    }
+
+    // ndi://<address> selects by IP, anything else is a stream name
+    if location.parse::<SocketAddr>().is_ok() || parse_ip_literal(&location).is_some() {
+        Ok((String::new(), location))
+    } else {
+        Ok((location, String::new()))
+    }
+}
+
+fn source_to_ndi_uri(stream_name: &str, ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        _ if ip.is_empty() => format!("ndi://{}", percent_encode(stream_name)),
+        // Bracketed so the address can't be mistaken for a port
+        Ok(IpAddr::V6(addr)) => format!("ndi://[{}]", addr),
+        _ => format!("ndi://{}", ip),
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+// Stops an element's use of the receiver with this id. None if there is no such
+// receiver, otherwise the receiver if no other element uses it any more.
+fn remove_receiver(id: usize, audio: bool) -> Option<Option<SharedReceiver>> {
+    let mut receivers = hashmap_receivers
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    {
+        let val = receivers.get_mut(&id)?;
         if val.video && val.audio {
-            if element.get_name().contains("audiosrc") {
+            if audio {
                 val.audio = false;
             } else {
                 val.video = false;
             }
-            return true;
-        }
-
-        let recv = &val.ndi_instance;
-        let pNDI_recv = recv.recv;
-        unsafe {
-            NDIlib_recv_destroy(pNDI_recv);
-            // ndi_struct.recv = None;
-            NDIlib_destroy();
+            return Some(None);
         }
     }
-    receivers.remove(&id);
+    receivers.remove(&id).map(|val| Some(val.ndi_instance))
+}
+
+fn stop_ndi(cat: gst::DebugCategory, element: &gst_base::BaseSrc, id: usize) -> bool {
+    gst_debug!(cat, obj: element, "Closing NDI connection...");
+    let ndi_instance = match remove_receiver(id, element.get_name().contains("audiosrc")) {
+        Some(Some(ndi_instance)) => ndi_instance,
+        Some(None) => return true,
+        None => return false,
+    };
+
+    // Waits for any capture still running on this receiver, so the SDK isn't
+    // torn down under it
+    let mut recv = ndi_instance.write().unwrap_or_else(PoisonError::into_inner);
+    recv.destroy();
+    ndi_release();
     gst_debug!(cat, obj: element, "Closed NDI connection");
     true
 }
@@ -264,3 +1696,395 @@ gst_plugin_define!(
     "https://github.com/teltek/gst-plugin-ndi",
     "2018-04-09"
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::sync::Once;
+
+    // GStreamer is initialized once for the tests creating elements
+    pub fn init() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| gst::init().unwrap());
+    }
+
+    pub fn fake_receiver_info(recv: FakeReceiver, video: bool, audio: bool) -> ndi_receiver_info {
+        ndi_receiver_info {
+            stream_name: "Fake".to_string(),
+            ip: "127.0.0.1:5961".to_string(),
+            video,
+            audio,
+            receive_video: video,
+            allow_video_fields: false,
+            exclusive: false,
+            source_index: -1,
+            select_policy: "first".to_string(),
+            groups: String::new(),
+            ndi_instance: Arc::new(RwLock::new(Box::new(recv))),
+            initial_timestamp: Arc::new(AtomicU64::new(0)),
+            initial_timecode: Arc::new(AtomicU64::new(0)),
+            product: Arc::new(Mutex::new(None)),
+            id: 0,
+        }
+    }
+
+    // Adds `recv` to the receivers like connect_ndi() does, for an element to
+    // capture from with the returned id
+    pub fn add_fake_receiver(recv: FakeReceiver, video: bool, audio: bool) -> usize {
+        add_receiver(
+            &mut hashmap_receivers.lock().unwrap(),
+            fake_receiver_info(recv, video, audio),
+        )
+    }
+
+    #[test]
+    fn fake_receiver_drops_frames_not_captured() {
+        let video_frame = NDIlib_video_frame_v2_t {
+            xres: 1920,
+            yres: 1080,
+            ..Default::default()
+        };
+        let recv = FakeReceiver::new(vec![
+            FakeFrame::Audio(Default::default()),
+            FakeFrame::Video(video_frame),
+        ]);
+        assert_eq!(recv.queue().audio_frames, 1);
+        assert_eq!(recv.queue().video_frames, 1);
+
+        let mut frame: NDIlib_video_frame_v2_t = Default::default();
+        assert_eq!(
+            recv.capture_video(&mut frame, 0),
+            NDIlib_frame_type_e::NDIlib_frame_type_none
+        );
+        assert_eq!(
+            recv.capture_video(&mut frame, 0),
+            NDIlib_frame_type_e::NDIlib_frame_type_video
+        );
+        assert_eq!((frame.xres, frame.yres), (1920, 1080));
+        recv.free_video(&frame);
+        assert_eq!(recv.freed.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            recv.capture_video(&mut frame, 0),
+            NDIlib_frame_type_e::NDIlib_frame_type_none
+        );
+    }
+
+    #[test]
+    fn shared_receiver_stays_until_both_elements_stop() {
+        let recv = FakeReceiver::new(vec![]);
+        let destroyed = recv.destroyed.clone();
+        let id = add_receiver(
+            &mut hashmap_receivers.lock().unwrap(),
+            fake_receiver_info(recv, true, true),
+        );
+        assert_ne!(id, 0);
+
+        assert!(remove_receiver(id, true).unwrap().is_none());
+        {
+            let receivers = hashmap_receivers.lock().unwrap();
+            let receiver = &receivers[&id];
+            assert!(receiver.video && !receiver.audio);
+        }
+
+        let ndi_instance = remove_receiver(id, false).unwrap().unwrap();
+        assert!(!hashmap_receivers.lock().unwrap().contains_key(&id));
+        ndi_instance.write().unwrap().destroy();
+        assert!(destroyed.load(Ordering::SeqCst));
+
+        assert!(remove_receiver(id, false).is_none());
+    }
+
+    #[test]
+    fn receivers_get_distinct_ids() {
+        let mut receivers = hashmap_receivers.lock().unwrap();
+        let first = add_receiver(
+            &mut receivers,
+            fake_receiver_info(FakeReceiver::new(vec![]), true, false),
+        );
+        let second = add_receiver(
+            &mut receivers,
+            fake_receiver_info(FakeReceiver::new(vec![]), false, true),
+        );
+        assert_ne!(first, second);
+        assert_eq!(receivers[&first].id, first);
+        assert_eq!(receivers[&second].id, second);
+        receivers.remove(&first);
+        receivers.remove(&second);
+    }
+
+    #[test]
+    fn receiver_ids_wrap_around_without_zero() {
+        let mut receivers = hashmap_receivers.lock().unwrap();
+        id_receiver.store(usize::MAX - 1, Ordering::SeqCst);
+        let last = add_receiver(
+            &mut receivers,
+            fake_receiver_info(FakeReceiver::new(vec![]), true, false),
+        );
+        let wrapped = add_receiver(
+            &mut receivers,
+            fake_receiver_info(FakeReceiver::new(vec![]), false, true),
+        );
+        assert_eq!(last, usize::MAX);
+        assert_ne!(wrapped, 0);
+        assert!(wrapped < last);
+        receivers.remove(&last);
+        receivers.remove(&wrapped);
+    }
+
+    #[test]
+    fn receiver_without_sdk_instance_is_skipped() {
+        let id = add_receiver(
+            &mut hashmap_receivers.lock().unwrap(),
+            fake_receiver_info(FakeReceiver::new(vec![]), true, false),
+        );
+        assert!(!with_receiver(id, |_| true));
+        assert!(!add_connection_metadata(id, "<ndi_product/>"));
+        assert_eq!(web_control_url(id), "");
+        hashmap_receivers.lock().unwrap().remove(&id);
+    }
+
+    #[test]
+    fn product_is_read_from_ndi_product_metadata() {
+        assert_eq!(
+            metadata_product(
+                "<ndi_product long_name=\"PTZ Camera\" manufacturer=\"Acme\" model_name=\"X1\"/>"
+            ),
+            Some(("Acme".to_string(), "X1".to_string()))
+        );
+        assert_eq!(
+            metadata_product("<ndi_product manufacturer=\"Acme\"/>"),
+            Some(("Acme".to_string(), String::new()))
+        );
+        assert_eq!(
+            metadata_product("<ndi_tally_echo on_program=\"true\"/>"),
+            None
+        );
+    }
+
+    #[test]
+    fn source_without_finder_counts_as_available() {
+        let recv = FakeReceiver::new(vec![]);
+        assert!(source_available(&recv, "127.0.0.1:5961", "Fake"));
+    }
+
+    #[test]
+    fn timestamps_go_on_after_a_reconnect() {
+        let mut resume = PtsResume::default();
+        assert_eq!(
+            resume.apply(gst::ClockTime::from(40)),
+            gst::ClockTime::from(40)
+        );
+
+        // The last buffer ended at 100, the new receiver starts at 10 again
+        let mut resume = resume.reconnected(gst::ClockTime::from(100));
+        assert_eq!(
+            resume.apply(gst::ClockTime::from(10)),
+            gst::ClockTime::from(100)
+        );
+        assert_eq!(
+            resume.apply(gst::ClockTime::from(30)),
+            gst::ClockTime::from(120)
+        );
+
+        // Reconnecting again before a frame came keeps the resume point
+        let resume = resume.reconnected(gst::ClockTime::from(140));
+        let mut resume = resume.reconnected(gst::CLOCK_TIME_NONE);
+        resume.skip(gst::ClockTime::from(150));
+        assert_eq!(
+            resume.apply(gst::ClockTime::from(5)),
+            gst::ClockTime::from(150)
+        );
+        // Frames later than the resume point keep their time
+        let mut resume = resume.reconnected(gst::ClockTime::from(160));
+        assert_eq!(
+            resume.apply(gst::ClockTime::from(200)),
+            gst::ClockTime::from(200)
+        );
+    }
+
+    #[test]
+    fn direct_addresses_get_the_default_port() {
+        assert_eq!(direct_source_address("192.168.1.20"), "192.168.1.20:5961");
+        assert_eq!(
+            direct_source_address("192.168.1.20:5962"),
+            "192.168.1.20:5962"
+        );
+        assert_eq!(direct_source_address("fd00::20"), "[fd00::20]:5961");
+        assert_eq!(direct_source_address("[fd00::20]"), "[fd00::20]:5961");
+        assert_eq!(direct_source_address("[fd00::20]:5962"), "[fd00::20]:5962");
+        assert_eq!(direct_source_address("camera.local"), "camera.local:5961");
+        assert_eq!(
+            direct_source_address("camera.local:5962"),
+            "camera.local:5962"
+        );
+    }
+
+    #[test]
+    fn ip_literals_are_parsed_with_and_without_brackets() {
+        assert_eq!(
+            parse_ip_literal("10.0.0.1"),
+            Some(IpAddr::from([10, 0, 0, 1]))
+        );
+        assert_eq!(parse_ip_literal("::1"), "::1".parse().ok());
+        assert_eq!(parse_ip_literal("[::1]"), "::1".parse().ok());
+        assert_eq!(parse_ip_literal("10.0.0.1:5960"), None);
+        assert_eq!(parse_ip_literal("[10.0.0.1"), None);
+        assert_eq!(parse_ip_literal("camera.local"), None);
+    }
+
+    #[test]
+    fn ndi_uris_select_by_address_or_name() {
+        assert_eq!(
+            ndi_uri_to_source("ndi://10.0.0.1:5961"),
+            Ok((String::new(), "10.0.0.1:5961".to_string()))
+        );
+        assert_eq!(
+            ndi_uri_to_source("ndi://[fd00::20]"),
+            Ok((String::new(), "[fd00::20]".to_string()))
+        );
+        assert_eq!(
+            ndi_uri_to_source("ndi://HOST%20(Camera%201)"),
+            Ok(("HOST (Camera 1)".to_string(), String::new()))
+        );
+        assert!(ndi_uri_to_source("ndi://").is_err());
+        assert!(ndi_uri_to_source("ndi://%zz").is_err());
+        assert!(ndi_uri_to_source("http://10.0.0.1").is_err());
+        assert_eq!(
+            ndi_uri_to_source(&source_to_ndi_uri("HOST (Camera 1)", "")),
+            Ok(("HOST (Camera 1)".to_string(), String::new()))
+        );
+    }
+
+    #[test]
+    fn percent_decoding_rejects_broken_escapes() {
+        assert_eq!(percent_decode("a%20b%2F"), Some("a b/".to_string()));
+        assert_eq!(percent_decode("plain"), Some("plain".to_string()));
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%g0"), None);
+        assert_eq!(percent_decode("%ff"), None);
+    }
+
+    #[test]
+    fn metadata_attributes_come_from_the_first_matching_tag() {
+        let data = r#"<ndi_tally_echo on_program="true" on_preview="false"/><ndi_product manufacturer="NewTek" model_name="PTZ1"/>"#;
+        assert_eq!(
+            metadata_attribute(data, "ndi_tally_echo", "on_preview"),
+            Some("false".to_string())
+        );
+        assert_eq!(
+            metadata_attribute(data, "ndi_product", "model_name"),
+            Some("PTZ1".to_string())
+        );
+        // Attributes of later tags don't count
+        assert_eq!(
+            metadata_attribute(data, "ndi_tally_echo", "manufacturer"),
+            None
+        );
+        assert_eq!(
+            metadata_attribute(data, "ndi_capabilities", "ntk_ptz"),
+            None
+        );
+    }
+
+    #[test]
+    fn sources_match_by_name_address_or_both() {
+        assert!(source_matches("HOST (A)", "10.0.0.1:5961", "HOST (A)", ""));
+        assert!(source_matches(
+            "HOST (A)",
+            "10.0.0.1:5961",
+            "",
+            "10.0.0.1:5961"
+        ));
+        assert!(source_matches(
+            "HOST (A)",
+            "10.0.0.1:5961",
+            "HOST (A)",
+            "10.0.0.1:5961"
+        ));
+        assert!(!source_matches(
+            "HOST (A)",
+            "10.0.0.1:5961",
+            "HOST (A)",
+            "10.0.0.2:5961"
+        ));
+        assert!(!source_matches("HOST (A)", "10.0.0.1:5961", "", ""));
+        assert!(!source_matches(
+            "HOST (A)",
+            "10.0.0.1:5961",
+            TEST_SOURCE,
+            ""
+        ));
+    }
+
+    #[test]
+    fn loss_threshold_bounds_the_empty_captures() {
+        // 0 reconnects on the first capture without a frame
+        assert!(source_lost(1, 0));
+        assert!(!source_lost(0, 0));
+        assert!(!source_lost(60, 60));
+        assert!(source_lost(61, 60));
+        assert!(!source_lost(3600, 3600));
+    }
+
+    #[test]
+    fn undefined_timestamps_fall_back_to_the_timecode() {
+        assert_eq!(ndi_frame_time("receive-timestamp", 100, 200), 200);
+        assert_eq!(ndi_frame_time("timecode", 100, 200), 100);
+        assert_eq!(
+            ndi_frame_time("receive-timestamp", 100, NDIlib_recv_timestamp_undefined),
+            100
+        );
+        assert!(timestamp_undefined(
+            "receive-timestamp",
+            NDIlib_recv_timestamp_undefined
+        ));
+        assert!(!timestamp_undefined(
+            "timecode",
+            NDIlib_recv_timestamp_undefined
+        ));
+    }
+
+    #[test]
+    fn timestamp_mode_values_round_trip() {
+        init();
+        for &timestamp_mode in &TIMESTAMP_MODES {
+            let value = timestamp_mode_to_value(timestamp_mode);
+            assert_eq!(value.type_(), *timestamp_mode_type);
+            assert_eq!(timestamp_mode_from_value(&value), Some(timestamp_mode));
+        }
+    }
+
+    #[test]
+    fn max_latency_is_unlimited_or_at_least_the_latency() {
+        let latency = gst::ClockTime::from(40_000_000);
+        assert_eq!(
+            max_latency(latency, u64::MAX),
+            (gst::CLOCK_TIME_NONE, false)
+        );
+        assert_eq!(max_latency(latency, 40_000_000), (latency, false));
+        assert_eq!(
+            max_latency(latency, 40_000_001),
+            (gst::ClockTime::from(40_000_001), false)
+        );
+        assert_eq!(max_latency(latency, 39_999_999), (latency, true));
+        assert_eq!(max_latency(latency, 0), (latency, true));
+    }
+
+    #[test]
+    fn ndi_is_initialized_again_after_the_last_release() {
+        let users = || *ndi_users.lock().unwrap();
+        // Like elements coming and going, or the plugin being loaded again
+        for _ in 0..3 {
+            assert!(ndi_acquire());
+            assert!(ndi_acquire());
+            ndi_release();
+            assert_eq!(users(), 1);
+            ndi_release();
+            assert_eq!(users(), 0);
+        }
+        // Releasing more often doesn't destroy NDI twice
+        ndi_release();
+        assert_eq!(users(), 0);
+    }
+}
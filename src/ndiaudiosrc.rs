@@ -3,6 +3,7 @@
 use glib;
 use glib::subclass;
 use glib::subclass::prelude::*;
+use glib::translate::ToGlib;
 use gst;
 use gst::prelude::*;
 use gst::subclass::prelude::*;
@@ -10,42 +11,129 @@ use gst_audio;
 use gst_base;
 use gst_base::prelude::*;
 use gst_base::subclass::prelude::*;
-
-use std::sync::Mutex;
-use std::{i32, u32};
-
-use std::ptr;
-
+use lazy_static;
+
+use std::cmp;
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use add_timecode_meta;
+use available_sources;
+use capture_frame;
+use changed_caps;
 use connect_ndi;
+use connection_info;
+use emit_metadata;
+use forget_discovered_sources;
+use max_latency;
+use ndi_frame_time;
 use ndi_struct;
+use ndi_uri_to_source;
 use ndisys::*;
+use nditest::{test_sender, TestSender, TEST_SOURCE};
+use ndiutil::audio_to_interleaved;
+use probe_format;
+use publish_source_info;
+use receiver_product;
+use receiver_shared;
+use receiver_source;
+use receiver_tags;
+use renegotiate;
+use select_policy_from_value;
+use select_policy_param_spec;
+use select_policy_to_value;
+use source_available;
+use source_lost;
+use source_to_ndi_uri;
 use stop_ndi;
+use timestamp_mode_from_value;
+use timestamp_mode_param_spec;
+use timestamp_mode_to_value;
+use timestamp_undefined;
+use update_clock_drift;
+use update_receiver_tags;
+use wait_for_queue;
+use web_control_url;
+use with_receiver;
+use PtsResume;
+use SOURCE_INFO_CONTEXT;
 
-use byte_slice_cast::AsMutSliceOf;
 use hashmap_receivers;
 
+// Shortest buffer-duration accepted, so tiny values don't make create() spin
+const MIN_BUFFER_DURATION: u64 = 1_000_000;
+
 #[derive(Debug, Clone)]
 struct Settings {
     stream_name: String,
     ip: String,
-    loss_threshold: u32,
-    id_receiver: i8,
+    receiver_name: String,
+    eos_on_disconnect: bool,
+    id_receiver: usize,
     latency: Option<gst::ClockTime>,
+    max_latency: u64,
+    timeout: u64,
+    select_policy: String,
+    silence_on_gap: bool,
+    channel_select: i32,
+    output_rate: u32,
+    queue_depth: u32,
+    attach_timecode_meta: bool,
+    buffer_duration: u64,
+    failover_source: String,
+    bind_address: String,
+    use_frame_timestamps: bool,
+    receive_video: bool,
+    warmup_frames: u32,
+    poll_interval: u32,
+    capture_timeout: u32,
+    config_path: String,
+    source_index: i32,
+    groups: String,
+    dump_first_frame_header: bool,
+    timestamp_mode: String,
+    discovery_timeout: u32,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Settings {
-            stream_name: String::from("Fixed ndi stream name"),
+            stream_name: String::from(""),
             ip: String::from(""),
-            loss_threshold: 5,
+            receiver_name: String::from(""),
+            eos_on_disconnect: false,
             id_receiver: 0,
             latency: None,
+            max_latency: u64::MAX,
+            timeout: 0,
+            select_policy: String::from("first"),
+            silence_on_gap: false,
+            channel_select: -1,
+            output_rate: 0,
+            queue_depth: 0,
+            attach_timecode_meta: false,
+            buffer_duration: 0,
+            failover_source: String::new(),
+            bind_address: String::new(),
+            use_frame_timestamps: true,
+            receive_video: true,
+            warmup_frames: 0,
+            poll_interval: 0,
+            capture_timeout: 1000,
+            config_path: String::new(),
+            source_index: -1,
+            groups: String::new(),
+            dump_first_frame_header: false,
+            timestamp_mode: String::from("receive-timestamp"),
+            discovery_timeout: 2000,
         }
     }
 }
 
-static PROPERTIES: [subclass::Property; 3] = [
+static PROPERTIES: [subclass::Property; 34] = [
     subclass::Property("stream-name", |_| {
         glib::ParamSpec::string(
             "stream-name",
@@ -59,7 +147,7 @@ static PROPERTIES: [subclass::Property; 3] = [
         glib::ParamSpec::string(
             "ip",
             "Stream IP",
-            "IP of the streaming device. Ex: 127.0.0.1:5961",
+            "IP of the streaming device. Ex: 127.0.0.1:5961 or [::1]:5961",
             None,
             glib::ParamFlags::READWRITE,
         )
@@ -68,27 +156,379 @@ static PROPERTIES: [subclass::Property; 3] = [
         glib::ParamSpec::uint(
             "loss-threshold",
             "Loss threshold",
-            "Loss threshold",
+            "Number of consecutive captures timing out after capture-timeout-ms before reconnecting to the source (0 = on the first one)",
             0,
-            60,
+            3600,
             5,
             glib::ParamFlags::READWRITE,
         )
     }),
+    subclass::Property("eos-on-disconnect", |_| {
+        glib::ParamSpec::boolean(
+            "eos-on-disconnect",
+            "EOS on disconnect",
+            "Send EOS instead of an error when the loss threshold is reached and the source is no longer visible on the network",
+            false,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("max-latency", |_| {
+        glib::ParamSpec::uint64(
+            "max-latency",
+            "Max latency",
+            "Maximum latency reported in the latency query, in nanoseconds (-1 = unlimited)",
+            0,
+            u64::MAX,
+            u64::MAX,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("available-sources", |_| {
+        glib::ParamSpec::string(
+            "available-sources",
+            "Available sources",
+            "Newline-separated names of the NDI sources currently visible on the network",
+            None,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("receiver-name", |_| {
+        glib::ParamSpec::string(
+            "receiver-name",
+            "Receiver Name",
+            "Name advertised to the NDI source for this receiver (defaults to the element name)",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("timeout", |_| {
+        glib::ParamSpec::uint64(
+            "timeout",
+            "Timeout",
+            "Post an error if no frame is received for this long, in nanoseconds (0 = disabled)",
+            0,
+            u64::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("uri", |_| {
+        glib::ParamSpec::string(
+            "uri",
+            "URI",
+            "NDI source as an ndi:// URI, e.g. ndi://HOST%20(Channel%201) or ndi://127.0.0.1:5961",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("select-policy", |_| {
+        select_policy_param_spec("How to pick between sources sharing the same name")
+    }),
+    subclass::Property("web-control-url", |_| {
+        glib::ParamSpec::string(
+            "web-control-url",
+            "Web Control URL",
+            "URL of the web configuration page advertised by the connected NDI source",
+            None,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("silence-on-gap", |_| {
+        glib::ParamSpec::boolean(
+            "silence-on-gap",
+            "Silence On Gap",
+            "Output silence when audio frames are late or arrive without samples instead of waiting for them",
+            false,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("channel-select", |_| {
+        glib::ParamSpec::int(
+            "channel-select",
+            "Channel Select",
+            "Zero-based index of the only channel to output (-1 = all channels, -2 = downmix to mono)",
+            -2,
+            i32::MAX,
+            -1,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("output-rate", |_| {
+        glib::ParamSpec::uint(
+            "output-rate",
+            "Output Rate",
+            "Resample the audio to this sample rate (0 = native rate of the source)",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("queue-depth", |_| {
+        glib::ParamSpec::uint(
+            "queue-depth",
+            "Queue Depth",
+            "Number of frames NDI buffers before the first buffer is pushed, added to the reported latency",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("attach-timecode-meta", |_| {
+        glib::ParamSpec::boolean(
+            "attach-timecode-meta",
+            "Attach timecode meta",
+            "Attach the sender's NDI timecode to each buffer as a reference timestamp (timestamp/x-ndi-timecode)",
+            false,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("buffer-duration", |_| {
+        glib::ParamSpec::uint64(
+            "buffer-duration",
+            "Buffer duration",
+            "Duration of the produced buffers in nanoseconds, adding up to that much latency (0 = one buffer per NDI frame)",
+            0,
+            u64::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("failover-source", |_| {
+        glib::ParamSpec::string(
+            "failover-source",
+            "Failover source",
+            "Name of the NDI source to connect to when the configured one can't be found",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("bind-address", |_| {
+        glib::ParamSpec::string(
+            "bind-address",
+            "Bind address",
+            "IP address of the local interface NDI should use, applied process-wide before NDI is initialized",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("use-frame-timestamps", |_| {
+        glib::ParamSpec::boolean(
+            "use-frame-timestamps",
+            "Use frame timestamps",
+            "Timestamp buffers from the NDI frame timestamps instead of their arrival time",
+            true,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("receive-video", |_| {
+        glib::ParamSpec::boolean(
+            "receive-video",
+            "Receive video",
+            "Also receive the source's video so an ndivideosrc can share the connection, audio only when disabled",
+            true,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("warmup-frames", |_| {
+        glib::ParamSpec::uint(
+            "warmup-frames",
+            "Warm-up Frames",
+            "Number of frames dropped after connecting, while the NDI stream settles",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("min-frame-samples", |_| {
+        glib::ParamSpec::uint(
+            "min-frame-samples",
+            "Minimum Frame Samples",
+            "Smallest number of samples per channel in an NDI audio frame since start",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("max-frame-samples", |_| {
+        glib::ParamSpec::uint(
+            "max-frame-samples",
+            "Maximum Frame Samples",
+            "Largest number of samples per channel in an NDI audio frame since start",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("poll-interval", |_| {
+        glib::ParamSpec::uint(
+            "poll-interval",
+            "Poll Interval",
+            "Longest wait for a frame in one go in ms, 0 waits for the whole capture-timeout-ms",
+            0,
+            1000,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("connection-info", |_| {
+        glib::ParamSpec::boxed(
+            "connection-info",
+            "Connection info",
+            "Source, address, URL, bandwidth, groups and negotiated caps of the current connection",
+            gst::Structure::static_type(),
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("capture-timeout-ms", |_| {
+        glib::ParamSpec::uint(
+            "capture-timeout-ms",
+            "Capture Timeout",
+            "How long to wait for a frame in ms before counting it as lost towards loss-threshold",
+            1,
+            60_000,
+            1000,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("config-path", |_| {
+        glib::ParamSpec::string(
+            "config-path",
+            "Config path",
+            "Directory with an ndi-config.v1.json to initialize NDI with, applied process-wide before NDI is initialized",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("clock-drift", |_| {
+        glib::ParamSpec::int64(
+            "clock-drift",
+            "Clock Drift",
+            "Average of how far the running time at delivery is ahead of the buffer timestamps, in nanoseconds",
+            i64::MIN,
+            i64::MAX,
+            0,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("source-index", |_| {
+        glib::ParamSpec::int(
+            "source-index",
+            "Source Index",
+            "Index of the source to connect to among those sharing the same name, ordered by URL (-1 = use select-policy)",
+            -1,
+            i32::MAX,
+            -1,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("groups", |_| {
+        glib::ParamSpec::string(
+            "groups",
+            "Groups",
+            "Comma separated NDI groups to look for the source in (empty = the groups NDI is configured with)",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("dump-first-frame-header", |_| {
+        glib::ParamSpec::boolean(
+            "dump-first-frame-header",
+            "Dump first frame header",
+            "Log every field of the first NDI audio frame header at TRACE level, for troubleshooting sources that look wrong",
+            false,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("timestamp-mode", |_| {
+        timestamp_mode_param_spec("What NDI frame time to timestamp buffers from")
+    }),
+    subclass::Property("discovery-timeout", |_| {
+        glib::ParamSpec::uint(
+            "discovery-timeout",
+            "Discovery timeout",
+            "How long to look for the source on the network before giving up, in milliseconds",
+            0,
+            u32::MAX,
+            2000,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
 ];
 
 struct State {
     info: Option<gst_audio::AudioInfo>,
+    adapter: gst_base::UniqueAdapter,
+    // Offset of the first sample in the adapter, and whether the next buffer
+    // taken from it is a discontinuity. The adapter keeps neither.
+    adapter_offset: u64,
+    adapter_discont: bool,
+    // Whether the first frame since set_caps() was compared against the caps
+    caps_checked: bool,
+    // Caps for the new format of a source that changed it, see renegotiate()
+    pending_caps: Option<gst::Caps>,
 }
 
 impl Default for State {
     fn default() -> State {
-        State { info: None }
+        State {
+            info: None,
+            adapter: gst_base::UniqueAdapter::new(),
+            adapter_offset: 0,
+            adapter_discont: false,
+            caps_checked: false,
+            pending_caps: None,
+        }
     }
 }
 
 struct TimestampData {
     offset: u64,
+    last_frame: Instant,
+    discont: bool,
+    // Frames dropped so far for warmup-frames
+    warmup_dropped: u32,
+    // Whether dump-first-frame-header already logged a frame
+    header_dumped: bool,
+    // Whether frames already came without a receive timestamp
+    timestamp_undefined: bool,
+    // Buffers in the clock-drift average so far
+    drift_samples: i64,
+    // PTS plus duration of the last buffer, where silence for a gap starts
+    next_pts: gst::ClockTime,
+    // Running time of the first buffer with an output-rate
+    clocked_start: Option<u64>,
+    // Where the frames of a new receiver go on from after reconnect()
+    resume: PtsResume,
+}
+
+impl Default for TimestampData {
+    fn default() -> TimestampData {
+        TimestampData {
+            offset: 0,
+            last_frame: Instant::now(),
+            discont: false,
+            warmup_dropped: 0,
+            header_dumped: false,
+            timestamp_undefined: false,
+            drift_samples: 0,
+            next_pts: gst::CLOCK_TIME_NONE,
+            clocked_start: None,
+            resume: Default::default(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref CAT: gst::DebugCategory = gst::DebugCategory::new(
+        "ndiaudiosrc",
+        gst::DebugColorFlags::empty(),
+        "NewTek NDI Audio Source",
+    );
 }
 
 struct NdiAudioSrc {
@@ -96,6 +536,136 @@ struct NdiAudioSrc {
     settings: Mutex<Settings>,
     state: Mutex<State>,
     timestamp_data: Mutex<TimestampData>,
+    test_sender: Mutex<Option<Arc<TestSender>>>,
+    // Resamples to output-rate, created on the first capture
+    framesync: Mutex<Option<FrameSync>>,
+    loss_threshold: AtomicU32,
+    // Set between unlock() and unlock_stop(), checked between captures
+    unlocked: AtomicBool,
+    // Set when a property needs a new receiver, which create() then connects
+    // between captures
+    reconnect_pending: AtomicBool,
+    clock_drift: AtomicI64,
+    // Smallest and largest no_samples since start(), 0 until the first frame
+    min_frame_samples: AtomicU32,
+    max_frame_samples: AtomicU32,
+    // The ndi-source-info context of the current connection, for context queries
+    source_info: Mutex<Option<gst::Context>>,
+    // The source's manufacturer and model as last sent in the stream tags
+    tags_product: Mutex<Option<(String, String)>>,
+    // Metadata received while capturing, emitted after the capture
+    pending_metadata: Mutex<Vec<gst::Structure>>,
+}
+
+impl NdiAudioSrc {
+    fn connect(&self, element: &gst_base::BaseSrc, settings: &mut Settings) -> bool {
+        let receiver_name = if settings.receiver_name.is_empty() {
+            element.get_name().to_string()
+        } else {
+            settings.receiver_name.clone()
+        };
+        if settings.stream_name == TEST_SOURCE {
+            let sender = test_sender();
+            if sender.is_none() {
+                gst_element_error!(
+                    element,
+                    gst::ResourceError::OpenRead,
+                    ["Could not start the NDI test pattern sender"]
+                );
+                return false;
+            }
+            *self
+                .test_sender
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = sender;
+        }
+        settings.id_receiver = connect_ndi(
+            self.cat,
+            element,
+            &settings.ip.clone(),
+            &settings.stream_name.clone(),
+            &receiver_name,
+            false,
+            false,
+            &settings.select_policy.clone(),
+            settings.source_index,
+            &settings.failover_source.clone(),
+            &settings.groups.clone(),
+            settings.discovery_timeout,
+            &settings.bind_address.clone(),
+            &settings.config_path.clone(),
+            settings.receive_video,
+            // The frame-sync is the only one to capture from the receiver then
+            settings.output_rate != 0,
+        );
+        if settings.id_receiver == 0 {
+            return false;
+        }
+
+        // Base sources hold a tag event back until after the segment
+        if let Some(tags) = receiver_tags(settings.id_receiver) {
+            element.send_event(gst::Event::new_tag(tags).build());
+        }
+        *self
+            .tags_product
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = receiver_product(settings.id_receiver);
+
+        *self
+            .source_info
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) =
+            Some(publish_source_info(element, settings.id_receiver, ""));
+        true
+    }
+
+    // Only called from create(), so no capture is using the receiver meanwhile
+    fn reconnect(&self, element: &gst_base::BaseSrc) -> bool {
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        if settings.id_receiver == 0 {
+            gst_warning!(self.cat, obj: element, "No NDI receiver to reconnect");
+            return false;
+        }
+        // Recreating it would pull it from under the other element, which
+        // keeps the id of the old one
+        if receiver_shared(settings.id_receiver) {
+            gst_warning!(
+                self.cat,
+                obj: element,
+                "Not reconnecting the NDI receiver shared with another element"
+            );
+            return false;
+        }
+
+        gst_debug!(self.cat, obj: element, "Reconnecting to the NDI source");
+        // The source may have gone away or moved
+        forget_discovered_sources();
+        *self
+            .framesync
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        stop_ndi(self.cat, element, settings.id_receiver);
+        let connected = self.connect(element, &mut settings);
+        if !connected {
+            gst_element_error!(
+                element,
+                gst::ResourceError::NotFound,
+                ["Could not reconnect to this source"]
+            );
+        }
+
+        let mut timestamp_data = self
+            .timestamp_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Timestamps go on from the last buffer instead of starting over
+        let resume = timestamp_data.resume.reconnected(timestamp_data.next_pts);
+        *timestamp_data = Default::default();
+        timestamp_data.discont = true;
+        timestamp_data.resume = resume;
+
+        connected
+    }
 }
 
 impl ObjectSubclass for NdiAudioSrc {
@@ -106,16 +676,27 @@ impl ObjectSubclass for NdiAudioSrc {
 
     glib_object_subclass!();
 
+    fn type_init(type_: &mut subclass::InitializingType<Self>) {
+        type_.add_interface::<gst::URIHandler>();
+    }
+
     fn new() -> Self {
         Self {
-            cat: gst::DebugCategory::new(
-                "ndiaudiosrc",
-                gst::DebugColorFlags::empty(),
-                "NewTek NDI Audio Source",
-            ),
+            cat: *CAT,
             settings: Mutex::new(Default::default()),
             state: Mutex::new(Default::default()),
-            timestamp_data: Mutex::new(TimestampData { offset: 0 }),
+            timestamp_data: Mutex::new(Default::default()),
+            test_sender: Mutex::new(None),
+            framesync: Mutex::new(None),
+            loss_threshold: AtomicU32::new(5),
+            unlocked: AtomicBool::new(false),
+            reconnect_pending: AtomicBool::new(false),
+            clock_drift: AtomicI64::new(0),
+            source_info: Mutex::new(None),
+            tags_product: Mutex::new(None),
+            pending_metadata: Mutex::new(Vec::new()),
+            min_frame_samples: AtomicU32::new(0),
+            max_frame_samples: AtomicU32::new(0),
         }
     }
 
@@ -133,10 +714,9 @@ impl ObjectSubclass for NdiAudioSrc {
                 (
                     "format",
                     &gst::List::new(&[
-                        //TODO add more formats?
-                        //&gst_audio::AUDIO_FORMAT_F32.to_string(),
-                        //&gst_audio::AUDIO_FORMAT_F64.to_string(),
                         &gst_audio::AUDIO_FORMAT_S16.to_string(),
+                        &gst_audio::AUDIO_FORMAT_S32.to_string(),
+                        &gst_audio::AUDIO_FORMAT_F32.to_string(),
                     ]),
                 ),
                 ("rate", &gst::IntRange::<i32>::new(1, i32::MAX)),
@@ -156,6 +736,51 @@ impl ObjectSubclass for NdiAudioSrc {
         klass.add_pad_template(src_pad_template);
 
         klass.install_properties(&PROPERTIES);
+
+        klass.add_signal_with_class_handler(
+            "probe-format",
+            glib::SignalFlags::RUN_LAST | glib::SignalFlags::ACTION,
+            &[],
+            gst::Structure::static_type(),
+            |_, args| {
+                let element = args[0].get::<gst_base::BaseSrc>().unwrap();
+                let src = NdiAudioSrc::from_instance(&element);
+                let (ip, stream_name) = {
+                    let settings = src.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                    (settings.ip.clone(), settings.stream_name.clone())
+                };
+
+                let format = probe_format(&ip, &stream_name);
+                gst_debug!(src.cat, obj: &element, "Probed source format {:?}", format);
+                Some(format.to_value())
+            },
+        );
+
+        klass.add_signal(
+            "format-changed",
+            glib::SignalFlags::RUN_LAST,
+            &[gst::Caps::static_type()],
+            glib::Type::Unit,
+        );
+
+        klass.add_signal(
+            "metadata-received",
+            glib::SignalFlags::RUN_LAST,
+            &[gst::Structure::static_type()],
+            glib::Type::Unit,
+        );
+
+        klass.add_signal_with_class_handler(
+            "reconnect",
+            glib::SignalFlags::RUN_LAST | glib::SignalFlags::ACTION,
+            &[],
+            bool::static_type(),
+            |_, args| {
+                let element = args[0].get::<gst_base::BaseSrc>().unwrap();
+                let src = NdiAudioSrc::from_instance(&element);
+                Some(src.reconnect(&element).to_value())
+            },
+        );
     }
 }
 
@@ -178,8 +803,8 @@ impl ObjectImpl for NdiAudioSrc {
 
         match *prop {
             subclass::Property("stream-name", ..) => {
-                let mut settings = self.settings.lock().unwrap();
-                let stream_name = value.get().unwrap();
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let stream_name = value.get().unwrap_or_default();
                 gst_debug!(
                     self.cat,
                     obj: basesrc,
@@ -191,8 +816,8 @@ impl ObjectImpl for NdiAudioSrc {
                 drop(settings);
             }
             subclass::Property("ip", ..) => {
-                let mut settings = self.settings.lock().unwrap();
-                let ip = value.get().unwrap();
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let ip = value.get().unwrap_or_default();
                 gst_debug!(
                     self.cat,
                     obj: basesrc,
@@ -204,165 +829,720 @@ impl ObjectImpl for NdiAudioSrc {
                 drop(settings);
             }
             subclass::Property("loss-threshold", ..) => {
-                let mut settings = self.settings.lock().unwrap();
+                // Not part of the settings, so a running create() sees the
+                // change right away
                 let loss_threshold = value.get().unwrap();
                 gst_debug!(
                     self.cat,
                     obj: basesrc,
                     "Changing loss threshold from {} to {}",
-                    settings.loss_threshold,
+                    self.loss_threshold.load(Ordering::SeqCst),
                     loss_threshold
                 );
-                settings.loss_threshold = loss_threshold;
-                drop(settings);
+                self.loss_threshold.store(loss_threshold, Ordering::SeqCst);
             }
-            _ => unimplemented!(),
-        }
-    }
-
-    fn get_property(&self, _obj: &glib::Object, id: usize) -> Result<glib::Value, ()> {
-        let prop = &PROPERTIES[id];
-
-        match *prop {
-            subclass::Property("stream-name", ..) => {
-                let settings = self.settings.lock().unwrap();
-                Ok(settings.stream_name.to_value())
+            subclass::Property("eos-on-disconnect", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let eos_on_disconnect = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing eos-on-disconnect from {} to {}",
+                    settings.eos_on_disconnect,
+                    eos_on_disconnect
+                );
+                settings.eos_on_disconnect = eos_on_disconnect;
+                drop(settings);
             }
-            subclass::Property("ip", ..) => {
-                let settings = self.settings.lock().unwrap();
-                Ok(settings.ip.to_value())
+            subclass::Property("max-latency", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let max_latency = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing max latency from {} to {}",
+                    settings.max_latency,
+                    max_latency
+                );
+                settings.max_latency = max_latency;
+                drop(settings);
             }
-            subclass::Property("loss-threshold", ..) => {
-                let settings = self.settings.lock().unwrap();
-                Ok(settings.loss_threshold.to_value())
+            subclass::Property("receiver-name", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let receiver_name = value.get().unwrap_or_default();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing receiver-name from {} to {}",
+                    settings.receiver_name,
+                    receiver_name
+                );
+                settings.receiver_name = receiver_name;
+                drop(settings);
             }
-            _ => unimplemented!(),
-        }
-    }
-}
-
-impl ElementImpl for NdiAudioSrc {
-    fn change_state(
-        &self,
-        element: &gst::Element,
-        transition: gst::StateChange,
-    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
-        if transition == gst::StateChange::PausedToPlaying {
-            let mut receivers = hashmap_receivers.lock().unwrap();
-            let settings = self.settings.lock().unwrap();
-
-            let receiver = receivers.get_mut(&settings.id_receiver).unwrap();
-            let recv = &receiver.ndi_instance;
-            let pNDI_recv = recv.recv;
-
-            let audio_frame: NDIlib_audio_frame_v2_t = Default::default();
-
-            unsafe {
-                while NDIlib_recv_capture_v2(
-                    pNDI_recv,
-                    ptr::null(),
-                    &audio_frame,
-                    ptr::null(),
-                    1000,
-                ) != NDIlib_frame_type_e::NDIlib_frame_type_audio
-                {}
+            subclass::Property("timeout", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let timeout = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing timeout from {} to {}",
+                    settings.timeout,
+                    timeout
+                );
+                settings.timeout = timeout;
+                drop(settings);
             }
-            gst_debug!(
-                self.cat,
-                obj: element,
-                "NDI audio frame received: {:?}",
-                audio_frame
-            );
-
-            if receiver.initial_timestamp <= audio_frame.timestamp as u64
-                || receiver.initial_timestamp == 0
-            {
-                receiver.initial_timestamp = audio_frame.timestamp as u64;
+            subclass::Property("uri", ..) => {
+                let uri: String = value.get().unwrap_or_default();
+                match ndi_uri_to_source(&uri) {
+                    Ok((stream_name, ip)) => {
+                        let mut settings =
+                            self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                        gst_debug!(
+                            self.cat,
+                            obj: basesrc,
+                            "Changing uri to {} (stream-name '{}', ip '{}')",
+                            uri,
+                            stream_name,
+                            ip
+                        );
+                        settings.stream_name = stream_name;
+                        settings.ip = ip;
+                    }
+                    Err(err) => gst_warning!(self.cat, obj: basesrc, "{}", err),
+                }
             }
-            unsafe {
-                NDIlib_recv_free_audio_v2(pNDI_recv, &audio_frame);
+            subclass::Property("select-policy", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                if let Some(select_policy) = select_policy_from_value(value) {
+                    gst_debug!(
+                        self.cat,
+                        obj: basesrc,
+                        "Changing select-policy from {} to {}",
+                        settings.select_policy,
+                        select_policy
+                    );
+                    settings.select_policy = select_policy.to_string();
+                }
+                drop(settings);
             }
-            gst_debug!(
-                self.cat,
-                obj: element,
-                "Setting initial timestamp to {}",
-                receiver.initial_timestamp
-            );
-        }
-        self.parent_change_state(element, transition)
-    }
-}
-
-impl BaseSrcImpl for NdiAudioSrc {
-    fn set_caps(
-        &self,
-        element: &gst_base::BaseSrc,
-        caps: &gst::CapsRef,
-    ) -> Result<(), gst::LoggableError> {
-        let info = match gst_audio::AudioInfo::from_caps(caps) {
-            None => {
-                return Err(gst_loggable_error!(
+            subclass::Property("silence-on-gap", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let silence_on_gap = value.get().unwrap();
+                gst_debug!(
                     self.cat,
-                    "Failed to build `AudioInfo` from caps {}",
-                    caps
-                ));
+                    obj: basesrc,
+                    "Changing silence-on-gap from {} to {}",
+                    settings.silence_on_gap,
+                    silence_on_gap
+                );
+                settings.silence_on_gap = silence_on_gap;
+                drop(settings);
             }
-            Some(info) => info,
-        };
+            subclass::Property("channel-select", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let channel_select = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing channel-select from {} to {}",
+                    settings.channel_select,
+                    channel_select
+                );
+                settings.channel_select = channel_select;
+                drop(settings);
+            }
+            subclass::Property("output-rate", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let output_rate = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing output-rate from {} to {}",
+                    settings.output_rate,
+                    output_rate
+                );
+                settings.output_rate = output_rate;
+                drop(settings);
+            }
+            subclass::Property("queue-depth", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let queue_depth = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing queue-depth from {} to {}",
+                    settings.queue_depth,
+                    queue_depth
+                );
+                settings.queue_depth = queue_depth;
+                drop(settings);
+            }
+            subclass::Property("attach-timecode-meta", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let attach_timecode_meta = value.get().unwrap();
+                if attach_timecode_meta && !cfg!(feature = "v1_14") {
+                    gst_warning!(
+                        self.cat,
+                        obj: basesrc,
+                        "Timecode meta needs GStreamer 1.14, build with the v1_14 feature"
+                    );
+                }
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing attach-timecode-meta from {} to {}",
+                    settings.attach_timecode_meta,
+                    attach_timecode_meta
+                );
+                settings.attach_timecode_meta = attach_timecode_meta;
+                drop(settings);
+            }
+            subclass::Property("buffer-duration", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut buffer_duration = value.get().unwrap();
+                if buffer_duration != 0 && buffer_duration < MIN_BUFFER_DURATION {
+                    gst_warning!(
+                        self.cat,
+                        obj: basesrc,
+                        "Buffer duration {} is too short, using {}",
+                        buffer_duration,
+                        MIN_BUFFER_DURATION
+                    );
+                    buffer_duration = MIN_BUFFER_DURATION;
+                }
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing buffer-duration from {} to {}",
+                    settings.buffer_duration,
+                    buffer_duration
+                );
+                settings.buffer_duration = buffer_duration;
+                drop(settings);
+            }
+            subclass::Property("failover-source", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let failover_source = value.get().unwrap_or_default();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing failover-source from {} to {}",
+                    settings.failover_source,
+                    failover_source
+                );
+                settings.failover_source = failover_source;
+                drop(settings);
+            }
+            subclass::Property("bind-address", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let bind_address = value.get().unwrap_or_default();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing bind-address from {} to {}",
+                    settings.bind_address,
+                    bind_address
+                );
+                settings.bind_address = bind_address;
+                drop(settings);
+            }
+            subclass::Property("use-frame-timestamps", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let use_frame_timestamps = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing use-frame-timestamps from {} to {}",
+                    settings.use_frame_timestamps,
+                    use_frame_timestamps
+                );
+                settings.use_frame_timestamps = use_frame_timestamps;
+                drop(settings);
+                // BaseSrc only timestamps buffers that come without a PTS
+                basesrc.set_do_timestamp(!use_frame_timestamps);
+            }
+            subclass::Property("receive-video", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let receive_video = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing receive-video from {} to {}",
+                    settings.receive_video,
+                    receive_video
+                );
+                let changed = settings.receive_video != receive_video;
+                settings.receive_video = receive_video;
+                let connected = settings.id_receiver != 0;
+                drop(settings);
+                // The bandwidth is fixed when the receiver is created
+                if changed && connected {
+                    self.reconnect_pending.store(true, Ordering::SeqCst);
+                }
+            }
+            subclass::Property("warmup-frames", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let warmup_frames = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing warmup-frames from {} to {}",
+                    settings.warmup_frames,
+                    warmup_frames
+                );
+                settings.warmup_frames = warmup_frames;
+                drop(settings);
+            }
+            subclass::Property("poll-interval", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let poll_interval = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing poll-interval from {} to {}",
+                    settings.poll_interval,
+                    poll_interval
+                );
+                settings.poll_interval = poll_interval;
+                drop(settings);
+            }
+            subclass::Property("capture-timeout-ms", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let capture_timeout = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing capture-timeout-ms from {} to {}",
+                    settings.capture_timeout,
+                    capture_timeout
+                );
+                settings.capture_timeout = capture_timeout;
+                drop(settings);
+            }
+            subclass::Property("config-path", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let config_path = value.get().unwrap_or_default();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing config-path from {} to {}",
+                    settings.config_path,
+                    config_path
+                );
+                settings.config_path = config_path;
+                drop(settings);
+            }
+            subclass::Property("source-index", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let source_index = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing source-index from {} to {}",
+                    settings.source_index,
+                    source_index
+                );
+                settings.source_index = source_index;
+                drop(settings);
+            }
+            subclass::Property("groups", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let groups = value.get().unwrap_or_default();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing groups from {} to {}",
+                    settings.groups,
+                    groups
+                );
+                settings.groups = groups;
+                drop(settings);
+            }
+            subclass::Property("dump-first-frame-header", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let dump_first_frame_header = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing dump-first-frame-header from {} to {}",
+                    settings.dump_first_frame_header,
+                    dump_first_frame_header
+                );
+                settings.dump_first_frame_header = dump_first_frame_header;
+                drop(settings);
+            }
+            subclass::Property("timestamp-mode", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                if let Some(timestamp_mode) = timestamp_mode_from_value(value) {
+                    gst_debug!(
+                        self.cat,
+                        obj: basesrc,
+                        "Changing timestamp-mode from {} to {}",
+                        settings.timestamp_mode,
+                        timestamp_mode
+                    );
+                    settings.timestamp_mode = timestamp_mode.to_string();
+                }
+                drop(settings);
+            }
+            subclass::Property("discovery-timeout", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let discovery_timeout = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing discovery-timeout from {} to {}",
+                    settings.discovery_timeout,
+                    discovery_timeout
+                );
+                settings.discovery_timeout = discovery_timeout;
+                drop(settings);
+            }
+            _ => unimplemented!(),
+        }
+    }
 
-        gst_debug!(self.cat, obj: element, "Configuring for caps {}", caps);
+    fn get_property(&self, obj: &glib::Object, id: usize) -> Result<glib::Value, ()> {
+        let prop = &PROPERTIES[id];
 
-        let mut state = self.state.lock().unwrap();
-        state.info = Some(info);
+        match *prop {
+            subclass::Property("stream-name", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.stream_name.to_value())
+            }
+            subclass::Property("ip", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.ip.to_value())
+            }
+            subclass::Property("loss-threshold", ..) => {
+                Ok(self.loss_threshold.load(Ordering::SeqCst).to_value())
+            }
+            subclass::Property("eos-on-disconnect", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.eos_on_disconnect.to_value())
+            }
+            subclass::Property("max-latency", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.max_latency.to_value())
+            }
+            subclass::Property("available-sources", ..) => Ok(available_sources().to_value()),
+            subclass::Property("receiver-name", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.receiver_name.to_value())
+            }
+            subclass::Property("timeout", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.timeout.to_value())
+            }
+            subclass::Property("uri", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(source_to_ndi_uri(&settings.stream_name, &settings.ip).to_value())
+            }
+            subclass::Property("select-policy", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(select_policy_to_value(&settings.select_policy))
+            }
+            subclass::Property("web-control-url", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(web_control_url(settings.id_receiver).to_value())
+            }
+            subclass::Property("silence-on-gap", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.silence_on_gap.to_value())
+            }
+            subclass::Property("channel-select", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.channel_select.to_value())
+            }
+            subclass::Property("output-rate", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.output_rate.to_value())
+            }
+            subclass::Property("queue-depth", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.queue_depth.to_value())
+            }
+            subclass::Property("attach-timecode-meta", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.attach_timecode_meta.to_value())
+            }
+            subclass::Property("buffer-duration", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.buffer_duration.to_value())
+            }
+            subclass::Property("failover-source", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.failover_source.to_value())
+            }
+            subclass::Property("bind-address", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.bind_address.to_value())
+            }
+            subclass::Property("use-frame-timestamps", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.use_frame_timestamps.to_value())
+            }
+            subclass::Property("receive-video", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.receive_video.to_value())
+            }
+            subclass::Property("warmup-frames", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.warmup_frames.to_value())
+            }
+            subclass::Property("min-frame-samples", ..) => {
+                Ok(self.min_frame_samples.load(Ordering::SeqCst).to_value())
+            }
+            subclass::Property("max-frame-samples", ..) => {
+                Ok(self.max_frame_samples.load(Ordering::SeqCst).to_value())
+            }
+            subclass::Property("poll-interval", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.poll_interval.to_value())
+            }
+            subclass::Property("connection-info", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut info = connection_info(settings.id_receiver);
+                drop(settings);
+                let caps = obj
+                    .downcast_ref::<gst_base::BaseSrc>()
+                    .and_then(|basesrc| basesrc.get_static_pad("src"))
+                    .and_then(|pad| pad.get_current_caps());
+                if let Some(caps) = caps {
+                    info.set("caps", &caps);
+                }
+                Ok(info.to_value())
+            }
+            subclass::Property("capture-timeout-ms", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.capture_timeout.to_value())
+            }
+            subclass::Property("config-path", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.config_path.to_value())
+            }
+            subclass::Property("clock-drift", ..) => {
+                Ok(self.clock_drift.load(Ordering::SeqCst).to_value())
+            }
+            subclass::Property("source-index", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.source_index.to_value())
+            }
+            subclass::Property("groups", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.groups.to_value())
+            }
+            subclass::Property("dump-first-frame-header", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.dump_first_frame_header.to_value())
+            }
+            subclass::Property("timestamp-mode", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(timestamp_mode_to_value(&settings.timestamp_mode))
+            }
+            subclass::Property("discovery-timeout", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.discovery_timeout.to_value())
+            }
+            _ => unimplemented!(),
+        }
+    }
+}
+
+impl ElementImpl for NdiAudioSrc {}
+
+impl URIHandlerImpl for NdiAudioSrc {
+    fn get_uri(&self, _element: &gst::URIHandler) -> Option<String> {
+        let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        Some(source_to_ndi_uri(&settings.stream_name, &settings.ip))
+    }
+
+    fn set_uri(&self, _element: &gst::URIHandler, uri: Option<String>) -> Result<(), glib::Error> {
+        let uri = uri.unwrap_or_default();
+        let (stream_name, ip) =
+            ndi_uri_to_source(&uri).map_err(|err| glib::Error::new(gst::URIError::BadUri, &err))?;
+
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        gst_debug!(
+            self.cat,
+            "Setting uri to {} (stream-name '{}', ip '{}')",
+            uri,
+            stream_name,
+            ip
+        );
+        settings.stream_name = stream_name;
+        settings.ip = ip;
 
         Ok(())
     }
 
-    fn start(&self, element: &gst_base::BaseSrc) -> Result<(), gst::ErrorMessage> {
-        *self.state.lock().unwrap() = Default::default();
+    fn get_uri_type() -> gst::URIType {
+        gst::URIType::Src
+    }
 
-        let mut settings = self.settings.lock().unwrap();
-        settings.id_receiver = connect_ndi(
+    fn get_protocols() -> Vec<String> {
+        vec!["ndi".to_string()]
+    }
+}
+
+impl BaseSrcImpl for NdiAudioSrc {
+    fn set_caps(
+        &self,
+        element: &gst_base::BaseSrc,
+        caps: &gst::CapsRef,
+    ) -> Result<(), gst::LoggableError> {
+        let info = match gst_audio::AudioInfo::from_caps(caps) {
+            None => {
+                return Err(gst_loggable_error!(
+                    self.cat,
+                    "Failed to build `AudioInfo` from caps {}",
+                    caps
+                ));
+            }
+            Some(info) => info,
+        };
+
+        gst_debug!(self.cat, obj: element, "Configuring for caps {}", caps);
+
+        let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        let (source_name, source_ip) = receiver_source(settings.id_receiver).unwrap_or_default();
+        drop(settings);
+        gst_info!(
             self.cat,
-            element,
-            &settings.ip.clone(),
-            &settings.stream_name.clone(),
+            obj: element,
+            "Negotiated NDI source '{}' at '{}': {} {} Hz, {} channels",
+            source_name,
+            source_ip,
+            // The format's Display recurses, its name doesn't
+            info.format().to_string(),
+            info.rate(),
+            info.channels()
         );
 
-        match settings.id_receiver {
-            0 => Err(gst_error_msg!(
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.info = Some(info);
+        state.caps_checked = false;
+
+        Ok(())
+    }
+
+    fn start(&self, element: &gst_base::BaseSrc) -> Result<(), gst::ErrorMessage> {
+        *self.state.lock().unwrap_or_else(PoisonError::into_inner) = Default::default();
+        *self
+            .timestamp_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Default::default();
+        self.min_frame_samples.store(0, Ordering::SeqCst);
+        self.max_frame_samples.store(0, Ordering::SeqCst);
+        self.clock_drift.store(0, Ordering::SeqCst);
+
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        if settings.stream_name.is_empty() && settings.ip.is_empty() {
+            return Err(gst_error_msg!(
+                gst::ResourceError::Settings,
+                ["Neither stream-name nor ip set; nothing to connect to"]
+            ));
+        }
+        if self.connect(element, &mut settings) {
+            Ok(())
+        } else {
+            Err(gst_error_msg!(
                 gst::ResourceError::NotFound,
                 ["Could not connect to this source"]
-            )),
-            _ => Ok(()),
+            ))
         }
     }
 
     fn stop(&self, element: &gst_base::BaseSrc) -> Result<(), gst::ErrorMessage> {
-        *self.state.lock().unwrap() = Default::default();
+        *self.state.lock().unwrap_or_else(PoisonError::into_inner) = Default::default();
 
-        let settings = self.settings.lock().unwrap();
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        *self
+            .framesync
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
         stop_ndi(self.cat, element, settings.id_receiver);
+        settings.id_receiver = 0;
+        self.reconnect_pending.store(false, Ordering::SeqCst);
+        *self
+            .source_info
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        *self
+            .tags_product
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        self.pending_metadata
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        *self
+            .test_sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
         // Commented because when adding ndi destroy stopped in this line
-        //*self.state.lock().unwrap() = Default::default();
+        //*self.state.lock().unwrap_or_else(PoisonError::into_inner) = Default::default();
         Ok(())
     }
 
     fn query(&self, element: &gst_base::BaseSrc, query: &mut gst::QueryRef) -> bool {
         use gst::QueryView;
+        if let QueryView::Context(ref mut q) = query.view_mut() {
+            if q.get_context_type() == SOURCE_INFO_CONTEXT {
+                if let Some(ref context) = *self
+                    .source_info
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                {
+                    q.set_context(context);
+                    return true;
+                }
+            }
+        }
         if let QueryView::Scheduling(ref mut q) = query.view_mut() {
             q.set(gst::SchedulingFlags::SEQUENTIAL, 1, -1, 0);
             q.add_scheduling_modes(&[gst::PadMode::Push]);
             return true;
         }
+        if let QueryView::Seeking(ref mut q) = query.view_mut() {
+            let format = q.get_format();
+            q.set(
+                false,
+                gst::GenericFormattedValue::new(format, -1),
+                gst::GenericFormattedValue::new(format, -1),
+            );
+            return true;
+        }
         if let QueryView::Latency(ref mut q) = query.view_mut() {
-            let settings = &*self.settings.lock().unwrap();
-            let state = self.state.lock().unwrap();
-
-            if let Some(ref _info) = state.info {
-                let latency = settings.latency.unwrap();
-                gst_debug!(self.cat, obj: element, "Returning latency {}", latency);
-                q.set(true, latency, gst::CLOCK_TIME_NONE);
+            let settings = &*self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+            let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+            // The frame latency is only known once fixate() saw a frame
+            if let (&Some(_), Some(frame_latency)) = (&state.info, settings.latency) {
+                // Each queued frame adds one frame duration of latency, and
+                // collecting longer buffers adds their duration
+                let latency = frame_latency * (u64::from(settings.queue_depth) + 1)
+                    + gst::ClockTime::from(settings.buffer_duration);
+                let (max_latency, clamped) = max_latency(latency, settings.max_latency);
+                if clamped {
+                    gst_warning!(
+                        self.cat,
+                        obj: element,
+                        "Max latency {} is lower than latency {}, clamping",
+                        gst::ClockTime::from(settings.max_latency),
+                        latency
+                    );
+                }
+                gst_debug!(
+                    self.cat,
+                    obj: element,
+                    "Returning latency {} (max {})",
+                    latency,
+                    max_latency
+                );
+                q.set(true, latency, max_latency);
                 return true;
             } else {
                 return false;
@@ -371,47 +1551,195 @@ impl BaseSrcImpl for NdiAudioSrc {
         BaseSrcImplExt::parent_query(self, element, query)
     }
 
-    fn fixate(&self, element: &gst_base::BaseSrc, caps: gst::Caps) -> gst::Caps {
-        let receivers = hashmap_receivers.lock().unwrap();
-        let mut settings = self.settings.lock().unwrap();
+    fn unlock(&self, element: &gst_base::BaseSrc) -> Result<(), gst::ErrorMessage> {
+        gst_debug!(self.cat, obj: element, "Unlocking");
+        self.unlocked.store(true, Ordering::SeqCst);
+        Ok(())
+    }
 
-        let receiver = receivers.get(&settings.id_receiver).unwrap();
+    fn unlock_stop(&self, element: &gst_base::BaseSrc) -> Result<(), gst::ErrorMessage> {
+        gst_debug!(self.cat, obj: element, "Stop unlocking");
+        self.unlocked.store(false, Ordering::SeqCst);
+        Ok(())
+    }
 
-        let recv = &receiver.ndi_instance;
-        let pNDI_recv = recv.recv;
+    fn is_seekable(&self, _element: &gst_base::BaseSrc) -> bool {
+        false
+    }
 
-        let audio_frame: NDIlib_audio_frame_v2_t = Default::default();
+    fn event(&self, element: &gst_base::BaseSrc, event: &gst::Event) -> bool {
+        use gst::EventView;
+        match event.view() {
+            EventView::Seek(_) => {
+                gst_debug!(self.cat, obj: element, "Refusing seek on live NDI source");
+                return false;
+            }
+            // A live source picks up wherever NDI is after a flush: the
+            // offsets start over and the next buffer is a discontinuity
+            EventView::FlushStop(_) => {
+                gst_debug!(self.cat, obj: element, "Flushed, resetting timestamps");
+                let mut timestamp_data = self
+                    .timestamp_data
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                // The warm-up is about the first frames of the connection,
+                // which a flush doesn't start over
+                *timestamp_data = TimestampData {
+                    discont: true,
+                    warmup_dropped: timestamp_data.warmup_dropped,
+                    ..Default::default()
+                };
+                self.state
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .adapter
+                    .clear();
+            }
+            _ => (),
+        }
+        BaseSrcImplExt::parent_event(self, element, event)
+    }
 
-        unsafe {
-            while NDIlib_recv_capture_v2(pNDI_recv, ptr::null(), &audio_frame, ptr::null(), 1000)
-                != NDIlib_frame_type_e::NDIlib_frame_type_audio
-            {}
+    fn fixate(&self, element: &gst_base::BaseSrc, caps: gst::Caps) -> gst::Caps {
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let ndi_instance = match hashmap_receivers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&settings.id_receiver)
+        {
+            Some(receiver) => receiver.ndi_instance.clone(),
+            None => {
+                // Caps can be negotiated before start(), fall back to the
+                // template values then
+                gst_debug!(
+                    self.cat,
+                    obj: element,
+                    "No NDI receiver yet, fixating caps without the source format"
+                );
+                drop(settings);
+                return self.parent_fixate(element, caps);
+            }
+        };
+        let recv = ndi_instance.read().unwrap_or_else(PoisonError::into_inner);
+
+        // The receiver can't be captured from next to a frame-sync, the next
+        // capture creates a new one
+        *self
+            .framesync
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+
+        let mut audio_frame: NDIlib_audio_frame_v2_t = Default::default();
+
+        // Given up on like capturing, so neither stopping nor a source that
+        // sends nothing keeps the negotiation waiting with the locks held
+        let mut count_frame_none = 0;
+        loop {
+            match recv.capture_audio(&mut audio_frame, settings.capture_timeout) {
+                NDIlib_frame_type_e::NDIlib_frame_type_audio => break,
+                NDIlib_frame_type_e::NDIlib_frame_type_none
+                | NDIlib_frame_type_e::NDIlib_frame_type_error => count_frame_none += 1,
+                _ => (),
+            }
+            if self.unlocked.load(Ordering::SeqCst) {
+                gst_debug!(
+                    self.cat,
+                    obj: element,
+                    "Unlocked while waiting for a frame, fixating caps without the source format"
+                );
+                drop(settings);
+                return self.parent_fixate(element, caps);
+            }
+            if source_lost(count_frame_none, self.loss_threshold.load(Ordering::SeqCst)) {
+                gst_element_error!(
+                    element,
+                    gst::CoreError::Negotiation,
+                    ["No NDI audio frame received to negotiate the format with"]
+                );
+                return gst::Caps::new_empty();
+            }
         }
 
         let no_samples = audio_frame.no_samples as u64;
-        let audio_rate = audio_frame.sample_rate;
-        settings.latency = gst::SECOND.mul_div_floor(no_samples, audio_rate as u64);
+        let source_rate = audio_frame.sample_rate;
+        let audio_rate = if settings.output_rate != 0 {
+            settings.output_rate as i32
+        } else {
+            source_rate
+        };
+        settings.latency = gst::SECOND.mul_div_floor(no_samples, source_rate as u64);
 
         let mut caps = gst::Caps::truncate(caps);
-        {
+        let accepted = {
             let caps = caps.make_mut();
             let s = caps.get_mut_structure(0).unwrap();
+
+            // Only produce float when downstream asks for it first, otherwise
+            // let NDI convert to S16 for us
+            let formats = match s.get_value("format").and_then(|v| v.get::<gst::List>()) {
+                Some(list) => list
+                    .as_slice()
+                    .iter()
+                    .filter_map(|v| v.get::<String>())
+                    .collect::<Vec<_>>(),
+                None => Vec::new(),
+            };
+            let f32_format = gst_audio::AUDIO_FORMAT_F32.to_string();
+            let s16_format = gst_audio::AUDIO_FORMAT_S16.to_string();
+            if formats.first().map(String::as_str) == Some(f32_format) {
+                s.fixate_field_str("format", f32_format);
+            } else if formats.iter().any(|f| f == s16_format) {
+                s.fixate_field_str("format", s16_format);
+            }
+
             s.fixate_field_nearest_int("rate", audio_rate);
-            s.fixate_field_nearest_int("channels", audio_frame.no_channels);
+            let channels = if settings.channel_select == -1 {
+                audio_frame.no_channels
+            } else {
+                1
+            };
+            s.fixate_field_nearest_int("channels", channels);
             s.fixate_field_str("layout", "interleaved");
+            // There are only standard layouts for up to 8 channels, larger
+            // sources like Dante or AES67 bridges are sent positionless
+            let channel_mask = if channels > 8 {
+                0
+            } else {
+                gst_audio::AudioChannelPosition::get_fallback_mask(channels as u32)
+            };
             s.set_value(
                 "channel-mask",
-                gst::Bitmask::new(gst_audio::AudioChannelPosition::get_fallback_mask(
-                    audio_frame.no_channels as u32,
-                ))
-                .to_send_value(),
+                gst::Bitmask::new(channel_mask).to_send_value(),
             );
+
+            // We can't convert rate or channels, so downstream has to accept
+            // what the source sends
+            if s.get::<i32>("rate") != Some(audio_rate)
+                || s.get::<i32>("channels") != Some(channels)
+            {
+                gst_element_error!(
+                    element,
+                    gst::CoreError::Negotiation,
+                    [
+                        "Downstream doesn't accept the NDI source's format of {} Hz with {} channels",
+                        audio_rate,
+                        channels
+                    ]
+                );
+                false
+            } else {
+                true
+            }
+        };
+        recv.free_audio(&audio_frame);
+
+        // Empty caps aren't fixed, which fails the negotiation
+        if !accepted {
+            return gst::Caps::new_empty();
         }
 
         let _ = element.post_message(&gst::Message::new_latency().src(Some(element)).build());
-        unsafe {
-            NDIlib_recv_free_audio_v2(pNDI_recv, &audio_frame);
-        }
 
         self.parent_fixate(element, caps)
     }
@@ -422,62 +1750,586 @@ impl BaseSrcImpl for NdiAudioSrc {
         _offset: u64,
         _length: u32,
     ) -> Result<gst::Buffer, gst::FlowError> {
-        let _settings = &*self.settings.lock().unwrap();
+        let buffer_duration = self
+            .settings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .buffer_duration;
+        if buffer_duration == 0 {
+            return self.capture_or_reconnect(element);
+        }
 
-        let mut timestamp_data = self.timestamp_data.lock().unwrap();
+        // Collect whole NDI frames until there's enough for one buffer of the
+        // configured duration
+        loop {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let (rate, bpf) = match state.info {
+                None => {
+                    gst_element_error!(element, gst::CoreError::Negotiation, ["Have no caps yet"]);
+                    return Err(gst::FlowError::NotNegotiated);
+                }
+                Some(ref info) => (u64::from(info.rate()), info.bpf() as usize),
+            };
+            let no_samples = cmp::max(
+                buffer_duration
+                    .mul_div_floor(rate, gst::SECOND_VAL)
+                    .unwrap_or(1),
+                1,
+            );
+            let size = no_samples as usize * bpf;
 
-        let state = self.state.lock().unwrap();
-        let _info = match state.info {
+            if state.adapter.available() >= size {
+                let (pts, distance) = state.adapter.prev_pts();
+                let offset = state.adapter_offset;
+                let mut buffer = state.adapter.take_buffer(size).unwrap();
+                {
+                    let buffer = buffer.get_mut().unwrap();
+                    let skipped = distance / bpf as u64;
+                    buffer.set_pts(
+                        pts + gst::ClockTime::from(skipped.mul_div_floor(gst::SECOND_VAL, rate)),
+                    );
+                    buffer.set_duration(gst::ClockTime::from(
+                        no_samples.mul_div_floor(gst::SECOND_VAL, rate),
+                    ));
+                    buffer.set_offset(offset);
+                    buffer.set_offset_end(offset + no_samples);
+                    if state.adapter_discont {
+                        buffer.set_flags(gst::BufferFlags::DISCONT);
+                        state.adapter_discont = false;
+                    }
+                }
+                state.adapter_offset += no_samples;
+                return Ok(buffer);
+            }
+            drop(state);
+
+            let buffer = self.capture_or_reconnect(element)?;
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            // Pass gaps on right away instead of waiting for more data, unless
+            // they'd overtake samples already collected
+            if buffer.get_size() == 0
+                || (buffer.get_flags().contains(gst::BufferFlags::GAP)
+                    && state.adapter.available() == 0)
+            {
+                return Ok(buffer);
+            }
+            if buffer.get_flags().contains(gst::BufferFlags::DISCONT) {
+                // Samples from before the discontinuity would be mistimed in
+                // one buffer with those after it
+                if state.adapter.available() > 0 {
+                    gst_debug!(
+                        self.cat,
+                        obj: element,
+                        "Discontinuity, dropping {} collected bytes",
+                        state.adapter.available()
+                    );
+                    state.adapter.clear();
+                }
+                state.adapter_discont = true;
+            }
+            if state.adapter.available() == 0 {
+                state.adapter_offset = buffer.get_offset();
+            }
+            state.adapter.push(buffer);
+        }
+    }
+}
+
+impl NdiAudioSrc {
+    // capture_buffer() gives up with CustomError after loss-threshold captures
+    // without a frame or when the source changed its address. The receiver is
+    // recreated then and capturing goes on. When the source changed its format
+    // it returns CustomError1 with pending_caps set, which are negotiated before
+    // capturing the next frame.
+    fn capture_or_reconnect(
+        &self,
+        element: &gst_base::BaseSrc,
+    ) -> Result<gst::Buffer, gst::FlowError> {
+        loop {
+            if self.reconnect_pending.swap(false, Ordering::SeqCst) {
+                gst_debug!(self.cat, obj: element, "Reconnecting for the changed properties");
+                self.reconnect(element);
+            }
+            let resampled = self
+                .settings
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .output_rate
+                != 0;
+            let res = if resampled {
+                self.capture_resampled(element)
+            } else {
+                self.capture_buffer(element)
+            };
+            emit_metadata(element, &self.pending_metadata);
+            match res {
+                Err(gst::FlowError::CustomError) => {
+                    let id = self
+                        .settings
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .id_receiver;
+                    // NDI keeps trying to get the source back on its own
+                    if receiver_shared(id) {
+                        gst_warning!(
+                            self.cat,
+                            obj: element,
+                            "Lost the NDI source, waiting for the shared receiver to get it back"
+                        );
+                        continue;
+                    }
+                    gst_warning!(self.cat, obj: element, "Lost the NDI source, reconnecting");
+                    if !self.reconnect(element) {
+                        return Err(gst::FlowError::Error);
+                    }
+                }
+                Err(gst::FlowError::CustomError1) => {
+                    let caps = self
+                        .state
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .pending_caps
+                        .take();
+                    let renegotiated = match caps {
+                        Some(caps) => renegotiate(self.cat, element, &caps),
+                        None => false,
+                    };
+                    if !renegotiated {
+                        return Err(gst::FlowError::NotNegotiated);
+                    }
+                }
+                Ok(buffer) => {
+                    let id = self
+                        .settings
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .id_receiver;
+                    update_receiver_tags(element, id, &self.tags_product);
+                    return Ok(buffer);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    // With an output-rate the audio comes from an NDI frame-sync resampling it
+    // to that rate. The frame-sync only hands over what it received and fills
+    // the rest with silence, so each buffer of one source frame's duration is
+    // captured once the pipeline clock passed its end.
+    fn capture_resampled(
+        &self,
+        element: &gst_base::BaseSrc,
+    ) -> Result<gst::Buffer, gst::FlowError> {
+        // A copy, so that properties can be set while waiting for a frame
+        let _settings = &self
+            .settings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let mut timestamp_data = self
+            .timestamp_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let info = match state.info {
             None => {
                 gst_element_error!(element, gst::CoreError::Negotiation, ["Have no caps yet"]);
                 return Err(gst::FlowError::NotNegotiated);
             }
             Some(ref info) => info.clone(),
         };
-        let receivers = hashmap_receivers.lock().unwrap();
+        drop(state);
+        let (clock, base_time) = match element.get_clock() {
+            Some(clock) => (clock, element.get_base_time()),
+            None => {
+                gst_element_error!(element, gst::CoreError::Clock, ["Have no clock"]);
+                return Err(gst::FlowError::Error);
+            }
+        };
+
+        let mut framesync = self
+            .framesync
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if framesync.is_none() {
+            *framesync = with_receiver(_settings.id_receiver, |recv| unsafe {
+                FrameSync::new(recv)
+            });
+        }
+        let framesync = match *framesync {
+            Some(ref framesync) => framesync,
+            None => {
+                gst_element_error!(
+                    element,
+                    gst::CoreError::Failed,
+                    ["Can't create an NDI frame-sync"]
+                );
+                return Err(gst::FlowError::Error);
+            }
+        };
+
+        let rate = u64::from(info.rate());
+        // One source frame's worth as measured in fixate(), 10 ms without one
+        let no_samples = _settings
+            .latency
+            .and_then(|latency| latency.nseconds())
+            .and_then(|latency| latency.mul_div_floor(rate, gst::SECOND_VAL))
+            .filter(|&no_samples| no_samples > 0)
+            .unwrap_or_else(|| cmp::max(rate / 100, 1));
+        let sample_time = |offset: u64| offset.mul_div_floor(gst::SECOND_VAL, rate).unwrap_or(0);
+        let running_time = || (clock.get_time() - base_time).nseconds().unwrap_or(0);
+
+        let start = match timestamp_data.clocked_start {
+            Some(start) => start,
+            None => running_time(),
+        };
+        timestamp_data.clocked_start = Some(start);
+        let offset = timestamp_data.offset;
+        let pts = start + sample_time(offset);
+        let due = start + sample_time(offset + no_samples);
+        loop {
+            if self.unlocked.load(Ordering::SeqCst) {
+                gst_debug!(self.cat, obj: element, "Unlocked while waiting for samples");
+                return Err(gst::FlowError::Flushing);
+            }
+            let now = running_time();
+            if now >= due {
+                break;
+            }
+            thread::sleep(cmp::min(
+                Duration::from_nanos(due - now),
+                Duration::from_millis(10),
+            ));
+        }
+
+        // With the source's channels, which are selected or remixed below
+        let mut audio_frame: NDIlib_audio_frame_v2_t = Default::default();
+        framesync.capture_audio(&mut audio_frame, info.rate() as i32, 0, no_samples as i32);
+        let have_samples = !audio_frame.p_data.is_null()
+            && audio_frame.no_channels > 0
+            && audio_frame.no_samples as u64 == no_samples;
+        if have_samples && _settings.channel_select >= audio_frame.no_channels {
+            framesync.free_audio(&mut audio_frame);
+            gst_element_error!(
+                element,
+                gst::StreamError::Format,
+                [
+                    "Channel {} selected but the NDI source only has {} channels",
+                    _settings.channel_select,
+                    audio_frame.no_channels
+                ]
+            );
+            return Err(gst::FlowError::NotNegotiated);
+        }
+
+        // All supported formats are silent at zero
+        let mut buffer =
+            gst::Buffer::from_mut_slice(vec![0u8; no_samples as usize * info.bpf() as usize]);
+        {
+            let buffer = buffer.get_mut().unwrap();
+            let res = if !have_samples {
+                Ok(())
+            } else {
+                let mut map = buffer.map_writable().unwrap();
+                unsafe {
+                    audio_to_interleaved(
+                        info.format(),
+                        &audio_frame,
+                        _settings.channel_select,
+                        info.channels() as usize,
+                        no_samples as usize,
+                        map.as_mut_slice(),
+                    )
+                }
+            };
+            framesync.free_audio(&mut audio_frame);
+            if let Err(err) = res {
+                gst_element_error!(
+                    element,
+                    gst::StreamError::Failed,
+                    ["Failed to convert the NDI audio samples: {}", err]
+                );
+                return Err(gst::FlowError::Error);
+            }
+
+            buffer.set_pts(gst::ClockTime::from(pts));
+            buffer.set_duration(gst::ClockTime::from(
+                sample_time(offset + no_samples) - sample_time(offset),
+            ));
+            let mut flags = gst::BufferFlags::empty();
+            if !have_samples {
+                flags |= gst::BufferFlags::GAP;
+            }
+            if timestamp_data.discont {
+                flags |= gst::BufferFlags::DISCONT;
+                timestamp_data.discont = false;
+            }
+            buffer.set_flags(flags);
+            buffer.set_offset(offset);
+            buffer.set_offset_end(offset + no_samples);
+        }
+        timestamp_data.offset += no_samples;
+        timestamp_data.next_pts = gst::ClockTime::from(due);
+
+        gst_log!(self.cat, obj: element, "Produced buffer {:?}", buffer);
+        Ok(buffer)
+    }
+
+    fn capture_buffer(&self, element: &gst_base::BaseSrc) -> Result<gst::Buffer, gst::FlowError> {
+        let (id, queue_depth) = {
+            let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+            (settings.id_receiver, settings.queue_depth)
+        };
+        let first = self
+            .timestamp_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .offset
+            == 0;
+        if queue_depth != 0 && first {
+            wait_for_queue(self.cat, element, id, queue_depth, false, &self.unlocked)?;
+        }
+
+        // A copy, so that properties can be set while waiting for a frame
+        let _settings = &self
+            .settings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
 
-        let recv = &receivers.get(&_settings.id_receiver).unwrap().ndi_instance;
-        let pNDI_recv = recv.recv;
+        let mut timestamp_data = self
+            .timestamp_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let info = match state.info {
+            None => {
+                gst_element_error!(element, gst::CoreError::Negotiation, ["Have no caps yet"]);
+                return Err(gst::FlowError::NotNegotiated);
+            }
+            Some(ref info) => info.clone(),
+        };
+        let receivers = hashmap_receivers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let receiver = match receivers.get(&_settings.id_receiver) {
+            Some(receiver) => receiver,
+            None => {
+                gst_element_error!(
+                    element,
+                    gst::CoreError::Failed,
+                    ["Not connected to an NDI source"]
+                );
+                return Err(gst::FlowError::Error);
+            }
+        };
+        let ndi_instance = receiver.ndi_instance.clone();
+        let initial_timestamp = if _settings.timestamp_mode == "timecode" {
+            receiver.initial_timecode.clone()
+        } else {
+            receiver.initial_timestamp.clone()
+        };
+        let source_ip = receiver.ip.clone();
+        let source_name = receiver.stream_name.clone();
+        let product = receiver.product.clone();
+        drop(receivers);
+
+        // Hold the receiver read lock for the whole capture so stop_ndi() can't
+        // destroy it under us
+        let recv = ndi_instance.read().unwrap_or_else(PoisonError::into_inner);
 
         let pts: u64;
-        let audio_frame: NDIlib_audio_frame_v2_t = Default::default();
+        let mut audio_frame: NDIlib_audio_frame_v2_t = Default::default();
 
         unsafe {
-            let time = receivers
-                .get(&_settings.id_receiver)
-                .unwrap()
-                .initial_timestamp;
-
+            let mut time = initial_timestamp.load(Ordering::SeqCst);
             let mut skip_frame = true;
             let mut count_frame_none = 0;
+            // A lost frame is a capture-timeout without one, made up of
+            // several captures with a poll-interval
+            let poll_in_ms = if _settings.poll_interval != 0 {
+                cmp::min(_settings.poll_interval, _settings.capture_timeout)
+            } else {
+                _settings.capture_timeout
+            };
+            let mut waited_ms = 0;
             while skip_frame {
-                let frame_type =
-                    NDIlib_recv_capture_v2(pNDI_recv, ptr::null(), &audio_frame, ptr::null(), 1000);
-                if (frame_type == NDIlib_frame_type_e::NDIlib_frame_type_none
-                    && _settings.loss_threshold != 0)
+                // Once audio is flowing, silence-on-gap only waits one frame
+                // for the next one and fills in silence otherwise, until the
+                // source has been quiet for as long as loss-threshold allows
+                let loss_window = Duration::from_millis(
+                    u64::from(_settings.capture_timeout)
+                        * (u64::from(self.loss_threshold.load(Ordering::SeqCst)) + 1),
+                );
+                let fill_gap = _settings.silence_on_gap
+                    && timestamp_data.offset != 0
+                    && timestamp_data.last_frame.elapsed() < loss_window;
+                let timeout_in_ms = match _settings.latency.and_then(|l| l.mseconds()) {
+                    Some(frame_ms) if fill_gap => cmp::max(frame_ms, 1) as u32,
+                    _ => poll_in_ms,
+                };
+                let mut frame_type = capture_frame(
+                    self.cat,
+                    element,
+                    &**recv,
+                    &product,
+                    &self.pending_metadata,
+                    None,
+                    Some(&mut audio_frame),
+                    timeout_in_ms,
+                );
+                if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_audio
+                    && (audio_frame.p_data.is_null() || audio_frame.no_samples <= 0)
+                {
+                    recv.free_audio(&audio_frame);
+                    if _settings.silence_on_gap {
+                        gst_debug!(
+                            self.cat,
+                            obj: element,
+                            "Audio frame without samples received, sending silence"
+                        );
+                        let mut buffer =
+                            silence_buffer(&info, _settings.latency, &mut timestamp_data);
+                        if !_settings.use_frame_timestamps {
+                            buffer.make_mut().set_pts(gst::CLOCK_TIME_NONE);
+                        }
+                        return Ok(buffer);
+                    }
+                    gst_debug!(self.cat, obj: element, "Audio frame without samples received");
+                    frame_type = NDIlib_frame_type_e::NDIlib_frame_type_none;
+                }
+                if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_audio {
+                    timestamp_data.last_frame = Instant::now();
+                } else if _settings.timeout != 0
+                    && timestamp_data.last_frame.elapsed()
+                        >= Duration::from_nanos(_settings.timeout)
+                {
+                    gst_element_error!(
+                        element,
+                        gst::ResourceError::Read,
+                        [
+                            "No NDI audio frame received in {} ms",
+                            _settings.timeout / 1_000_000
+                        ]
+                    );
+                    return Err(gst::FlowError::Error);
+                }
+                // The source moved, e.g. after a DHCP renewal. Reconnecting
+                // looks it up again and connects to its new address.
+                if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_source_change {
+                    gst_info!(
+                        self.cat,
+                        obj: element,
+                        "NDI source '{}' at '{}' changed its address",
+                        source_name,
+                        source_ip
+                    );
+                    return Err(gst::FlowError::CustomError);
+                }
+                if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_none && fill_gap {
+                    gst_debug!(self.cat, obj: element, "No NDI audio frame in time, sending silence");
+                    let mut buffer = silence_buffer(&info, _settings.latency, &mut timestamp_data);
+                    if !_settings.use_frame_timestamps {
+                        buffer.make_mut().set_pts(gst::CLOCK_TIME_NONE);
+                    }
+                    return Ok(buffer);
+                }
+                if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_none
                     || frame_type == NDIlib_frame_type_e::NDIlib_frame_type_error
                 {
-                    if count_frame_none < _settings.loss_threshold {
-                        count_frame_none += 1;
+                    if self.unlocked.load(Ordering::SeqCst) {
+                        gst_debug!(self.cat, obj: element, "Unlocked while waiting for a frame");
+                        return Err(gst::FlowError::Flushing);
+                    }
+                    waited_ms += poll_in_ms;
+                    if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_none
+                        && waited_ms < _settings.capture_timeout
+                    {
                         continue;
                     }
-                    gst_element_error!(element, gst::ResourceError::Read, ["NDI frame type none or error received, assuming that the source closed the stream...."]);
+                    waited_ms = 0;
+                    count_frame_none += 1;
+                    let loss_threshold = self.loss_threshold.load(Ordering::SeqCst);
+                    if !source_lost(count_frame_none, loss_threshold) {
+                        gst_debug!(
+                            self.cat,
+                            obj: element,
+                            "No NDI audio frame in {} ms, retry {} of {}",
+                            _settings.capture_timeout,
+                            count_frame_none,
+                            loss_threshold
+                        );
+                        continue;
+                    }
+                    if _settings.eos_on_disconnect
+                        && !source_available(&**recv, &source_ip, &source_name)
+                    {
+                        gst_debug!(
+                            self.cat,
+                            obj: element,
+                            "NDI source is no longer available, sending EOS"
+                        );
+                        return Err(gst::FlowError::Eos);
+                    }
+                    gst_debug!(
+                        self.cat,
+                        obj: element,
+                        "No NDI audio frame in {} captures",
+                        count_frame_none
+                    );
                     return Err(gst::FlowError::CustomError);
-                } else if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_none
-                    && _settings.loss_threshold == 0
-                {
+                }
+
+                // Dropped before the initial timestamp is taken, so the first
+                // real buffer still starts at zero
+                if timestamp_data.warmup_dropped < _settings.warmup_frames {
+                    timestamp_data.warmup_dropped += 1;
+                    recv.free_audio(&audio_frame);
                     gst_debug!(
                         self.cat,
                         obj: element,
-                        "No audio frame received, sending empty buffer"
+                        "Dropping warm-up frame {} of {}",
+                        timestamp_data.warmup_dropped,
+                        _settings.warmup_frames
                     );
-                    let buffer = gst::Buffer::with_size(0).unwrap();
-                    return Ok(buffer);
+                    count_frame_none = 0;
+                    waited_ms = 0;
+                    continue;
                 }
 
-                if time >= (audio_frame.timestamp as u64) {
-                    NDIlib_recv_free_audio_v2(pNDI_recv, &audio_frame);
-                    gst_debug!(self.cat, obj: element, "Frame timestamp ({:?}) is lower than received in the first frame from NDI ({:?}), so skiping...", (audio_frame.timestamp as u64), time);
+                let frame_time = ndi_frame_time(
+                    &_settings.timestamp_mode,
+                    audio_frame.timecode,
+                    audio_frame.timestamp,
+                );
+
+                // The first frame captured by any element sharing this receiver
+                // sets the initial timestamp
+                if time == 0 {
+                    time = match initial_timestamp.compare_exchange(
+                        0,
+                        frame_time,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    ) {
+                        Ok(_) => {
+                            gst_debug!(
+                                self.cat,
+                                obj: element,
+                                "Setting initial timestamp to {}",
+                                frame_time
+                            );
+                            frame_time
+                        }
+                        Err(time) => time,
+                    };
+                }
+
+                if time > frame_time {
+                    recv.free_audio(&audio_frame);
+                    gst_debug!(self.cat, obj: element, "Frame timestamp ({:?}) is lower than received in the first frame from NDI ({:?}), so skiping...", frame_time, time);
                 } else {
                     skip_frame = false;
                 }
@@ -489,8 +2341,38 @@ impl BaseSrcImpl for NdiAudioSrc {
                 "NDI audio frame received: {:?}",
                 (audio_frame)
             );
+            if _settings.dump_first_frame_header && !timestamp_data.header_dumped {
+                timestamp_data.header_dumped = true;
+                dump_frame_header(self.cat, element, &audio_frame);
+            }
 
-            pts = audio_frame.timestamp as u64 - time;
+            if timestamp_undefined(&_settings.timestamp_mode, audio_frame.timestamp)
+                && !timestamp_data.timestamp_undefined
+            {
+                timestamp_data.timestamp_undefined = true;
+                gst_warning!(
+                    self.cat,
+                    obj: element,
+                    "Source doesn't timestamp its frames, using their timecode instead"
+                );
+            }
+            pts = ndi_frame_time(
+                &_settings.timestamp_mode,
+                audio_frame.timecode,
+                audio_frame.timestamp,
+            ) - time;
+
+            // Only this streaming thread updates them
+            let frame_samples = audio_frame.no_samples as u32;
+            let min_frame_samples = self.min_frame_samples.load(Ordering::SeqCst);
+            if min_frame_samples == 0 || frame_samples < min_frame_samples {
+                self.min_frame_samples
+                    .store(frame_samples, Ordering::SeqCst);
+            }
+            if frame_samples > self.max_frame_samples.load(Ordering::SeqCst) {
+                self.max_frame_samples
+                    .store(frame_samples, Ordering::SeqCst);
+            }
 
             gst_log!(
                 self.cat,
@@ -499,41 +2381,150 @@ impl BaseSrcImpl for NdiAudioSrc {
                 (pts)
             );
 
-            // We multiply by 2 because is the size in bytes of an i16 variable
-            let buff_size = (audio_frame.no_samples * 2 * audio_frame.no_channels) as usize;
-            let mut buffer = gst::Buffer::with_size(buff_size).unwrap();
+            // Caps forced from downstream can disagree with the source rate,
+            // which would play the audio at the wrong speed. Channels are
+            // remixed, so they may differ.
+            if !state.caps_checked {
+                if audio_frame.sample_rate != info.rate() as i32 {
+                    recv.free_audio(&audio_frame);
+                    gst_element_error!(
+                        element,
+                        gst::CoreError::Negotiation,
+                        [
+                            "Negotiated caps don't match the NDI source: {} Hz negotiated, source sends {} Hz",
+                            info.rate(),
+                            audio_frame.sample_rate
+                        ]
+                    );
+                    return Err(gst::FlowError::NotNegotiated);
+                }
+                state.caps_checked = true;
+            } else if audio_frame.sample_rate != info.rate() as i32 {
+                let sample_rate = audio_frame.sample_rate;
+                state.pending_caps = changed_caps(element, |s| s.set("rate", &sample_rate));
+                // Samples collected for buffer-duration are at the old rate
+                state.adapter.clear();
+                recv.free_audio(&audio_frame);
+                return Err(gst::FlowError::CustomError1);
+            }
+
+            // Checked before the frame gets its offset, which a dropped frame
+            // mustn't advance
+            if _settings.channel_select >= audio_frame.no_channels {
+                recv.free_audio(&audio_frame);
+                gst_element_error!(
+                    element,
+                    gst::StreamError::Format,
+                    [
+                        "Channel {} selected but the NDI source only has {} channels",
+                        _settings.channel_select,
+                        audio_frame.no_channels
+                    ]
+                );
+                return Err(gst::FlowError::NotNegotiated);
+            }
+
+            let no_samples = audio_frame.no_samples as u64;
+            // Whole frames of samples, so the sample slices always fit exactly
+            let buff_size = no_samples as usize * info.bpf() as usize;
+            let mut buffer = match gst::Buffer::with_size(buff_size) {
+                Some(buffer) => buffer,
+                None => {
+                    recv.free_audio(&audio_frame);
+                    gst_element_error!(
+                        element,
+                        gst::ResourceError::Failed,
+                        ["Failed to allocate a buffer of {} bytes", buff_size]
+                    );
+                    return Err(gst::FlowError::Error);
+                }
+            };
             {
                 if ndi_struct.start_pts == gst::ClockTime(Some(0)) {
-                    ndi_struct.start_pts =
-                        element.get_clock().unwrap().get_time() - element.get_base_time();
+                    ndi_struct.start_pts = match element.get_clock() {
+                        Some(clock) => clock.get_time() - element.get_base_time(),
+                        None => {
+                            recv.free_audio(&audio_frame);
+                            gst_element_error!(element, gst::CoreError::Clock, ["Have no clock"]);
+                            return Err(gst::FlowError::Error);
+                        }
+                    };
                 }
 
                 let buffer = buffer.get_mut().unwrap();
 
                 // Newtek NDI yields times in 100ns intervals since the Unix Time
                 let pts: gst::ClockTime = (pts * 100).into();
-                buffer.set_pts(pts + ndi_struct.start_pts);
+                if _settings.use_frame_timestamps {
+                    buffer.set_pts(timestamp_data.resume.apply(pts + ndi_struct.start_pts));
+                    update_clock_drift(
+                        element,
+                        buffer,
+                        &mut timestamp_data.drift_samples,
+                        &self.clock_drift,
+                    );
+                }
 
-                let duration: gst::ClockTime = (((f64::from(audio_frame.no_samples)
-                    / f64::from(audio_frame.sample_rate))
+                let duration: gst::ClockTime = (((no_samples as f64 / f64::from(info.rate()))
                     * 1_000_000_000.0) as u64)
                     .into();
                 buffer.set_duration(duration);
+                timestamp_data.next_pts = buffer.get_pts() + duration;
+                if _settings.attach_timecode_meta {
+                    add_timecode_meta(buffer, audio_frame.timecode, duration);
+                }
 
+                if timestamp_data.discont {
+                    buffer.set_flags(gst::BufferFlags::DISCONT);
+                    timestamp_data.discont = false;
+                }
                 buffer.set_offset(timestamp_data.offset);
-                timestamp_data.offset += audio_frame.no_samples as u64;
+                timestamp_data.offset += no_samples;
                 buffer.set_offset_end(timestamp_data.offset);
 
-                let mut dst: NDIlib_audio_frame_interleaved_16s_t = Default::default();
-                dst.reference_level = 0;
-                dst.p_data = buffer
-                    .map_writable()
-                    .unwrap()
-                    .as_mut_slice_of::<i16>()
-                    .unwrap()
-                    .as_mut_ptr();
-                NDIlib_util_audio_to_interleaved_16s_v2(&audio_frame, &mut dst);
-                NDIlib_recv_free_audio_v2(pNDI_recv, &audio_frame);
+                // Remixed to the negotiated channels while converting
+                if _settings.channel_select == -1
+                    && audio_frame.no_channels != info.channels() as i32
+                {
+                    gst_debug!(
+                        self.cat,
+                        obj: element,
+                        "NDI source sends {} channels instead of {}, remixing",
+                        audio_frame.no_channels,
+                        info.channels()
+                    );
+                }
+
+                let mut map = match buffer.map_writable() {
+                    Some(map) => map,
+                    None => {
+                        recv.free_audio(&audio_frame);
+                        gst_element_error!(
+                            element,
+                            gst::ResourceError::Failed,
+                            ["Failed to map the output buffer writable"]
+                        );
+                        return Err(gst::FlowError::Error);
+                    }
+                };
+                let res = audio_to_interleaved(
+                    info.format(),
+                    &audio_frame,
+                    _settings.channel_select,
+                    info.channels() as usize,
+                    no_samples as usize,
+                    map.as_mut_slice(),
+                );
+                drop(map);
+                recv.free_audio(&audio_frame);
+                if let Err(err) = res {
+                    gst_element_error!(
+                        element,
+                        gst::StreamError::Failed,
+                        ["Failed to convert the NDI audio samples: {}", err]
+                    );
+                    return Err(gst::FlowError::Error);
+                }
             }
 
             gst_log!(self.cat, obj: element, "Produced buffer {:?}", buffer);
@@ -543,6 +2534,473 @@ impl BaseSrcImpl for NdiAudioSrc {
     }
 }
 
+// Produces a GAP buffer of silence covering one frame's worth of audio,
+// continuing from the end of the last buffer
+unsafe fn silence_buffer(
+    info: &gst_audio::AudioInfo,
+    latency: Option<gst::ClockTime>,
+    timestamp_data: &mut TimestampData,
+) -> gst::Buffer {
+    let rate = u64::from(info.rate());
+    let no_samples = latency
+        .and_then(|latency| latency.nseconds())
+        .and_then(|latency| latency.mul_div_floor(rate, gst::SECOND_VAL))
+        .unwrap_or(0);
+
+    // All supported formats are silent at zero
+    let mut buffer =
+        gst::Buffer::from_mut_slice(vec![0u8; no_samples as usize * info.bpf() as usize]);
+    {
+        let buffer = buffer.get_mut().unwrap();
+        // Before the first frame, go by the samples so far, or on from the
+        // previous receiver after a reconnect
+        let pts = if timestamp_data.next_pts.is_some() {
+            timestamp_data.next_pts
+        } else if timestamp_data.resume.pending().is_some() {
+            timestamp_data.resume.pending()
+        } else {
+            let pts = match timestamp_data.offset.mul_div_floor(gst::SECOND_VAL, rate) {
+                Some(pts) => pts,
+                None => {
+                    // The offset can't be expressed as a time anymore, so
+                    // count from zero again
+                    timestamp_data.offset = 0;
+                    timestamp_data.discont = true;
+                    0
+                }
+            };
+            gst::ClockTime::from(pts) + ndi_struct.start_pts
+        };
+        buffer.set_pts(pts);
+        let duration: gst::ClockTime = no_samples.mul_div_floor(gst::SECOND_VAL, rate).into();
+        buffer.set_duration(duration);
+        timestamp_data.next_pts = pts + duration;
+        timestamp_data.resume.skip(pts + duration);
+
+        let mut flags = gst::BufferFlags::GAP;
+        if timestamp_data.discont {
+            flags |= gst::BufferFlags::DISCONT;
+            timestamp_data.discont = false;
+        }
+        buffer.set_flags(flags);
+        buffer.set_offset(timestamp_data.offset);
+        timestamp_data.offset += no_samples;
+        buffer.set_offset_end(timestamp_data.offset);
+    }
+    buffer
+}
+
+// Logs the whole header of an audio frame, metadata included, for
+// dump-first-frame-header
+unsafe fn dump_frame_header(
+    cat: gst::DebugCategory,
+    element: &gst_base::BaseSrc,
+    audio_frame: &NDIlib_audio_frame_v2_t,
+) {
+    let metadata = if audio_frame.p_metadata.is_null() {
+        "".into()
+    } else {
+        CStr::from_ptr(audio_frame.p_metadata).to_string_lossy()
+    };
+    gst_trace!(
+        cat,
+        obj: element,
+        "First NDI audio frame header: sample_rate {}, no_channels {}, no_samples {}, \
+         timecode {}, p_data {:?}, channel_stride_in_bytes {}, timestamp {}, p_metadata {:?}",
+        audio_frame.sample_rate,
+        audio_frame.no_channels,
+        audio_frame.no_samples,
+        audio_frame.timecode,
+        audio_frame.p_data,
+        audio_frame.channel_stride_in_bytes,
+        audio_frame.timestamp,
+        metadata
+    );
+}
+
 pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
-    gst::Element::register(Some(plugin), "ndiaudiosrc", 0, NdiAudioSrc::get_type())
+    // Registers the debug category before any element exists
+    lazy_static::initialize(&CAT);
+    // Not ranked, so ndi:// URIs go to ndivideosrc. ndiaudiosrc still handles
+    // them when created by name.
+    gst::Element::register(
+        Some(plugin),
+        "ndiaudiosrc",
+        gst::Rank::None.to_glib() as u32,
+        NdiAudioSrc::get_type(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{add_fake_receiver, init};
+
+    // Silence for the scripted frames to point to, enough for 10 ms of 32
+    // channels at 48 kHz
+    static SAMPLES: [f32; 480 * 32] = [0.0; 480 * 32];
+
+    // 10 ms of `no_channels` at 48 kHz, received at `timestamp`
+    fn audio_frame(no_channels: i32, timestamp: i64) -> NDIlib_audio_frame_v2_t {
+        NDIlib_audio_frame_v2_t {
+            sample_rate: 48_000,
+            no_channels,
+            no_samples: 480,
+            timestamp,
+            p_data: SAMPLES.as_ptr(),
+            channel_stride_in_bytes: 480 * 4,
+            ..Default::default()
+        }
+    }
+
+    // `count` frames of 10 ms, one after the other
+    fn audio_frames(no_channels: i32, count: usize) -> Vec<FakeFrame> {
+        (0..count)
+            .map(|i| FakeFrame::Audio(audio_frame(no_channels, 10_000_000 + i as i64 * 100_000)))
+            .collect()
+    }
+
+    // An ndiaudiosrc capturing from a fake receiver with `frames`, as if it was
+    // started and negotiated to S16 with `channels` at 48 kHz
+    fn audio_src(frames: Vec<FakeFrame>, channels: u32) -> (gst_base::BaseSrc, usize) {
+        init();
+        let element = glib::Object::new(NdiAudioSrc::get_type(), &[])
+            .unwrap()
+            .downcast::<gst_base::BaseSrc>()
+            .unwrap();
+        let id = add_fake_receiver(FakeReceiver::new(frames), false, true);
+        let src = NdiAudioSrc::from_instance(&element);
+        {
+            let mut settings = src.settings.lock().unwrap();
+            settings.id_receiver = id;
+            settings.latency = Some(gst::ClockTime::from_mseconds(10));
+        }
+        src.state.lock().unwrap().info =
+            gst_audio::AudioInfo::new(gst_audio::AUDIO_FORMAT_S16, 48_000, channels).build();
+        element.set_clock(&gst::SystemClock::obtain()).unwrap();
+        (element, id)
+    }
+
+    fn create(element: &gst_base::BaseSrc) -> Result<gst::Buffer, gst::FlowError> {
+        NdiAudioSrc::from_instance(element).create(element, u64::MAX, 0)
+    }
+
+    fn remove_src(id: usize) {
+        hashmap_receivers.lock().unwrap().remove(&id);
+    }
+
+    #[test]
+    fn frames_are_pulled_from_the_receiver() {
+        let (element, id) = audio_src(audio_frames(2, 2), 2);
+        let first = create(&element).unwrap();
+        let second = create(&element).unwrap();
+        assert_eq!(first.get_size(), 480 * 2 * 2);
+        assert_eq!(first.get_duration(), gst::ClockTime::from_mseconds(10));
+        assert_eq!(
+            second.get_pts(),
+            first.get_pts() + gst::ClockTime::from_mseconds(10)
+        );
+        remove_src(id);
+    }
+
+    #[test]
+    fn frames_without_samples_become_silent_gaps() {
+        let mut frames = audio_frames(2, 3);
+        if let FakeFrame::Audio(ref mut frame) = frames[1] {
+            frame.p_data = std::ptr::null();
+        }
+        let (element, id) = audio_src(frames, 2);
+        NdiAudioSrc::from_instance(&element)
+            .settings
+            .lock()
+            .unwrap()
+            .silence_on_gap = true;
+        let first = create(&element).unwrap();
+        let gap = create(&element).unwrap();
+        assert!(!first.get_flags().contains(gst::BufferFlags::GAP));
+        assert!(gap.get_flags().contains(gst::BufferFlags::GAP));
+        assert_eq!(gap.get_size(), 480 * 2 * 2);
+        assert!(gap.map_readable().unwrap().iter().all(|&byte| byte == 0));
+        assert_eq!(gap.get_pts(), first.get_pts() + first.get_duration());
+        let next = create(&element).unwrap();
+        assert!(!next.get_flags().contains(gst::BufferFlags::GAP));
+        remove_src(id);
+    }
+
+    #[test]
+    fn channel_count_changes_are_remixed() {
+        let frames = vec![
+            FakeFrame::Audio(audio_frame(2, 10_000_000)),
+            FakeFrame::Audio(audio_frame(1, 10_100_000)),
+            FakeFrame::Audio(audio_frame(6, 10_200_000)),
+        ];
+        let (element, id) = audio_src(frames, 2);
+        for _ in 0..3 {
+            assert_eq!(create(&element).unwrap().get_size(), 480 * 2 * 2);
+        }
+        remove_src(id);
+    }
+
+    #[test]
+    fn missing_selected_channel_leaves_the_offset() {
+        let frames = vec![
+            FakeFrame::Audio(audio_frame(2, 10_000_000)),
+            FakeFrame::Audio(audio_frame(1, 10_100_000)),
+        ];
+        let (element, id) = audio_src(frames, 1);
+        let src = NdiAudioSrc::from_instance(&element);
+        src.settings.lock().unwrap().channel_select = 1;
+        create(&element).unwrap();
+        assert_eq!(create(&element), Err(gst::FlowError::NotNegotiated));
+        assert_eq!(src.timestamp_data.lock().unwrap().offset, 480);
+        remove_src(id);
+    }
+
+    #[test]
+    fn offsets_are_contiguous() {
+        let (element, id) = audio_src(audio_frames(2, 3), 2);
+        let mut offset = create(&element).unwrap().get_offset_end();
+        assert_eq!(offset, 480);
+        for _ in 0..2 {
+            let buffer = create(&element).unwrap();
+            assert_eq!(buffer.get_offset(), offset);
+            assert_eq!(buffer.get_offset_end(), offset + 480);
+            offset = buffer.get_offset_end();
+        }
+        remove_src(id);
+    }
+
+    #[test]
+    fn seeks_are_refused_without_disturbing_the_stream() {
+        let (element, id) = audio_src(audio_frames(2, 2), 2);
+        let first = create(&element).unwrap();
+        let seek = gst::Event::new_seek(
+            1.0,
+            gst::SeekFlags::FLUSH,
+            gst::SeekType::Set,
+            gst::ClockTime::from_seconds(1),
+            gst::SeekType::None,
+            gst::CLOCK_TIME_NONE,
+        )
+        .build();
+        assert!(!NdiAudioSrc::from_instance(&element).event(&element, &seek));
+        let second = create(&element).unwrap();
+        assert_eq!(second.get_offset(), first.get_offset_end());
+        assert!(!second.get_flags().contains(gst::BufferFlags::DISCONT));
+        remove_src(id);
+    }
+
+    #[test]
+    fn silence_goes_on_from_offsets_of_long_streams() {
+        init();
+        let info = gst_audio::AudioInfo::new(gst_audio::AUDIO_FORMAT_S16, 48_000, 2)
+            .build()
+            .unwrap();
+        let latency = Some(gst::ClockTime::from_mseconds(10));
+
+        // Weeks of samples still have a time
+        let offset = u64::MAX / gst::SECOND_VAL;
+        let mut timestamp_data = TimestampData {
+            offset,
+            ..Default::default()
+        };
+        let buffer = unsafe { silence_buffer(&info, latency, &mut timestamp_data) };
+        // From the start PTS, which the other tests may have set meanwhile
+        let start_pts = buffer.get_pts()
+            - gst::ClockTime::from(offset.mul_div_floor(gst::SECOND_VAL, 48_000).unwrap());
+        assert!(
+            start_pts == gst::ClockTime(Some(0)) || start_pts == unsafe { ndi_struct.start_pts }
+        );
+        assert_eq!(buffer.get_offset(), offset);
+        assert_eq!(timestamp_data.offset, offset + 480);
+
+        // Beyond that the offsets start over as a discontinuity
+        let mut timestamp_data = TimestampData {
+            offset: u64::MAX - 480,
+            ..Default::default()
+        };
+        let buffer = unsafe { silence_buffer(&info, latency, &mut timestamp_data) };
+        assert!(buffer.get_flags().contains(gst::BufferFlags::DISCONT));
+        assert_eq!(buffer.get_offset(), 0);
+        assert_eq!(buffer.get_offset_end(), 480);
+    }
+
+    #[test]
+    fn fixate_without_a_receiver_uses_the_template() {
+        let (element, id) = audio_src(Vec::new(), 2);
+        remove_src(id);
+        let caps = element
+            .get_static_pad("src")
+            .unwrap()
+            .get_pad_template_caps()
+            .unwrap();
+        let caps = NdiAudioSrc::from_instance(&element).fixate(&element, caps);
+        assert!(caps.is_fixed());
+    }
+
+    #[test]
+    fn flushing_starts_the_offsets_over() {
+        let (element, id) = audio_src(audio_frames(2, 3), 2);
+        let src = NdiAudioSrc::from_instance(&element);
+        create(&element).unwrap();
+        assert_eq!(create(&element).unwrap().get_offset(), 480);
+        src.timestamp_data.lock().unwrap().warmup_dropped = 2;
+        assert!(src.event(&element, &gst::Event::new_flush_start().build()));
+        assert!(src.event(&element, &gst::Event::new_flush_stop(true).build()));
+        assert_eq!(src.timestamp_data.lock().unwrap().warmup_dropped, 2);
+        let buffer = create(&element).unwrap();
+        assert_eq!(buffer.get_offset(), 0);
+        assert!(buffer.get_flags().contains(gst::BufferFlags::DISCONT));
+        remove_src(id);
+    }
+
+    #[test]
+    fn latency_is_only_known_after_negotiation() {
+        let (element, id) = audio_src(Vec::new(), 2);
+        let src = NdiAudioSrc::from_instance(&element);
+        let info = src.state.lock().unwrap().info.take();
+        let latency = src.settings.lock().unwrap().latency.take();
+        let mut query = gst::Query::new_latency();
+        assert!(!BaseSrcImpl::query(src, &element, &mut query));
+
+        src.state.lock().unwrap().info = info;
+        src.settings.lock().unwrap().latency = latency;
+        let queue_depth = u64::from(src.settings.lock().unwrap().queue_depth);
+        assert!(BaseSrcImpl::query(src, &element, &mut query));
+        let (live, min, _) = query.get_result();
+        assert!(live);
+        assert_eq!(min, gst::ClockTime::from_mseconds(10) * (queue_depth + 1));
+        remove_src(id);
+    }
+
+    #[test]
+    fn large_channel_counts_are_positionless() {
+        for &channels in &[16, 32] {
+            let (element, id) = audio_src(audio_frames(channels, 3), channels as u32);
+            let src = NdiAudioSrc::from_instance(&element);
+            let caps = element
+                .get_static_pad("src")
+                .unwrap()
+                .get_pad_template_caps()
+                .unwrap();
+            let caps = src.fixate(&element, caps);
+            let s = caps.get_structure(0).unwrap();
+            assert_eq!(s.get::<i32>("channels"), Some(channels));
+            assert_eq!(
+                s.get::<gst::Bitmask>("channel-mask"),
+                Some(gst::Bitmask::new(0))
+            );
+            for _ in 0..2 {
+                assert_eq!(
+                    create(&element).unwrap().get_size(),
+                    480 * channels as usize * 2
+                );
+            }
+            remove_src(id);
+        }
+    }
+
+    #[test]
+    fn fixate_gives_up_on_a_source_without_frames() {
+        let (element, id) = audio_src(Vec::new(), 2);
+        let src = NdiAudioSrc::from_instance(&element);
+        let template = element
+            .get_static_pad("src")
+            .unwrap()
+            .get_pad_template_caps()
+            .unwrap();
+        assert!(src.fixate(&element, template.clone()).is_empty());
+
+        src.unlocked.store(true, Ordering::SeqCst);
+        assert!(src.fixate(&element, template).is_fixed());
+        remove_src(id);
+    }
+
+    #[test]
+    fn receive_video_is_reconnected_from_create() {
+        let (element, id) = audio_src(Vec::new(), 2);
+        element.set_property("receive-video", &false).unwrap();
+        let src = NdiAudioSrc::from_instance(&element);
+        assert!(src.reconnect_pending.load(Ordering::SeqCst));
+        remove_src(id);
+    }
+
+    // Not run by default, with `cargo test --release -- --ignored --nocapture`
+    // it prints how fast 16 channel frames are converted straight into the
+    // output buffer, as create() does, and through a reused scratch buffer
+    #[test]
+    #[ignore]
+    fn bench_audio_conversion_scratch_buffer() {
+        const FRAMES: usize = 2000;
+        const NO_SAMPLES: usize = 1920;
+        const NO_CHANNELS: usize = 16;
+        init();
+        let planes = vec![0.25f32; NO_SAMPLES * NO_CHANNELS];
+        let frame = NDIlib_audio_frame_v2_t {
+            sample_rate: 48_000,
+            no_channels: NO_CHANNELS as i32,
+            no_samples: NO_SAMPLES as i32,
+            p_data: planes.as_ptr(),
+            channel_stride_in_bytes: (NO_SAMPLES * 4) as i32,
+            ..Default::default()
+        };
+        // S16 downmixed to stereo and S32 with every channel, both converted
+        // here rather than by the SDK
+        for &(format, width, no_channels, channel_select) in &[
+            (gst_audio::AUDIO_FORMAT_S16, 2, 2, -2),
+            (gst_audio::AUDIO_FORMAT_S32, 4, NO_CHANNELS, -1),
+        ] {
+            let size = NO_SAMPLES * no_channels * width;
+            let mut scratch = Vec::new();
+            for &use_scratch in &[false, true] {
+                let start = Instant::now();
+                for _ in 0..FRAMES {
+                    let mut buffer = gst::Buffer::with_size(size).unwrap();
+                    let mut map = buffer.get_mut().unwrap().map_writable().unwrap();
+                    if use_scratch {
+                        if scratch.len() < size {
+                            scratch.resize(size, 0u8);
+                        }
+                        unsafe {
+                            audio_to_interleaved(
+                                format,
+                                &frame,
+                                channel_select,
+                                no_channels,
+                                NO_SAMPLES,
+                                &mut scratch[..size],
+                            )
+                        }
+                        .unwrap();
+                        map.as_mut_slice().copy_from_slice(&scratch[..size]);
+                    } else {
+                        unsafe {
+                            audio_to_interleaved(
+                                format,
+                                &frame,
+                                channel_select,
+                                no_channels,
+                                NO_SAMPLES,
+                                map.as_mut_slice(),
+                            )
+                        }
+                        .unwrap();
+                    }
+                }
+                let elapsed = start.elapsed();
+                println!(
+                    "{} to {} channels {}: {:.0} frames/s",
+                    format.to_string(),
+                    no_channels,
+                    if use_scratch {
+                        "with a scratch buffer"
+                    } else {
+                        "in place"
+                    },
+                    FRAMES as f64
+                        / (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9)
+                );
+            }
+        }
+    }
 }
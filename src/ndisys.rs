@@ -1,5 +1,23 @@
 #![allow(non_camel_case_types, non_upper_case_globals, non_snake_case)]
 
+//! FFI bindings for the NDI SDK.
+//!
+//! The bindings work with both the NDI SDK v4 and v5, which install the same
+//! `libndi` and export the `NDIlib_find_create_v2`,
+//! `NDIlib_recv_create_v3`, `NDIlib_recv_capture_v2`, `NDIlib_recv_free_video_v2`,
+//! `NDIlib_recv_free_audio_v2`, `NDIlib_recv_free_metadata`, `NDIlib_send_create`, `NDIlib_send_send_video_v2`,
+//! `NDIlib_send_send_audio_v2`, `NDIlib_recv_ptz_is_supported`, `NDIlib_recv_ptz_zoom`,
+//! `NDIlib_recv_ptz_pan_tilt_speed`, `NDIlib_recv_ptz_recall_preset`,
+//! `NDIlib_recv_recording_is_supported`, `NDIlib_recv_recording_start`,
+//! `NDIlib_recv_recording_stop`, `NDIlib_recv_recording_get_filename`,
+//! `NDIlib_framesync_create`, `NDIlib_framesync_destroy`,
+//! `NDIlib_framesync_capture_video`, `NDIlib_framesync_free_video`,
+//! `NDIlib_framesync_capture_audio`, `NDIlib_framesync_free_audio`,
+//! `NDIlib_util_audio_to_interleaved_16s_v2` and
+//! `NDIlib_util_audio_to_interleaved_32f_v2` symbols, whose structs have the same
+//! layout in both versions.
+
+use std::ffi::{CStr, CString};
 use std::ptr;
 
 #[link(name = "ndi")]
@@ -8,6 +26,10 @@ extern "C" {
     pub fn NDIlib_find_create_v2(
         p_create_settings: *const NDIlib_find_create_t,
     ) -> NDIlib_find_instance_t;
+    pub fn NDIlib_find_wait_for_sources(
+        p_instance: NDIlib_find_instance_t,
+        timeout_in_ms: u32,
+    ) -> bool;
     pub fn NDIlib_find_get_current_sources(
         p_instance: NDIlib_find_instance_t,
         p_no_sources: *mut u32,
@@ -15,7 +37,7 @@ extern "C" {
     pub fn NDIlib_recv_create_v3(
         p_create_settings: *const NDIlib_recv_create_v3_t,
     ) -> NDIlib_recv_instance_t;
-    pub fn NDIlib_find_destroy(p_instance: NDIlib_recv_instance_t);
+    pub fn NDIlib_find_destroy(p_instance: NDIlib_find_instance_t);
     pub fn NDIlib_recv_destroy(p_instance: NDIlib_recv_instance_t);
     pub fn NDIlib_destroy();
     pub fn NDIlib_recv_set_tally(
@@ -26,11 +48,47 @@ extern "C" {
         p_instance: NDIlib_recv_instance_t,
         p_metadata: *const NDIlib_metadata_frame_t,
     ) -> bool;
-    pub fn NDIlib_recv_capture_v2(
+    pub fn NDIlib_recv_add_connection_metadata(
         p_instance: NDIlib_recv_instance_t,
-        p_video_data: *const NDIlib_video_frame_v2_t,
-        p_audio_data: *const NDIlib_audio_frame_v2_t,
         p_metadata: *const NDIlib_metadata_frame_t,
+    );
+    pub fn NDIlib_recv_get_queue(
+        p_instance: NDIlib_recv_instance_t,
+        p_total: *mut NDIlib_recv_queue_t,
+    );
+    pub fn NDIlib_recv_get_web_control(
+        p_instance: NDIlib_recv_instance_t,
+    ) -> *const ::std::os::raw::c_char;
+    pub fn NDIlib_recv_free_string(
+        p_instance: NDIlib_recv_instance_t,
+        p_string: *const ::std::os::raw::c_char,
+    );
+    pub fn NDIlib_recv_ptz_is_supported(p_instance: NDIlib_recv_instance_t) -> bool;
+    pub fn NDIlib_recv_ptz_zoom(p_instance: NDIlib_recv_instance_t, zoom_value: f32) -> bool;
+    pub fn NDIlib_recv_ptz_pan_tilt_speed(
+        p_instance: NDIlib_recv_instance_t,
+        pan_speed: f32,
+        tilt_speed: f32,
+    ) -> bool;
+    pub fn NDIlib_recv_ptz_recall_preset(
+        p_instance: NDIlib_recv_instance_t,
+        preset_no: ::std::os::raw::c_int,
+        speed: f32,
+    ) -> bool;
+    pub fn NDIlib_recv_recording_is_supported(p_instance: NDIlib_recv_instance_t) -> bool;
+    pub fn NDIlib_recv_recording_start(
+        p_instance: NDIlib_recv_instance_t,
+        p_filename_hint: *const ::std::os::raw::c_char,
+    ) -> bool;
+    pub fn NDIlib_recv_recording_stop(p_instance: NDIlib_recv_instance_t) -> bool;
+    pub fn NDIlib_recv_recording_get_filename(
+        p_instance: NDIlib_recv_instance_t,
+    ) -> *const ::std::os::raw::c_char;
+    pub fn NDIlib_recv_capture_v2(
+        p_instance: NDIlib_recv_instance_t,
+        p_video_data: *mut NDIlib_video_frame_v2_t,
+        p_audio_data: *mut NDIlib_audio_frame_v2_t,
+        p_metadata: *mut NDIlib_metadata_frame_t,
         timeout_in_ms: u32,
     ) -> NDIlib_frame_type_e;
     pub fn NDIlib_recv_free_video_v2(
@@ -41,10 +99,52 @@ extern "C" {
         p_instance: NDIlib_recv_instance_t,
         p_audio_data: *const NDIlib_audio_frame_v2_t,
     );
+    pub fn NDIlib_recv_free_metadata(
+        p_instance: NDIlib_recv_instance_t,
+        p_metadata: *const NDIlib_metadata_frame_t,
+    );
+    pub fn NDIlib_framesync_create(
+        p_receiver: NDIlib_recv_instance_t,
+    ) -> NDIlib_framesync_instance_t;
+    pub fn NDIlib_framesync_destroy(p_instance: NDIlib_framesync_instance_t);
+    pub fn NDIlib_framesync_capture_video(
+        p_instance: NDIlib_framesync_instance_t,
+        p_video_data: *mut NDIlib_video_frame_v2_t,
+        field_type: NDIlib_frame_format_type_e,
+    );
+    pub fn NDIlib_framesync_free_video(
+        p_instance: NDIlib_framesync_instance_t,
+        p_video_data: *mut NDIlib_video_frame_v2_t,
+    );
+    pub fn NDIlib_framesync_capture_audio(
+        p_instance: NDIlib_framesync_instance_t,
+        p_audio_data: *mut NDIlib_audio_frame_v2_t,
+        sample_rate: ::std::os::raw::c_int,
+        no_channels: ::std::os::raw::c_int,
+        no_samples: ::std::os::raw::c_int,
+    );
+    pub fn NDIlib_framesync_free_audio(
+        p_instance: NDIlib_framesync_instance_t,
+        p_audio_data: *mut NDIlib_audio_frame_v2_t,
+    );
+    pub fn NDIlib_send_create(
+        p_create_settings: *const NDIlib_send_create_t,
+    ) -> NDIlib_send_instance_t;
+    pub fn NDIlib_send_destroy(p_instance: NDIlib_send_instance_t);
+    pub fn NDIlib_send_send_video_v2(
+        p_instance: NDIlib_send_instance_t,
+        p_video_data: *const NDIlib_video_frame_v2_t,
+    );
+    pub fn NDIlib_send_send_audio_v2(
+        p_instance: NDIlib_send_instance_t,
+        p_audio_data: *const NDIlib_audio_frame_v2_t,
+    );
 }
 
 pub type NDIlib_find_instance_t = *mut ::std::os::raw::c_void;
 
+pub type NDIlib_framesync_instance_t = *mut ::std::os::raw::c_void;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct NDIlib_find_create_t {
@@ -88,6 +188,7 @@ pub enum NDIlib_frame_type_e {
     NDIlib_frame_type_metadata = 3,
     NDIlib_frame_type_error = 4,
     NDIlib_frame_type_status_change = 100,
+    NDIlib_frame_type_source_change = 101,
 }
 
 #[repr(i32)]
@@ -107,6 +208,7 @@ pub enum NDIlib_recv_color_format_e {
     NDIlib_recv_color_format_RGBX_RGBA = 2,
     NDIlib_recv_color_format_UYVY_RGBA = 3,
     NDIlib_recv_color_format_fastest = 100,
+    NDIlib_recv_color_format_best = 101,
 }
 
 #[repr(u32)]
@@ -118,6 +220,28 @@ pub enum NDIlib_FourCC_type_e {
     NDIlib_FourCC_type_RGBA = 1_094_862_674,
     NDIlib_FourCC_type_RGBX = 1_480_738_642,
     NDIlib_FourCC_type_UYVA = 1_096_178_005,
+    NDIlib_FourCC_type_P216 = 909_193_808,
+    NDIlib_FourCC_type_PA16 = 909_197_648,
+}
+
+impl NDIlib_FourCC_type_e {
+    pub fn from_u32(fourcc: u32) -> Option<NDIlib_FourCC_type_e> {
+        use NDIlib_FourCC_type_e::*;
+
+        [
+            NDIlib_FourCC_type_UYVY,
+            NDIlib_FourCC_type_BGRA,
+            NDIlib_FourCC_type_BGRX,
+            NDIlib_FourCC_type_RGBA,
+            NDIlib_FourCC_type_RGBX,
+            NDIlib_FourCC_type_UYVA,
+            NDIlib_FourCC_type_P216,
+            NDIlib_FourCC_type_PA16,
+        ]
+        .iter()
+        .cloned()
+        .find(|known| *known as u32 == fourcc)
+    }
 }
 
 #[repr(u32)]
@@ -129,9 +253,9 @@ pub enum NDIlib_frame_format_type_e {
     NDIlib_frame_format_type_field_1 = 3,
 }
 
-pub const NDIlib_send_timecode_synthesize: i64 = ::std::i64::MAX;
+pub const NDIlib_send_timecode_synthesize: i64 = i64::MAX;
 pub const NDIlib_send_timecode_empty: i64 = 0;
-pub const NDIlib_recv_timestamp_undefined: i64 = ::std::i64::MAX;
+pub const NDIlib_recv_timestamp_undefined: i64 = i64::MAX;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -157,28 +281,421 @@ impl Default for NDIlib_recv_create_v3_t {
 
 pub type NDIlib_recv_instance_t = *mut ::std::os::raw::c_void;
 
-//Rust wrapper around *mut ::std::os::raw::c_void
-pub struct NdiInstance {
-    pub recv: NDIlib_recv_instance_t,
-    // pub audio: bool,
-}
-
-unsafe impl ::std::marker::Send for NdiInstance {}
+pub type NDIlib_send_instance_t = *mut ::std::os::raw::c_void;
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
-pub struct NDIlib_tally_t {
-    pub on_program: bool,
-    pub on_preview: bool,
+pub struct NDIlib_send_create_t {
+    pub p_ndi_name: *const ::std::os::raw::c_char,
+    pub p_groups: *const ::std::os::raw::c_char,
+    pub clock_video: bool,
+    pub clock_audio: bool,
 }
 
-impl Default for NDIlib_tally_t {
+impl Default for NDIlib_send_create_t {
     fn default() -> Self {
-        NDIlib_tally_t {
-            on_program: false,
-            on_preview: false,
+        NDIlib_send_create_t {
+            p_ndi_name: ptr::null(),
+            p_groups: ptr::null(),
+            clock_video: true,
+            clock_audio: true,
+        }
+    }
+}
+
+/// Owned NDI finder, destroyed when dropped.
+pub struct FindInstance {
+    find: NDIlib_find_instance_t,
+}
+
+unsafe impl ::std::marker::Send for FindInstance {}
+unsafe impl ::std::marker::Sync for FindInstance {}
+
+impl FindInstance {
+    /// Starts looking for sources in `groups`, the default groups if empty.
+    ///
+    /// # Safety
+    ///
+    /// NDI must be initialized for as long as the finder exists.
+    pub unsafe fn new(groups: &str) -> Option<FindInstance> {
+        let p_groups = CString::new(groups).ok()?;
+        let NDI_find_create_desc = NDIlib_find_create_t {
+            p_groups: if groups.is_empty() {
+                ptr::null()
+            } else {
+                p_groups.as_ptr()
+            },
+            ..Default::default()
+        };
+        let find = NDIlib_find_create_v2(&NDI_find_create_desc);
+        if find.is_null() {
+            None
+        } else {
+            Some(FindInstance { find })
+        }
+    }
+
+    /// Waits until the sources changed or the timeout passed, returns whether
+    /// they changed.
+    pub fn wait_for_sources(&self, timeout_in_ms: u32) -> bool {
+        unsafe { NDIlib_find_wait_for_sources(self.find, timeout_in_ms) }
+    }
+
+    /// Names and addresses of the sources found so far, without waiting.
+    pub fn current_sources(&self) -> Vec<(String, String)> {
+        let mut total_sources: u32 = 0;
+        unsafe {
+            let p_sources = NDIlib_find_get_current_sources(self.find, &mut total_sources);
+            if p_sources.is_null() {
+                return Vec::new();
+            }
+
+            (0..total_sources as usize)
+                .map(|i| {
+                    let source = &*p_sources.add(i);
+                    (
+                        CStr::from_ptr(source.p_ndi_name)
+                            .to_string_lossy()
+                            .into_owned(),
+                        CStr::from_ptr(source.p_ip_address)
+                            .to_string_lossy()
+                            .into_owned(),
+                    )
+                })
+                .collect()
+        }
+    }
+}
+
+impl Drop for FindInstance {
+    fn drop(&mut self) {
+        unsafe { NDIlib_find_destroy(self.find) };
+    }
+}
+
+/// Owned NDI receiver, destroyed when dropped.
+///
+/// It can own a finder alongside, which keeps watching the network for the
+/// source for as long as the receiver exists.
+pub struct RecvInstance {
+    recv: NDIlib_recv_instance_t,
+    finder: Option<FindInstance>,
+}
+
+unsafe impl ::std::marker::Send for RecvInstance {}
+// The NDI receive functions can be called from several threads at once
+unsafe impl ::std::marker::Sync for RecvInstance {}
+
+impl RecvInstance {
+    /// Takes ownership of a receiver created with `NDIlib_recv_create_v3`, and
+    /// of a finder watching for its source.
+    ///
+    /// # Safety
+    ///
+    /// `recv` must be a valid receiver not owned by anything else, and NDI must
+    /// stay initialized until the receiver is destroyed.
+    pub unsafe fn new(recv: NDIlib_recv_instance_t, finder: Option<FindInstance>) -> RecvInstance {
+        RecvInstance { recv, finder }
+    }
+}
+
+impl Drop for RecvInstance {
+    fn drop(&mut self) {
+        self.destroy();
+    }
+}
+
+/// Owned NDI frame-sync on top of a receiver, destroyed when dropped.
+///
+/// The receiver must outlive it and can't be captured from directly meanwhile.
+pub struct FrameSync {
+    framesync: NDIlib_framesync_instance_t,
+}
+
+unsafe impl ::std::marker::Send for FrameSync {}
+
+impl FrameSync {
+    /// Creates a frame-sync on top of `recv`, or `None` if NDI refused.
+    ///
+    /// # Safety
+    ///
+    /// `recv` must be a valid receiver that outlives the frame-sync and isn't
+    /// captured from while it exists.
+    pub unsafe fn new(recv: NDIlib_recv_instance_t) -> Option<FrameSync> {
+        let framesync = NDIlib_framesync_create(recv);
+        if framesync.is_null() {
+            None
+        } else {
+            Some(FrameSync { framesync })
+        }
+    }
+
+    /// Gets the frame due now, repeating or dropping received frames as
+    /// needed. Until the first frame is received it has no data.
+    pub fn capture_video(&self, video_frame: &mut NDIlib_video_frame_v2_t) {
+        unsafe {
+            NDIlib_framesync_capture_video(
+                self.framesync,
+                video_frame,
+                NDIlib_frame_format_type_e::NDIlib_frame_format_type_progressive,
+            )
+        }
+    }
+
+    pub fn free_video(&self, video_frame: &mut NDIlib_video_frame_v2_t) {
+        unsafe { NDIlib_framesync_free_video(self.framesync, video_frame) }
+    }
+
+    /// Gets the next `no_samples` samples resampled to `sample_rate`, with the
+    /// source's channels if `no_channels` is 0. Samples not received in time
+    /// are silent.
+    pub fn capture_audio(
+        &self,
+        audio_frame: &mut NDIlib_audio_frame_v2_t,
+        sample_rate: i32,
+        no_channels: i32,
+        no_samples: i32,
+    ) {
+        unsafe {
+            NDIlib_framesync_capture_audio(
+                self.framesync,
+                audio_frame,
+                sample_rate,
+                no_channels,
+                no_samples,
+            )
+        }
+    }
+
+    pub fn free_audio(&self, audio_frame: &mut NDIlib_audio_frame_v2_t) {
+        unsafe { NDIlib_framesync_free_audio(self.framesync, audio_frame) }
+    }
+}
+
+impl Drop for FrameSync {
+    fn drop(&mut self) {
+        unsafe { NDIlib_framesync_destroy(self.framesync) };
+    }
+}
+
+/// Receiving side of an NDI connection.
+///
+/// The elements only capture and free frames through this trait, so that the NDI
+/// SDK can be swapped for another frame provider.
+pub trait NdiReceiver {
+    fn capture(
+        &self,
+        video_frame: Option<&mut NDIlib_video_frame_v2_t>,
+        audio_frame: Option<&mut NDIlib_audio_frame_v2_t>,
+        metadata_frame: Option<&mut NDIlib_metadata_frame_t>,
+        timeout_in_ms: u32,
+    ) -> NDIlib_frame_type_e;
+    fn free_video(&self, video_frame: &NDIlib_video_frame_v2_t);
+    fn free_audio(&self, audio_frame: &NDIlib_audio_frame_v2_t);
+    fn free_metadata(&self, metadata_frame: &NDIlib_metadata_frame_t);
+    /// Number of frames received but not captured yet.
+    fn queue(&self) -> NDIlib_recv_queue_t;
+
+    /// The SDK receiver behind it for the calls not covered here, null if there
+    /// is none.
+    fn as_ptr(&self) -> NDIlib_recv_instance_t;
+    /// Destroys the receiver now instead of when the last reference goes away.
+    fn destroy(&mut self);
+
+    fn is_destroyed(&self) -> bool {
+        self.as_ptr().is_null()
+    }
+
+    /// The finder watching for the receiver's source, if any.
+    fn finder(&self) -> Option<&FindInstance> {
+        None
+    }
+
+    fn capture_video(
+        &self,
+        video_frame: &mut NDIlib_video_frame_v2_t,
+        timeout_in_ms: u32,
+    ) -> NDIlib_frame_type_e {
+        self.capture(Some(video_frame), None, None, timeout_in_ms)
+    }
+
+    fn capture_audio(
+        &self,
+        audio_frame: &mut NDIlib_audio_frame_v2_t,
+        timeout_in_ms: u32,
+    ) -> NDIlib_frame_type_e {
+        self.capture(None, Some(audio_frame), None, timeout_in_ms)
+    }
+}
+
+impl NdiReceiver for RecvInstance {
+    fn capture(
+        &self,
+        video_frame: Option<&mut NDIlib_video_frame_v2_t>,
+        audio_frame: Option<&mut NDIlib_audio_frame_v2_t>,
+        metadata_frame: Option<&mut NDIlib_metadata_frame_t>,
+        timeout_in_ms: u32,
+    ) -> NDIlib_frame_type_e {
+        unsafe {
+            NDIlib_recv_capture_v2(
+                self.recv,
+                video_frame.map_or(ptr::null_mut(), |f| f as *mut _),
+                audio_frame.map_or(ptr::null_mut(), |f| f as *mut _),
+                metadata_frame.map_or(ptr::null_mut(), |f| f as *mut _),
+                timeout_in_ms,
+            )
         }
     }
+
+    fn free_video(&self, video_frame: &NDIlib_video_frame_v2_t) {
+        unsafe { NDIlib_recv_free_video_v2(self.recv, video_frame) }
+    }
+
+    fn free_audio(&self, audio_frame: &NDIlib_audio_frame_v2_t) {
+        unsafe { NDIlib_recv_free_audio_v2(self.recv, audio_frame) }
+    }
+
+    fn free_metadata(&self, metadata_frame: &NDIlib_metadata_frame_t) {
+        unsafe { NDIlib_recv_free_metadata(self.recv, metadata_frame) }
+    }
+
+    fn queue(&self) -> NDIlib_recv_queue_t {
+        let mut queue: NDIlib_recv_queue_t = Default::default();
+        unsafe { NDIlib_recv_get_queue(self.recv, &mut queue) };
+        queue
+    }
+
+    fn as_ptr(&self) -> NDIlib_recv_instance_t {
+        self.recv
+    }
+
+    fn destroy(&mut self) {
+        self.finder = None;
+        if !self.recv.is_null() {
+            unsafe { NDIlib_recv_destroy(self.recv) };
+            self.recv = ptr::null_mut();
+        }
+    }
+
+    fn finder(&self) -> Option<&FindInstance> {
+        self.finder.as_ref()
+    }
+}
+
+/// Frames a [`FakeReceiver`](struct.FakeReceiver.html) hands out, in order.
+#[cfg(test)]
+pub enum FakeFrame {
+    Video(NDIlib_video_frame_v2_t),
+    Audio(NDIlib_audio_frame_v2_t),
+    Metadata(NDIlib_metadata_frame_t),
+}
+
+/// Receiver returning scripted frames instead of receiving them, for tests
+/// without the NDI SDK.
+///
+/// Like the SDK it drops a frame of a type the caller doesn't capture. The
+/// counters are shared so they can still be read once the receiver is boxed.
+#[cfg(test)]
+pub struct FakeReceiver {
+    frames: ::std::sync::Mutex<::std::collections::VecDeque<FakeFrame>>,
+    pub freed: ::std::sync::Arc<::std::sync::atomic::AtomicUsize>,
+    pub destroyed: ::std::sync::Arc<::std::sync::atomic::AtomicBool>,
+}
+
+// The scripted frames only point to static test data
+#[cfg(test)]
+unsafe impl ::std::marker::Send for FakeReceiver {}
+#[cfg(test)]
+unsafe impl ::std::marker::Sync for FakeReceiver {}
+
+#[cfg(test)]
+impl FakeReceiver {
+    pub fn new(frames: Vec<FakeFrame>) -> FakeReceiver {
+        FakeReceiver {
+            frames: ::std::sync::Mutex::new(frames.into_iter().collect()),
+            freed: Default::default(),
+            destroyed: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl NdiReceiver for FakeReceiver {
+    fn capture(
+        &self,
+        video_frame: Option<&mut NDIlib_video_frame_v2_t>,
+        audio_frame: Option<&mut NDIlib_audio_frame_v2_t>,
+        metadata_frame: Option<&mut NDIlib_metadata_frame_t>,
+        _timeout_in_ms: u32,
+    ) -> NDIlib_frame_type_e {
+        let frame = self.frames.lock().unwrap().pop_front();
+        match (frame, video_frame, audio_frame, metadata_frame) {
+            (Some(FakeFrame::Video(frame)), Some(video_frame), _, _) => {
+                *video_frame = frame;
+                NDIlib_frame_type_e::NDIlib_frame_type_video
+            }
+            (Some(FakeFrame::Audio(frame)), _, Some(audio_frame), _) => {
+                *audio_frame = frame;
+                NDIlib_frame_type_e::NDIlib_frame_type_audio
+            }
+            (Some(FakeFrame::Metadata(frame)), _, _, Some(metadata_frame)) => {
+                *metadata_frame = frame;
+                NDIlib_frame_type_e::NDIlib_frame_type_metadata
+            }
+            _ => NDIlib_frame_type_e::NDIlib_frame_type_none,
+        }
+    }
+
+    fn free_video(&self, _video_frame: &NDIlib_video_frame_v2_t) {
+        self.freed
+            .fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn free_audio(&self, _audio_frame: &NDIlib_audio_frame_v2_t) {
+        self.freed
+            .fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn free_metadata(&self, _metadata_frame: &NDIlib_metadata_frame_t) {
+        self.freed
+            .fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn queue(&self) -> NDIlib_recv_queue_t {
+        let mut queue: NDIlib_recv_queue_t = Default::default();
+        for frame in self.frames.lock().unwrap().iter() {
+            match *frame {
+                FakeFrame::Video(_) => queue.video_frames += 1,
+                FakeFrame::Audio(_) => queue.audio_frames += 1,
+                FakeFrame::Metadata(_) => queue.metadata_frames += 1,
+            }
+        }
+        queue
+    }
+
+    fn as_ptr(&self) -> NDIlib_recv_instance_t {
+        ptr::null_mut()
+    }
+
+    fn destroy(&mut self) {
+        self.destroyed
+            .store(true, ::std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NDIlib_recv_queue_t {
+    pub video_frames: ::std::os::raw::c_int,
+    pub audio_frames: ::std::os::raw::c_int,
+    pub metadata_frames: ::std::os::raw::c_int,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NDIlib_tally_t {
+    pub on_program: bool,
+    pub on_preview: bool,
 }
 
 #[repr(C)]
@@ -204,7 +721,8 @@ impl Default for NDIlib_metadata_frame_t {
 pub struct NDIlib_video_frame_v2_t {
     pub xres: ::std::os::raw::c_int,
     pub yres: ::std::os::raw::c_int,
-    pub FourCC: NDIlib_FourCC_type_e,
+    // Kept raw, newer SDKs send FourCCs that aren't in NDIlib_FourCC_type_e
+    pub FourCC: u32,
     pub frame_rate_N: ::std::os::raw::c_int,
     pub frame_rate_D: ::std::os::raw::c_int,
     pub picture_aspect_ratio: ::std::os::raw::c_float,
@@ -216,12 +734,18 @@ pub struct NDIlib_video_frame_v2_t {
     pub timestamp: i64,
 }
 
+impl NDIlib_video_frame_v2_t {
+    pub fn fourcc(&self) -> Option<NDIlib_FourCC_type_e> {
+        NDIlib_FourCC_type_e::from_u32(self.FourCC)
+    }
+}
+
 impl Default for NDIlib_video_frame_v2_t {
     fn default() -> Self {
         NDIlib_video_frame_v2_t {
             xres: 0,
             yres: 0,
-            FourCC: NDIlib_FourCC_type_e::NDIlib_FourCC_type_UYVY,
+            FourCC: NDIlib_FourCC_type_e::NDIlib_FourCC_type_UYVY as u32,
             frame_rate_N: 30000,
             frame_rate_D: 1001,
             picture_aspect_ratio: 0.0,
@@ -273,6 +797,11 @@ extern "C" {
         p_src: *const NDIlib_audio_frame_interleaved_16s_t,
         p_dst: *mut NDIlib_audio_frame_v2_t,
     );
+
+    pub fn NDIlib_util_audio_to_interleaved_32f_v2(
+        p_src: *const NDIlib_audio_frame_v2_t,
+        p_dst: *mut NDIlib_audio_frame_interleaved_32f_t,
+    );
 }
 
 #[repr(C)]
@@ -298,3 +827,25 @@ impl Default for NDIlib_audio_frame_interleaved_16s_t {
         }
     }
 }
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct NDIlib_audio_frame_interleaved_32f_t {
+    pub sample_rate: ::std::os::raw::c_int,
+    pub no_channels: ::std::os::raw::c_int,
+    pub no_samples: ::std::os::raw::c_int,
+    pub timecode: i64,
+    pub p_data: *mut ::std::os::raw::c_float,
+}
+
+impl Default for NDIlib_audio_frame_interleaved_32f_t {
+    fn default() -> Self {
+        NDIlib_audio_frame_interleaved_32f_t {
+            sample_rate: 48000,
+            no_channels: 2,
+            no_samples: 0,
+            timecode: NDIlib_send_timecode_synthesize,
+            p_data: ptr::null_mut(),
+        }
+    }
+}
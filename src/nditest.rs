@@ -0,0 +1,179 @@
+//! In-process NDI test pattern sender.
+//!
+//! Setting the stream name of an element to [`TEST_SOURCE`] starts a local NDI
+//! sender with color bars and a 440 Hz tone and connects to it, so the plugin
+//! can be checked end-to-end without any NDI device on the network. The sender
+//! is shared by all elements using it and stops when the last one releases it.
+
+use ndisys::*;
+use {ndi_acquire, ndi_release};
+
+use std::f32::consts::PI;
+use std::ffi::CString;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread;
+
+pub const TEST_SOURCE: &str = "__ndi_test__";
+const WIDTH: usize = 640;
+const HEIGHT: usize = 360;
+const FRAME_RATE_N: i32 = 30;
+const FRAME_RATE_D: i32 = 1;
+const SAMPLE_RATE: usize = 48000;
+const CHANNELS: usize = 2;
+const SAMPLES_PER_FRAME: usize = SAMPLE_RATE * FRAME_RATE_D as usize / FRAME_RATE_N as usize;
+
+// 100% BT.709 color bars as (Y, U, V)
+const BARS: [(u8, u8, u8); 8] = [
+    (235, 128, 128),
+    (219, 16, 138),
+    (188, 154, 16),
+    (173, 42, 26),
+    (78, 214, 230),
+    (63, 102, 240),
+    (32, 240, 118),
+    (16, 128, 128),
+];
+
+lazy_static! {
+    static ref running_sender: Mutex<Weak<TestSender>> = Mutex::new(Weak::new());
+    // Named after the process, the test senders of other processes or hosts
+    // on the network are never picked instead of ours
+    static ref test_sender_name: String =
+        format!("GStreamer NDI Test Pattern {}", process::id());
+}
+
+// NDI advertises senders as "HOSTNAME (sender name)"
+pub fn is_test_source(source_name: &str) -> bool {
+    source_name.ends_with(&format!("({})", *test_sender_name))
+}
+
+/// Returns the running test sender, starting it if no element holds it yet.
+pub fn test_sender() -> Option<Arc<TestSender>> {
+    let mut current = running_sender
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if let Some(sender) = current.upgrade() {
+        return Some(sender);
+    }
+
+    let sender = Arc::new(TestSender::start()?);
+    *current = Arc::downgrade(&sender);
+    Some(sender)
+}
+
+/// Test pattern sender thread, stopped and joined when dropped.
+pub struct TestSender {
+    running: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TestSender {
+    fn start() -> Option<TestSender> {
+        let running = Arc::new(AtomicBool::new(true));
+        let (started_sender, started_receiver) = mpsc::channel();
+
+        let thread_running = running.clone();
+        let thread = thread::spawn(move || unsafe {
+            if !ndi_acquire() {
+                let _ = started_sender.send(false);
+                return;
+            }
+
+            let p_ndi_name = CString::new(test_sender_name.as_str()).unwrap();
+            let NDI_send_create_desc = NDIlib_send_create_t {
+                p_ndi_name: p_ndi_name.as_ptr(),
+                // Video is clocked, the audio sent along with each frame
+                // follows it
+                clock_audio: false,
+                ..Default::default()
+            };
+            let pNDI_send = NDIlib_send_create(&NDI_send_create_desc);
+            if pNDI_send.is_null() {
+                ndi_release();
+                let _ = started_sender.send(false);
+                return;
+            }
+            let _ = started_sender.send(true);
+
+            let video = color_bars();
+            // Receivers would assume BT.601 for this SD resolution otherwise
+            let color_info = CString::new(
+                r#"<ndi_color_info transfer="BT_709" matrix="BT_709" primaries="BT_709"/>"#,
+            )
+            .unwrap();
+            let video_frame = NDIlib_video_frame_v2_t {
+                xres: WIDTH as i32,
+                yres: HEIGHT as i32,
+                FourCC: NDIlib_FourCC_type_e::NDIlib_FourCC_type_UYVY as u32,
+                frame_rate_N: FRAME_RATE_N,
+                frame_rate_D: FRAME_RATE_D,
+                p_data: video.as_ptr() as *const ::std::os::raw::c_char,
+                line_stride_in_bytes: (WIDTH * 2) as i32,
+                p_metadata: color_info.as_ptr(),
+                ..Default::default()
+            };
+
+            let mut audio = vec![0f32; SAMPLES_PER_FRAME * CHANNELS];
+            let mut sample = 0usize;
+            while thread_running.load(Ordering::SeqCst) {
+                for i in 0..SAMPLES_PER_FRAME {
+                    let t = ((sample + i) % SAMPLE_RATE) as f32 / SAMPLE_RATE as f32;
+                    let value = 0.1 * (2.0 * PI * 440.0 * t).sin();
+                    for channel in 0..CHANNELS {
+                        audio[channel * SAMPLES_PER_FRAME + i] = value;
+                    }
+                }
+                sample += SAMPLES_PER_FRAME;
+
+                let audio_frame = NDIlib_audio_frame_v2_t {
+                    sample_rate: SAMPLE_RATE as i32,
+                    no_channels: CHANNELS as i32,
+                    no_samples: SAMPLES_PER_FRAME as i32,
+                    p_data: audio.as_ptr(),
+                    channel_stride_in_bytes: (SAMPLES_PER_FRAME * 4) as i32,
+                    ..Default::default()
+                };
+                NDIlib_send_send_audio_v2(pNDI_send, &audio_frame);
+                // Blocks until the frame is due
+                NDIlib_send_send_video_v2(pNDI_send, &video_frame);
+            }
+
+            NDIlib_send_destroy(pNDI_send);
+            ndi_release();
+        });
+
+        if started_receiver.recv().unwrap_or(false) {
+            Some(TestSender {
+                running,
+                thread: Some(thread),
+            })
+        } else {
+            let _ = thread.join();
+            None
+        }
+    }
+}
+
+impl Drop for TestSender {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn color_bars() -> Vec<u8> {
+    let mut data = vec![0u8; WIDTH * HEIGHT * 2];
+    for line in data.chunks_mut(WIDTH * 2) {
+        // UYVY packs two pixels as U Y0 V Y1
+        for (x, pixels) in line.chunks_mut(4).enumerate() {
+            let (y, u, v) = BARS[x * 2 * BARS.len() / WIDTH];
+            pixels.copy_from_slice(&[u, y, v, y]);
+        }
+    }
+    data
+}
@@ -0,0 +1,336 @@
+//! Audio sample conversion shared by the sources.
+//!
+//! Plain interleaving goes through the NDI SDK's `_v2` utility functions. S16 is
+//! converted with a 0 dB reference level, so a full scale float sample maps to
+//! full scale S16 like it does in the S32 and channel selection paths done here.
+//! Output that isn't a whole number of aligned samples in a supported format,
+//! or a selected channel the frame doesn't have, is rejected with an error
+//! instead of being written.
+
+use byte_slice_cast::{AsMutSliceOf, FromByteSlice};
+use gst_audio;
+
+use std::{cmp, mem};
+
+use ndisys::*;
+
+// NDI's 0 dB reference level, where a float sample of 1.0 is full scale
+const REFERENCE_LEVEL: i32 = 0;
+
+/// Interleaves `audio_frame` into `dst` as signed 16-bit samples.
+pub unsafe fn to_interleaved_16s(audio_frame: &NDIlib_audio_frame_v2_t, dst: &mut [i16]) {
+    let mut frame = NDIlib_audio_frame_interleaved_16s_t {
+        reference_level: REFERENCE_LEVEL,
+        p_data: dst.as_mut_ptr(),
+        ..Default::default()
+    };
+    NDIlib_util_audio_to_interleaved_16s_v2(audio_frame, &mut frame);
+}
+
+/// Interleaves `audio_frame` into `dst` as 32-bit float samples.
+pub unsafe fn to_interleaved_32f(audio_frame: &NDIlib_audio_frame_v2_t, dst: &mut [f32]) {
+    let mut frame = NDIlib_audio_frame_interleaved_32f_t {
+        p_data: dst.as_mut_ptr(),
+        ..Default::default()
+    };
+    NDIlib_util_audio_to_interleaved_32f_v2(audio_frame, &mut frame);
+}
+
+/// Converts the planar float samples of an NDI audio frame into the
+/// interleaved layout of `format`, keeping all channels (-1), averaging them to
+/// mono (-2) or extracting a single one. `no_samples` is the number of samples
+/// per channel in `data`, those the frame doesn't have are left silent. The
+/// frames aren't resampled here, `output-rate` gets them from NDI's frame-sync
+/// at the right rate already.
+///
+/// When keeping all channels, `no_channels` is the number of channels in
+/// `data`. A frame with fewer channels is up-mixed, by copying a mono channel
+/// or leaving the missing channels silent, and extra channels are dropped, so
+/// that a source changing its channel count never writes past `data`. A
+/// selected channel is never read past the frame's channels either.
+pub unsafe fn audio_to_interleaved(
+    format: gst_audio::AudioFormat,
+    audio_frame: &NDIlib_audio_frame_v2_t,
+    channel_select: i32,
+    no_channels: usize,
+    no_samples: usize,
+    data: &mut [u8],
+) -> Result<(), String> {
+    if channel_select >= audio_frame.no_channels {
+        return Err(format!(
+            "channel {} selected but the frame only has {} channels",
+            channel_select, audio_frame.no_channels
+        ));
+    }
+    let unchanged = channel_select == -1
+        && no_channels == audio_frame.no_channels as usize
+        && no_samples == audio_frame.no_samples as usize;
+    if format == gst_audio::AUDIO_FORMAT_S16 && unchanged {
+        to_interleaved_16s(audio_frame, samples_of::<i16>(data)?);
+    } else if format == gst_audio::AUDIO_FORMAT_S16 {
+        interleave(
+            audio_frame,
+            channel_select,
+            no_channels,
+            no_samples,
+            samples_of::<i16>(data)?,
+            |sample| {
+                let sample = sample * 32_768.0;
+                if sample >= f32::from(i16::MAX) {
+                    i16::MAX
+                } else if sample <= f32::from(i16::MIN) {
+                    i16::MIN
+                } else {
+                    sample as i16
+                }
+            },
+        );
+    } else if format == gst_audio::AUDIO_FORMAT_S32 {
+        interleave(
+            audio_frame,
+            channel_select,
+            no_channels,
+            no_samples,
+            samples_of::<i32>(data)?,
+            |sample| {
+                // Scale to the full i32 range, clamping anything beyond full scale
+                let sample = f64::from(sample) * 2_147_483_648.0;
+                if sample >= f64::from(i32::MAX) {
+                    i32::MAX
+                } else if sample <= f64::from(i32::MIN) {
+                    i32::MIN
+                } else {
+                    sample as i32
+                }
+            },
+        );
+    } else if format == gst_audio::AUDIO_FORMAT_F32 && unchanged {
+        to_interleaved_32f(audio_frame, samples_of::<f32>(data)?);
+    } else if format == gst_audio::AUDIO_FORMAT_F32 {
+        interleave(
+            audio_frame,
+            channel_select,
+            no_channels,
+            no_samples,
+            samples_of::<f32>(data)?,
+            |sample| sample,
+        );
+    } else {
+        // The format's Display recurses, its name doesn't
+        return Err(format!("unsupported audio format {}", format.to_string()));
+    }
+    Ok(())
+}
+
+// `data` as samples of `T`, if it's a whole number of aligned ones
+fn samples_of<T: FromByteSlice>(data: &mut [u8]) -> Result<&mut [T], String> {
+    data.as_mut_slice_of::<T>().map_err(|err| err.to_string())
+}
+
+unsafe fn interleave<T, F: Fn(f32) -> T>(
+    audio_frame: &NDIlib_audio_frame_v2_t,
+    channel_select: i32,
+    no_channels_out: usize,
+    no_samples_out: usize,
+    dst: &mut [T],
+    convert: F,
+) {
+    let no_channels = audio_frame.no_channels as usize;
+    let no_samples = audio_frame.no_samples as usize;
+    let stride = audio_frame.channel_stride_in_bytes as usize / mem::size_of::<f32>();
+
+    // Samples past the end of a shorter frame are silent
+    let sample_at = |channel: usize, sample: usize| -> f32 {
+        if sample >= no_samples {
+            return 0.0;
+        }
+        *audio_frame.p_data.add(channel * stride + sample)
+    };
+
+    match channel_select {
+        -1 => {
+            for sample in 0..no_samples_out {
+                for channel in 0..no_channels_out {
+                    dst[sample * no_channels_out + channel] = convert(if channel < no_channels {
+                        sample_at(channel, sample)
+                    } else if no_channels == 1 {
+                        sample_at(0, sample)
+                    } else {
+                        0.0
+                    });
+                }
+            }
+        }
+        -2 => {
+            // Averaged, so a full scale signal on every channel stays in range
+            for (sample, dst) in dst.iter_mut().enumerate().take(no_samples_out) {
+                let sum: f32 = (0..no_channels)
+                    .map(|channel| sample_at(channel, sample))
+                    .sum();
+                *dst = convert(sum / cmp::max(no_channels, 1) as f32);
+            }
+        }
+        channel => {
+            for (sample, dst) in dst.iter_mut().enumerate().take(no_samples_out) {
+                *dst = convert(sample_at(channel as usize, sample));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byte_slice_cast::AsSliceOf;
+
+    // A frame with `no_channels` planes of samples, which `planes` must outlive
+    fn audio_frame(planes: &[f32], no_channels: usize) -> NDIlib_audio_frame_v2_t {
+        let no_samples = planes.len() / no_channels;
+        NDIlib_audio_frame_v2_t {
+            sample_rate: 48_000,
+            no_channels: no_channels as i32,
+            no_samples: no_samples as i32,
+            p_data: planes.as_ptr(),
+            channel_stride_in_bytes: (no_samples * mem::size_of::<f32>()) as i32,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn downmix_averages_the_channels() {
+        let planes = [1.0, 0.5, -1.0, 1.0, 0.5, 0.0];
+        let frame = audio_frame(&planes, 2);
+        let mut data = vec![0u8; 3 * mem::size_of::<f32>()];
+        unsafe {
+            audio_to_interleaved(gst_audio::AUDIO_FORMAT_F32, &frame, -2, 1, 3, &mut data).unwrap();
+        }
+        assert_eq!(data.as_slice_of::<f32>().unwrap(), &[1.0, 0.5, -0.5]);
+    }
+
+    #[test]
+    fn samples_missing_from_a_short_frame_are_silent() {
+        let planes = [0.25, -0.25];
+        let frame = audio_frame(&planes, 1);
+        let mut data = vec![0xffu8; 3 * mem::size_of::<f32>()];
+        unsafe {
+            audio_to_interleaved(gst_audio::AUDIO_FORMAT_F32, &frame, 0, 1, 3, &mut data).unwrap();
+        }
+        assert_eq!(data.as_slice_of::<f32>().unwrap(), &[0.25, -0.25, 0.0]);
+    }
+
+    #[test]
+    fn s16_is_scaled_to_full_scale_and_clamped() {
+        let planes = [1.0, -1.0, 0.5, 2.0, -2.0, 0.0];
+        let frame = audio_frame(&planes, 1);
+        let mut data = vec![0u8; 6 * mem::size_of::<i16>()];
+        unsafe {
+            audio_to_interleaved(gst_audio::AUDIO_FORMAT_S16, &frame, 0, 1, 6, &mut data).unwrap();
+        }
+        assert_eq!(
+            data.as_slice_of::<i16>().unwrap(),
+            &[i16::MAX, i16::MIN, 16_384, i16::MAX, i16::MIN, 0]
+        );
+    }
+
+    #[test]
+    fn s32_is_scaled_to_full_scale_and_clamped() {
+        let planes = [1.0, -1.0, 0.5, 2.0, -2.0, 0.0];
+        let frame = audio_frame(&planes, 1);
+        let mut data = vec![0u8; 6 * mem::size_of::<i32>()];
+        unsafe {
+            audio_to_interleaved(gst_audio::AUDIO_FORMAT_S32, &frame, 0, 1, 6, &mut data).unwrap();
+        }
+        assert_eq!(
+            data.as_slice_of::<i32>().unwrap(),
+            &[i32::MAX, i32::MIN, 1 << 30, i32::MAX, i32::MIN, 0]
+        );
+    }
+
+    #[test]
+    fn missing_channels_are_up_mixed() {
+        // Mono is copied to both channels, a missing third one of two is silent
+        let mono = [0.5, -0.5];
+        let mut data = vec![0u8; 4 * mem::size_of::<f32>()];
+        unsafe {
+            audio_to_interleaved(
+                gst_audio::AUDIO_FORMAT_F32,
+                &audio_frame(&mono, 1),
+                -1,
+                2,
+                2,
+                &mut data,
+            )
+            .unwrap();
+        }
+        assert_eq!(data.as_slice_of::<f32>().unwrap(), &[0.5, 0.5, -0.5, -0.5]);
+
+        let stereo = [0.25, 0.5, -0.25, -0.5];
+        let mut data = vec![0u8; 6 * mem::size_of::<f32>()];
+        unsafe {
+            audio_to_interleaved(
+                gst_audio::AUDIO_FORMAT_F32,
+                &audio_frame(&stereo, 2),
+                -1,
+                3,
+                2,
+                &mut data,
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            data.as_slice_of::<f32>().unwrap(),
+            &[0.25, -0.25, 0.0, 0.5, -0.5, 0.0]
+        );
+    }
+
+    #[test]
+    fn misaligned_output_is_rejected() {
+        let planes = [0.0, 0.0];
+        let mut data = vec![0u8; 2 * mem::size_of::<f32>() + 1];
+        let res = unsafe {
+            audio_to_interleaved(
+                gst_audio::AUDIO_FORMAT_F32,
+                &audio_frame(&planes, 1),
+                0,
+                1,
+                2,
+                &mut data,
+            )
+        };
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn channels_missing_from_the_frame_are_not_selected() {
+        let planes = [0.0, 0.0];
+        let mut data = vec![0u8; 2 * mem::size_of::<f32>()];
+        let res = unsafe {
+            audio_to_interleaved(
+                gst_audio::AUDIO_FORMAT_F32,
+                &audio_frame(&planes, 1),
+                1,
+                1,
+                2,
+                &mut data,
+            )
+        };
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn unsupported_formats_are_rejected() {
+        let planes = [0.0, 0.0];
+        let mut data = vec![0u8; 2 * mem::size_of::<f64>()];
+        let res = unsafe {
+            audio_to_interleaved(
+                gst_audio::AUDIO_FORMAT_F64,
+                &audio_frame(&planes, 1),
+                0,
+                1,
+                2,
+                &mut data,
+            )
+        };
+        assert!(res.is_err());
+    }
+}
@@ -3,6 +3,7 @@
 use glib;
 use glib::subclass;
 use glib::subclass::prelude::*;
+use glib::translate::ToGlib;
 use gst;
 use gst::prelude::*;
 use gst::subclass::prelude::*;
@@ -12,16 +13,57 @@ use gst_base::subclass::prelude::*;
 
 use gst::Fraction;
 use gst_video;
+use lazy_static;
 
-use std::sync::Mutex;
-use std::{i32, u32};
-
+use std::ffi::{CStr, CString};
 use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{cmp, slice};
 
+use add_connection_metadata;
+use add_timecode_meta;
+use available_sources;
+use capture_frame;
+use changed_caps;
 use connect_ndi;
+use connection_info;
+use emit_metadata;
+use forget_discovered_sources;
+use max_latency;
+use metadata_attribute;
+use ndi_frame_time;
 use ndi_struct;
+use ndi_uri_to_source;
 use ndisys::*;
+use nditest::{test_sender, TestSender, TEST_SOURCE};
+use probe_format;
+use publish_source_info;
+use receiver_product;
+use receiver_shared;
+use receiver_source;
+use receiver_tags;
+use renegotiate;
+use select_policy_from_value;
+use select_policy_param_spec;
+use select_policy_to_value;
+use source_available;
+use source_lost;
+use source_to_ndi_uri;
 use stop_ndi;
+use timestamp_mode_from_value;
+use timestamp_mode_param_spec;
+use timestamp_mode_to_value;
+use timestamp_undefined;
+use update_clock_drift;
+use update_receiver_tags;
+use wait_for_queue;
+use web_control_url;
+use with_receiver;
+use PtsResume;
+use SOURCE_INFO_CONTEXT;
 
 use hashmap_receivers;
 
@@ -29,24 +71,76 @@ use hashmap_receivers;
 struct Settings {
     stream_name: String,
     ip: String,
-    loss_threshold: u32,
-    id_receiver: i8,
+    receiver_name: String,
+    eos_on_disconnect: bool,
+    id_receiver: usize,
     latency: Option<gst::ClockTime>,
+    max_latency: u64,
+    request_width: u32,
+    request_height: u32,
+    request_framerate: gst::Fraction,
+    allow_video_fields: bool,
+    timeout: u64,
+    select_policy: String,
+    queue_depth: u32,
+    prefer_speed: bool,
+    attach_timecode_meta: bool,
+    failover_source: String,
+    max_width: u32,
+    max_height: u32,
+    bind_address: String,
+    use_frame_timestamps: bool,
+    warmup_frames: u32,
+    poll_interval: u32,
+    capture_timeout: u32,
+    config_path: String,
+    source_index: i32,
+    groups: String,
+    target_framerate: gst::Fraction,
+    dump_first_frame_header: bool,
+    timestamp_mode: String,
+    discovery_timeout: u32,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Settings {
-            stream_name: String::from("Fixed ndi stream name"),
+            stream_name: String::from(""),
             ip: String::from(""),
-            loss_threshold: 5,
+            receiver_name: String::from(""),
+            eos_on_disconnect: false,
             id_receiver: 0,
             latency: None,
+            max_latency: u64::MAX,
+            request_width: 0,
+            request_height: 0,
+            request_framerate: gst::Fraction::new(0, 1),
+            allow_video_fields: false,
+            timeout: 0,
+            select_policy: String::from("first"),
+            queue_depth: 0,
+            prefer_speed: false,
+            attach_timecode_meta: false,
+            failover_source: String::new(),
+            max_width: 0,
+            max_height: 0,
+            bind_address: String::new(),
+            use_frame_timestamps: true,
+            warmup_frames: 0,
+            poll_interval: 0,
+            capture_timeout: 1000,
+            config_path: String::new(),
+            source_index: -1,
+            groups: String::new(),
+            target_framerate: gst::Fraction::new(0, 1),
+            dump_first_frame_header: false,
+            timestamp_mode: String::from("receive-timestamp"),
+            discovery_timeout: 2000,
         }
     }
 }
 
-static PROPERTIES: [subclass::Property; 3] = [
+static PROPERTIES: [subclass::Property; 37] = [
     subclass::Property("stream-name", |_| {
         glib::ParamSpec::string(
             "stream-name",
@@ -60,7 +154,7 @@ static PROPERTIES: [subclass::Property; 3] = [
         glib::ParamSpec::string(
             "ip",
             "Stream IP",
-            "IP of the streaming device. Ex: 127.0.0.1:5961",
+            "IP of the streaming device. Ex: 127.0.0.1:5961 or [::1]:5961",
             None,
             glib::ParamFlags::READWRITE,
         )
@@ -69,27 +163,414 @@ static PROPERTIES: [subclass::Property; 3] = [
         glib::ParamSpec::uint(
             "loss-threshold",
             "Loss threshold",
-            "Loss threshold",
+            "Number of consecutive captures timing out after capture-timeout-ms before reconnecting to the source (0 = on the first one)",
             0,
-            60,
+            3600,
             5,
             glib::ParamFlags::READWRITE,
         )
     }),
+    subclass::Property("eos-on-disconnect", |_| {
+        glib::ParamSpec::boolean(
+            "eos-on-disconnect",
+            "EOS on disconnect",
+            "Send EOS instead of an error when the loss threshold is reached and the source is no longer visible on the network",
+            false,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("max-latency", |_| {
+        glib::ParamSpec::uint64(
+            "max-latency",
+            "Max latency",
+            "Maximum latency reported in the latency query, in nanoseconds (-1 = unlimited)",
+            0,
+            u64::MAX,
+            u64::MAX,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("available-sources", |_| {
+        glib::ParamSpec::string(
+            "available-sources",
+            "Available sources",
+            "Newline-separated names of the NDI sources currently visible on the network",
+            None,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("receiver-name", |_| {
+        glib::ParamSpec::string(
+            "receiver-name",
+            "Receiver Name",
+            "Name advertised to the NDI source for this receiver (defaults to the element name)",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("request-width", |_| {
+        glib::ParamSpec::uint(
+            "request-width",
+            "Request width",
+            "Width to ask the source to send, for sources that support it (0 = source default)",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("request-height", |_| {
+        glib::ParamSpec::uint(
+            "request-height",
+            "Request height",
+            "Height to ask the source to send, for sources that support it (0 = source default)",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("request-framerate", |_| {
+        glib::ParamSpec::fraction(
+            "request-framerate",
+            "Request framerate",
+            "Framerate to ask the source to send, for sources that support it (0/1 = source default)",
+            gst::Fraction::new(0, 1),
+            gst::Fraction::new(i32::MAX, 1),
+            gst::Fraction::new(0, 1),
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("allow-video-fields", |_| {
+        glib::ParamSpec::boolean(
+            "allow-video-fields",
+            "Allow video fields",
+            "Let the receiver deliver interlaced video as individual fields instead of weaved frames",
+            false,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("timeout", |_| {
+        glib::ParamSpec::uint64(
+            "timeout",
+            "Timeout",
+            "Post an error if no frame is received for this long, in nanoseconds (0 = disabled)",
+            0,
+            u64::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("uri", |_| {
+        glib::ParamSpec::string(
+            "uri",
+            "URI",
+            "NDI source as an ndi:// URI, e.g. ndi://HOST%20(Channel%201) or ndi://127.0.0.1:5961",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("select-policy", |_| {
+        select_policy_param_spec("How to pick between sources sharing the same name")
+    }),
+    subclass::Property("web-control-url", |_| {
+        glib::ParamSpec::string(
+            "web-control-url",
+            "Web Control URL",
+            "URL of the web configuration page advertised by the connected NDI source",
+            None,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("queue-depth", |_| {
+        glib::ParamSpec::uint(
+            "queue-depth",
+            "Queue Depth",
+            "Number of frames NDI buffers before the first buffer is pushed, added to the reported latency",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("prefer-speed", |_| {
+        glib::ParamSpec::boolean(
+            "prefer-speed",
+            "Prefer speed",
+            "Let NDI pick the fastest color conversion instead of the best quality one",
+            false,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("attach-timecode-meta", |_| {
+        glib::ParamSpec::boolean(
+            "attach-timecode-meta",
+            "Attach timecode meta",
+            "Attach the sender's NDI timecode to each buffer as a reference timestamp (timestamp/x-ndi-timecode)",
+            false,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("failover-source", |_| {
+        glib::ParamSpec::string(
+            "failover-source",
+            "Failover source",
+            "Name of the NDI source to connect to when the configured one can't be found",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("max-width", |_| {
+        glib::ParamSpec::uint(
+            "max-width",
+            "Max width",
+            "Ask the sender for video no wider than this, in pixels (0 = unlimited)",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("max-height", |_| {
+        glib::ParamSpec::uint(
+            "max-height",
+            "Max height",
+            "Ask the sender for video no taller than this, in pixels (0 = unlimited)",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("bind-address", |_| {
+        glib::ParamSpec::string(
+            "bind-address",
+            "Bind address",
+            "IP address of the local interface NDI should use, applied process-wide before NDI is initialized",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("use-frame-timestamps", |_| {
+        glib::ParamSpec::boolean(
+            "use-frame-timestamps",
+            "Use frame timestamps",
+            "Timestamp buffers from the NDI frame timestamps instead of their arrival time",
+            true,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("warmup-frames", |_| {
+        glib::ParamSpec::uint(
+            "warmup-frames",
+            "Warm-up Frames",
+            "Number of frames dropped after connecting, while the NDI stream settles",
+            0,
+            u32::MAX,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("poll-interval", |_| {
+        glib::ParamSpec::uint(
+            "poll-interval",
+            "Poll Interval",
+            "Longest wait for a frame in one go in ms, 0 waits for the whole capture-timeout-ms",
+            0,
+            1000,
+            0,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("ptz-is-supported", |_| {
+        glib::ParamSpec::boolean(
+            "ptz-is-supported",
+            "PTZ is supported",
+            "Whether the connected NDI source can be controlled with the ptz-* signals",
+            false,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("recording-is-supported", |_| {
+        glib::ParamSpec::boolean(
+            "recording-is-supported",
+            "Recording is supported",
+            "Whether the connected NDI source can be told to record with recording-start",
+            false,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("connection-info", |_| {
+        glib::ParamSpec::boxed(
+            "connection-info",
+            "Connection info",
+            "Source, address, URL, bandwidth, groups and negotiated caps of the current connection",
+            gst::Structure::static_type(),
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("capture-timeout-ms", |_| {
+        glib::ParamSpec::uint(
+            "capture-timeout-ms",
+            "Capture Timeout",
+            "How long to wait for a frame in ms before counting it as lost towards loss-threshold",
+            1,
+            60_000,
+            1000,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("config-path", |_| {
+        glib::ParamSpec::string(
+            "config-path",
+            "Config path",
+            "Directory with an ndi-config.v1.json to initialize NDI with, applied process-wide before NDI is initialized",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("clock-drift", |_| {
+        glib::ParamSpec::int64(
+            "clock-drift",
+            "Clock Drift",
+            "Average of how far the running time at delivery is ahead of the buffer timestamps, in nanoseconds",
+            i64::MIN,
+            i64::MAX,
+            0,
+            glib::ParamFlags::READABLE,
+        )
+    }),
+    subclass::Property("source-index", |_| {
+        glib::ParamSpec::int(
+            "source-index",
+            "Source Index",
+            "Index of the source to connect to among those sharing the same name, ordered by URL (-1 = use select-policy)",
+            -1,
+            i32::MAX,
+            -1,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("groups", |_| {
+        glib::ParamSpec::string(
+            "groups",
+            "Groups",
+            "Comma separated NDI groups to look for the source in (empty = the groups NDI is configured with)",
+            None,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("target-framerate", |_| {
+        glib::ParamSpec::fraction(
+            "target-framerate",
+            "Target framerate",
+            "Framerate to output at through an NDI frame-sync, repeating or dropping frames as needed (0/1 = source framerate)",
+            gst::Fraction::new(0, 1),
+            gst::Fraction::new(i32::MAX, 1),
+            gst::Fraction::new(0, 1),
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("dump-first-frame-header", |_| {
+        glib::ParamSpec::boolean(
+            "dump-first-frame-header",
+            "Dump first frame header",
+            "Log every field of the first NDI video frame header at TRACE level, for troubleshooting sources that look wrong",
+            false,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
+    subclass::Property("timestamp-mode", |_| {
+        timestamp_mode_param_spec("What NDI frame time to timestamp buffers from")
+    }),
+    subclass::Property("discovery-timeout", |_| {
+        glib::ParamSpec::uint(
+            "discovery-timeout",
+            "Discovery timeout",
+            "How long to look for the source on the network before giving up, in milliseconds",
+            0,
+            u32::MAX,
+            2000,
+            glib::ParamFlags::READWRITE,
+        )
+    }),
 ];
 
+#[derive(Default)]
 struct State {
     info: Option<gst_video::VideoInfo>,
+    // Used when downstream didn't give us a pool with buffers large enough
+    pool: Option<gst::BufferPool>,
+    // Whether the first frame since set_caps() was compared against the caps
+    caps_checked: bool,
+    // Caps for the new format of a source that changed it, see renegotiate()
+    pending_caps: Option<gst::Caps>,
+}
+
+// Set from QoS events, read when a frame is captured
+struct Qos {
+    // Frames with an earlier running time reach downstream too late
+    earliest: gst::ClockTime,
+    proportion: f64,
 }
 
-impl Default for State {
-    fn default() -> State {
-        State { info: None }
+impl Default for Qos {
+    fn default() -> Qos {
+        Qos {
+            earliest: gst::ClockTime::none(),
+            proportion: 1.0,
+        }
     }
 }
 
 struct TimestampData {
     offset: u64,
+    last_frame: Instant,
+    discont: bool,
+    // Frames dropped so far for warmup-frames
+    warmup_dropped: u32,
+    // Whether dump-first-frame-header already logged a frame
+    header_dumped: bool,
+    // Whether frames already came without a receive timestamp
+    timestamp_undefined: bool,
+    // Frames output and dropped for QoS, for the QoS messages
+    qos_processed: u64,
+    qos_dropped: u64,
+    // Running time of the first frame with a target-framerate
+    clocked_start: Option<u64>,
+    // Buffers in the clock-drift average so far
+    drift_samples: i64,
+    // PTS plus duration of the last buffer
+    next_pts: gst::ClockTime,
+    // Where the frames of a new receiver go on from after reconnect()
+    resume: PtsResume,
+}
+
+impl Default for TimestampData {
+    fn default() -> TimestampData {
+        TimestampData {
+            offset: 0,
+            last_frame: Instant::now(),
+            discont: false,
+            warmup_dropped: 0,
+            header_dumped: false,
+            timestamp_undefined: false,
+            qos_processed: 0,
+            qos_dropped: 0,
+            clocked_start: None,
+            drift_samples: 0,
+            next_pts: gst::CLOCK_TIME_NONE,
+            resume: Default::default(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref CAT: gst::DebugCategory = gst::DebugCategory::new(
+        "ndivideosrc",
+        gst::DebugColorFlags::empty(),
+        "NewTek NDI Video Source",
+    );
 }
 
 struct NdiVideoSrc {
@@ -97,6 +578,220 @@ struct NdiVideoSrc {
     settings: Mutex<Settings>,
     state: Mutex<State>,
     timestamp_data: Mutex<TimestampData>,
+    test_sender: Mutex<Option<Arc<TestSender>>>,
+    loss_threshold: AtomicU32,
+    // Set between unlock() and unlock_stop(), checked between captures
+    unlocked: AtomicBool,
+    // Created on the first capture with a target-framerate, and dropped
+    // before the receiver is stopped
+    framesync: Mutex<Option<FrameSync>>,
+    qos: Mutex<Qos>,
+    clock_drift: AtomicI64,
+    // The ndi-source-info context of the current connection, for context queries
+    source_info: Mutex<Option<gst::Context>>,
+    // The source's manufacturer and model as last sent in the stream tags
+    tags_product: Mutex<Option<(String, String)>>,
+    // Metadata received while capturing, emitted after the capture
+    pending_metadata: Mutex<Vec<gst::Structure>>,
+}
+
+impl NdiVideoSrc {
+    fn connect(&self, element: &gst_base::BaseSrc, settings: &mut Settings) -> bool {
+        let receiver_name = if settings.receiver_name.is_empty() {
+            element.get_name().to_string()
+        } else {
+            settings.receiver_name.clone()
+        };
+        if settings.stream_name == TEST_SOURCE {
+            let sender = test_sender();
+            if sender.is_none() {
+                gst_element_error!(
+                    element,
+                    gst::ResourceError::OpenRead,
+                    ["Could not start the NDI test pattern sender"]
+                );
+                return false;
+            }
+            *self
+                .test_sender
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = sender;
+        }
+        settings.id_receiver = connect_ndi(
+            self.cat,
+            element,
+            &settings.ip.clone(),
+            &settings.stream_name.clone(),
+            &receiver_name,
+            settings.allow_video_fields,
+            settings.prefer_speed,
+            &settings.select_policy.clone(),
+            settings.source_index,
+            &settings.failover_source.clone(),
+            &settings.groups.clone(),
+            settings.discovery_timeout,
+            &settings.bind_address.clone(),
+            &settings.config_path.clone(),
+            true,
+            // The frame-sync is the only one to capture from the receiver then
+            *settings.target_framerate.numer() != 0,
+        );
+        if settings.id_receiver == 0 {
+            return false;
+        }
+
+        // Base sources hold a tag event back until after the segment
+        if let Some(tags) = receiver_tags(settings.id_receiver) {
+            element.send_event(gst::Event::new_tag(tags).build());
+        }
+        *self
+            .tags_product
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = receiver_product(settings.id_receiver);
+
+        // A resolution cap is sent as a preferred resolution no larger than it
+        let cap = |request: u32, max: u32| {
+            if max != 0 && (request == 0 || request > max) {
+                max
+            } else {
+                request
+            }
+        };
+        let request_width = cap(settings.request_width, settings.max_width);
+        let request_height = cap(settings.request_height, settings.max_height);
+
+        let mut request = String::new();
+        if request_width != 0 {
+            request += &format!(" xres=\"{}\"", request_width);
+        }
+        if request_height != 0 {
+            request += &format!(" yres=\"{}\"", request_height);
+        }
+        if *settings.request_framerate.numer() != 0 {
+            request += &format!(
+                " frame_rate_N=\"{}\" frame_rate_D=\"{}\"",
+                settings.request_framerate.numer(),
+                settings.request_framerate.denom()
+            );
+        }
+        let request = if request.is_empty() {
+            request
+        } else {
+            format!("<ndi_video_preferred{}/>", request)
+        };
+        if !request.is_empty() {
+            gst_debug!(self.cat, obj: element, "Requesting video format {}", request);
+            add_connection_metadata(settings.id_receiver, &request);
+        }
+
+        *self
+            .source_info
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) =
+            Some(publish_source_info(element, settings.id_receiver, &request));
+        true
+    }
+
+    fn ptz<F>(&self, element: &gst_base::BaseSrc, command: &str, f: F) -> bool
+    where
+        F: FnOnce(NDIlib_recv_instance_t) -> bool,
+    {
+        let id_receiver = self
+            .settings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .id_receiver;
+        let sent = with_receiver(id_receiver, f);
+        if sent {
+            gst_debug!(self.cat, obj: element, "Sent PTZ {}", command);
+        } else {
+            gst_warning!(self.cat, obj: element, "Couldn't send PTZ {}", command);
+        }
+        sent
+    }
+
+    fn recording_start(&self, element: &gst_base::BaseSrc, filename_hint: &str) -> Option<String> {
+        let id_receiver = self
+            .settings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .id_receiver;
+        let p_filename_hint = CString::new(filename_hint).unwrap_or_default();
+        let filename = with_receiver(id_receiver, |recv| unsafe {
+            let p_filename_hint = if filename_hint.is_empty() {
+                ptr::null()
+            } else {
+                p_filename_hint.as_ptr()
+            };
+            if !NDIlib_recv_recording_start(recv, p_filename_hint) {
+                return None;
+            }
+            // Not every source reports the file name
+            let p_filename = NDIlib_recv_recording_get_filename(recv);
+            if p_filename.is_null() {
+                return Some(String::new());
+            }
+            let filename = CStr::from_ptr(p_filename).to_string_lossy().into_owned();
+            NDIlib_recv_free_string(recv, p_filename);
+            Some(filename)
+        });
+        match filename {
+            Some(ref filename) => {
+                gst_debug!(self.cat, obj: element, "Started recording to '{}'", filename)
+            }
+            None => gst_warning!(self.cat, obj: element, "Couldn't start recording"),
+        }
+        filename
+    }
+
+    fn reconnect(&self, element: &gst_base::BaseSrc) -> bool {
+        // create() holds the settings for the whole capture, so it waits here
+        // until the new receiver is in place
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        if settings.id_receiver == 0 {
+            gst_warning!(self.cat, obj: element, "No NDI receiver to reconnect");
+            return false;
+        }
+        // Recreating it would pull it from under the other element, which
+        // keeps the id of the old one
+        if receiver_shared(settings.id_receiver) {
+            gst_warning!(
+                self.cat,
+                obj: element,
+                "Not reconnecting the NDI receiver shared with another element"
+            );
+            return false;
+        }
+
+        gst_debug!(self.cat, obj: element, "Reconnecting to the NDI source");
+        // The source may have gone away or moved
+        forget_discovered_sources();
+        *self
+            .framesync
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        stop_ndi(self.cat, element, settings.id_receiver);
+        let connected = self.connect(element, &mut settings);
+        if !connected {
+            gst_element_error!(
+                element,
+                gst::ResourceError::NotFound,
+                ["Could not reconnect to this source"]
+            );
+        }
+
+        let mut timestamp_data = self
+            .timestamp_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Timestamps go on from the last buffer instead of starting over
+        let resume = timestamp_data.resume.reconnected(timestamp_data.next_pts);
+        *timestamp_data = Default::default();
+        timestamp_data.discont = true;
+        timestamp_data.resume = resume;
+
+        connected
+    }
 }
 
 impl ObjectSubclass for NdiVideoSrc {
@@ -107,16 +802,25 @@ impl ObjectSubclass for NdiVideoSrc {
 
     glib_object_subclass!();
 
+    fn type_init(type_: &mut subclass::InitializingType<Self>) {
+        type_.add_interface::<gst::URIHandler>();
+    }
+
     fn new() -> Self {
         Self {
-            cat: gst::DebugCategory::new(
-                "ndivideosrc",
-                gst::DebugColorFlags::empty(),
-                "NewTek NDI Video Source",
-            ),
+            cat: *CAT,
             settings: Mutex::new(Default::default()),
             state: Mutex::new(Default::default()),
-            timestamp_data: Mutex::new(TimestampData { offset: 0 }),
+            timestamp_data: Mutex::new(Default::default()),
+            test_sender: Mutex::new(None),
+            loss_threshold: AtomicU32::new(5),
+            unlocked: AtomicBool::new(false),
+            framesync: Mutex::new(None),
+            qos: Mutex::new(Default::default()),
+            clock_drift: AtomicI64::new(0),
+            source_info: Mutex::new(None),
+            tags_product: Mutex::new(None),
+            pending_metadata: Mutex::new(Vec::new()),
         }
     }
 
@@ -138,6 +842,9 @@ impl ObjectSubclass for NdiVideoSrc {
                     &gst::List::new(&[
                         //TODO add all formats
                         &gst_video::VideoFormat::Uyvy.to_string(),
+                        &gst_video::VideoFormat::V216.to_string(),
+                        &gst_video::VideoFormat::V210.to_string(),
+                        &gst_video::VideoFormat::P01010le.to_string(),
                         //&gst_video::VideoFormat::Rgb.to_string(),
                         //&gst_video::VideoFormat::Gray8.to_string(),
                     ]),
@@ -164,6 +871,146 @@ impl ObjectSubclass for NdiVideoSrc {
         klass.add_pad_template(src_pad_template);
 
         klass.install_properties(&PROPERTIES);
+
+        klass.add_signal_with_class_handler(
+            "probe-format",
+            glib::SignalFlags::RUN_LAST | glib::SignalFlags::ACTION,
+            &[],
+            gst::Structure::static_type(),
+            |_, args| {
+                let element = args[0].get::<gst_base::BaseSrc>().unwrap();
+                let src = NdiVideoSrc::from_instance(&element);
+                let (ip, stream_name) = {
+                    let settings = src.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                    (settings.ip.clone(), settings.stream_name.clone())
+                };
+
+                let format = probe_format(&ip, &stream_name);
+                gst_debug!(src.cat, obj: &element, "Probed source format {:?}", format);
+                Some(format.to_value())
+            },
+        );
+
+        klass.add_signal(
+            "format-changed",
+            glib::SignalFlags::RUN_LAST,
+            &[gst::Caps::static_type()],
+            glib::Type::Unit,
+        );
+
+        klass.add_signal(
+            "metadata-received",
+            glib::SignalFlags::RUN_LAST,
+            &[gst::Structure::static_type()],
+            glib::Type::Unit,
+        );
+
+        klass.add_signal_with_class_handler(
+            "reconnect",
+            glib::SignalFlags::RUN_LAST | glib::SignalFlags::ACTION,
+            &[],
+            bool::static_type(),
+            |_, args| {
+                let element = args[0].get::<gst_base::BaseSrc>().unwrap();
+                let src = NdiVideoSrc::from_instance(&element);
+                Some(src.reconnect(&element).to_value())
+            },
+        );
+
+        // Zoom from 0.0 (zoomed in) to 1.0 (zoomed out)
+        klass.add_signal_with_class_handler(
+            "ptz-zoom",
+            glib::SignalFlags::RUN_LAST | glib::SignalFlags::ACTION,
+            &[f32::static_type()],
+            bool::static_type(),
+            |_, args| {
+                let element = args[0].get::<gst_base::BaseSrc>().unwrap();
+                let zoom = args[1].get::<f32>().unwrap();
+                let src = NdiVideoSrc::from_instance(&element);
+                Some(
+                    src.ptz(&element, "zoom", |recv| unsafe {
+                        NDIlib_recv_ptz_zoom(recv, zoom)
+                    })
+                    .to_value(),
+                )
+            },
+        );
+
+        // Pan and tilt speeds from -1.0 to 1.0, 0.0 stops moving
+        klass.add_signal_with_class_handler(
+            "ptz-pan-tilt-speed",
+            glib::SignalFlags::RUN_LAST | glib::SignalFlags::ACTION,
+            &[f32::static_type(), f32::static_type()],
+            bool::static_type(),
+            |_, args| {
+                let element = args[0].get::<gst_base::BaseSrc>().unwrap();
+                let pan_speed = args[1].get::<f32>().unwrap();
+                let tilt_speed = args[2].get::<f32>().unwrap();
+                let src = NdiVideoSrc::from_instance(&element);
+                Some(
+                    src.ptz(&element, "pan-tilt-speed", |recv| unsafe {
+                        NDIlib_recv_ptz_pan_tilt_speed(recv, pan_speed, tilt_speed)
+                    })
+                    .to_value(),
+                )
+            },
+        );
+
+        // Preset from 0 to 99, speed from 0.0 to 1.0
+        klass.add_signal_with_class_handler(
+            "ptz-recall-preset",
+            glib::SignalFlags::RUN_LAST | glib::SignalFlags::ACTION,
+            &[i32::static_type(), f32::static_type()],
+            bool::static_type(),
+            |_, args| {
+                let element = args[0].get::<gst_base::BaseSrc>().unwrap();
+                let preset = args[1].get::<i32>().unwrap();
+                let speed = args[2].get::<f32>().unwrap();
+                let src = NdiVideoSrc::from_instance(&element);
+                Some(
+                    src.ptz(&element, "recall-preset", |recv| unsafe {
+                        NDIlib_recv_ptz_recall_preset(recv, preset, speed)
+                    })
+                    .to_value(),
+                )
+            },
+        );
+
+        // Takes a file name hint, which may be empty, and returns the name of
+        // the file the source records to, or None if it couldn't start
+        klass.add_signal_with_class_handler(
+            "recording-start",
+            glib::SignalFlags::RUN_LAST | glib::SignalFlags::ACTION,
+            &[String::static_type()],
+            String::static_type(),
+            |_, args| {
+                let element = args[0].get::<gst_base::BaseSrc>().unwrap();
+                let filename_hint = args[1].get::<String>().unwrap_or_default();
+                let src = NdiVideoSrc::from_instance(&element);
+                Some(src.recording_start(&element, &filename_hint).to_value())
+            },
+        );
+
+        klass.add_signal_with_class_handler(
+            "recording-stop",
+            glib::SignalFlags::RUN_LAST | glib::SignalFlags::ACTION,
+            &[],
+            bool::static_type(),
+            |_, args| {
+                let element = args[0].get::<gst_base::BaseSrc>().unwrap();
+                let src = NdiVideoSrc::from_instance(&element);
+                let id_receiver = src
+                    .settings
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .id_receiver;
+                let stopped = with_receiver(id_receiver, |recv| unsafe {
+                    NDIlib_recv_recording_stop(recv)
+                });
+                gst_debug!(src.cat, obj: &element, "Stopped recording: {}", stopped);
+                Some(stopped.to_value())
+            },
+        );
     }
 }
 
@@ -186,8 +1033,8 @@ impl ObjectImpl for NdiVideoSrc {
 
         match *prop {
             subclass::Property("stream-name", ..) => {
-                let mut settings = self.settings.lock().unwrap();
-                let stream_name = value.get().unwrap();
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let stream_name = value.get().unwrap_or_default();
                 gst_debug!(
                     self.cat,
                     obj: basesrc,
@@ -199,8 +1046,8 @@ impl ObjectImpl for NdiVideoSrc {
                 drop(settings);
             }
             subclass::Property("ip", ..) => {
-                let mut settings = self.settings.lock().unwrap();
-                let ip = value.get().unwrap();
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let ip = value.get().unwrap_or_default();
                 gst_debug!(
                     self.cat,
                     obj: basesrc,
@@ -212,92 +1059,604 @@ impl ObjectImpl for NdiVideoSrc {
                 drop(settings);
             }
             subclass::Property("loss-threshold", ..) => {
-                let mut settings = self.settings.lock().unwrap();
+                // Not part of the settings, so a running create() sees the
+                // change right away
                 let loss_threshold = value.get().unwrap();
                 gst_debug!(
                     self.cat,
                     obj: basesrc,
                     "Changing loss threshold from {} to {}",
-                    settings.loss_threshold,
+                    self.loss_threshold.load(Ordering::SeqCst),
                     loss_threshold
                 );
-                settings.loss_threshold = loss_threshold;
+                self.loss_threshold.store(loss_threshold, Ordering::SeqCst);
+            }
+            subclass::Property("eos-on-disconnect", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let eos_on_disconnect = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing eos-on-disconnect from {} to {}",
+                    settings.eos_on_disconnect,
+                    eos_on_disconnect
+                );
+                settings.eos_on_disconnect = eos_on_disconnect;
                 drop(settings);
             }
-            _ => unimplemented!(),
-        }
-    }
-
-    fn get_property(&self, _obj: &glib::Object, id: usize) -> Result<glib::Value, ()> {
-        let prop = &PROPERTIES[id];
-
-        match *prop {
-            subclass::Property("stream-name", ..) => {
-                let settings = self.settings.lock().unwrap();
-                Ok(settings.stream_name.to_value())
+            subclass::Property("max-latency", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let max_latency = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing max latency from {} to {}",
+                    settings.max_latency,
+                    max_latency
+                );
+                settings.max_latency = max_latency;
+                drop(settings);
             }
-            subclass::Property("ip", ..) => {
-                let settings = self.settings.lock().unwrap();
-                Ok(settings.ip.to_value())
+            subclass::Property("receiver-name", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let receiver_name = value.get().unwrap_or_default();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing receiver-name from {} to {}",
+                    settings.receiver_name,
+                    receiver_name
+                );
+                settings.receiver_name = receiver_name;
+                drop(settings);
             }
-            subclass::Property("loss-threshold", ..) => {
-                let settings = self.settings.lock().unwrap();
-                Ok(settings.loss_threshold.to_value())
+            subclass::Property("request-width", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let request_width = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing request width from {} to {}",
+                    settings.request_width,
+                    request_width
+                );
+                settings.request_width = request_width;
+                drop(settings);
             }
-            _ => unimplemented!(),
-        }
-    }
-}
-
-impl ElementImpl for NdiVideoSrc {
-    fn change_state(
-        &self,
-        element: &gst::Element,
-        transition: gst::StateChange,
-    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
-        if transition == gst::StateChange::PausedToPlaying {
-            let mut receivers = hashmap_receivers.lock().unwrap();
-            let settings = self.settings.lock().unwrap();
-
-            let receiver = receivers.get_mut(&settings.id_receiver).unwrap();
-            let recv = &receiver.ndi_instance;
-            let pNDI_recv = recv.recv;
-
-            let video_frame: NDIlib_video_frame_v2_t = Default::default();
-
-            unsafe {
-                while NDIlib_recv_capture_v2(
-                    pNDI_recv,
-                    &video_frame,
-                    ptr::null(),
-                    ptr::null(),
-                    1000,
-                ) != NDIlib_frame_type_e::NDIlib_frame_type_video
-                {}
+            subclass::Property("request-height", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let request_height = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing request height from {} to {}",
+                    settings.request_height,
+                    request_height
+                );
+                settings.request_height = request_height;
+                drop(settings);
             }
-            gst_debug!(
-                self.cat,
-                obj: element,
-                "NDI video frame received: {:?}",
-                video_frame
-            );
+            subclass::Property("request-framerate", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let request_framerate = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing request framerate from {} to {}",
+                    settings.request_framerate,
+                    request_framerate
+                );
+                settings.request_framerate = request_framerate;
+                drop(settings);
+            }
+            subclass::Property("allow-video-fields", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let allow_video_fields = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing allow-video-fields from {} to {}",
+                    settings.allow_video_fields,
+                    allow_video_fields
+                );
+                settings.allow_video_fields = allow_video_fields;
+                drop(settings);
+            }
+            subclass::Property("timeout", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let timeout = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing timeout from {} to {}",
+                    settings.timeout,
+                    timeout
+                );
+                settings.timeout = timeout;
+                drop(settings);
+            }
+            subclass::Property("uri", ..) => {
+                let uri: String = value.get().unwrap_or_default();
+                match ndi_uri_to_source(&uri) {
+                    Ok((stream_name, ip)) => {
+                        let mut settings =
+                            self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                        gst_debug!(
+                            self.cat,
+                            obj: basesrc,
+                            "Changing uri to {} (stream-name '{}', ip '{}')",
+                            uri,
+                            stream_name,
+                            ip
+                        );
+                        settings.stream_name = stream_name;
+                        settings.ip = ip;
+                    }
+                    Err(err) => gst_warning!(self.cat, obj: basesrc, "{}", err),
+                }
+            }
+            subclass::Property("select-policy", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                if let Some(select_policy) = select_policy_from_value(value) {
+                    gst_debug!(
+                        self.cat,
+                        obj: basesrc,
+                        "Changing select-policy from {} to {}",
+                        settings.select_policy,
+                        select_policy
+                    );
+                    settings.select_policy = select_policy.to_string();
+                }
+                drop(settings);
+            }
+            subclass::Property("queue-depth", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let queue_depth = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing queue-depth from {} to {}",
+                    settings.queue_depth,
+                    queue_depth
+                );
+                settings.queue_depth = queue_depth;
+                drop(settings);
+            }
+            subclass::Property("prefer-speed", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let prefer_speed = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing prefer-speed from {} to {}",
+                    settings.prefer_speed,
+                    prefer_speed
+                );
+                settings.prefer_speed = prefer_speed;
+                drop(settings);
+            }
+            subclass::Property("attach-timecode-meta", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let attach_timecode_meta = value.get().unwrap();
+                if attach_timecode_meta && !cfg!(feature = "v1_14") {
+                    gst_warning!(
+                        self.cat,
+                        obj: basesrc,
+                        "Timecode meta needs GStreamer 1.14, build with the v1_14 feature"
+                    );
+                }
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing attach-timecode-meta from {} to {}",
+                    settings.attach_timecode_meta,
+                    attach_timecode_meta
+                );
+                settings.attach_timecode_meta = attach_timecode_meta;
+                drop(settings);
+            }
+            subclass::Property("failover-source", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let failover_source = value.get().unwrap_or_default();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing failover-source from {} to {}",
+                    settings.failover_source,
+                    failover_source
+                );
+                settings.failover_source = failover_source;
+                drop(settings);
+            }
+            subclass::Property("max-width", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let max_width = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing max-width from {} to {}",
+                    settings.max_width,
+                    max_width
+                );
+                settings.max_width = max_width;
+                drop(settings);
+            }
+            subclass::Property("max-height", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let max_height = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing max-height from {} to {}",
+                    settings.max_height,
+                    max_height
+                );
+                settings.max_height = max_height;
+                drop(settings);
+            }
+            subclass::Property("bind-address", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let bind_address = value.get().unwrap_or_default();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing bind-address from {} to {}",
+                    settings.bind_address,
+                    bind_address
+                );
+                settings.bind_address = bind_address;
+                drop(settings);
+            }
+            subclass::Property("use-frame-timestamps", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let use_frame_timestamps = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing use-frame-timestamps from {} to {}",
+                    settings.use_frame_timestamps,
+                    use_frame_timestamps
+                );
+                settings.use_frame_timestamps = use_frame_timestamps;
+                drop(settings);
+                // BaseSrc only timestamps buffers that come without a PTS
+                basesrc.set_do_timestamp(!use_frame_timestamps);
+            }
+            subclass::Property("warmup-frames", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let warmup_frames = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing warmup-frames from {} to {}",
+                    settings.warmup_frames,
+                    warmup_frames
+                );
+                settings.warmup_frames = warmup_frames;
+                drop(settings);
+            }
+            subclass::Property("poll-interval", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let poll_interval = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing poll-interval from {} to {}",
+                    settings.poll_interval,
+                    poll_interval
+                );
+                settings.poll_interval = poll_interval;
+                drop(settings);
+            }
+            subclass::Property("capture-timeout-ms", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let capture_timeout = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing capture-timeout-ms from {} to {}",
+                    settings.capture_timeout,
+                    capture_timeout
+                );
+                settings.capture_timeout = capture_timeout;
+                drop(settings);
+            }
+            subclass::Property("config-path", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let config_path = value.get().unwrap_or_default();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing config-path from {} to {}",
+                    settings.config_path,
+                    config_path
+                );
+                settings.config_path = config_path;
+                drop(settings);
+            }
+            subclass::Property("source-index", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let source_index = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing source-index from {} to {}",
+                    settings.source_index,
+                    source_index
+                );
+                settings.source_index = source_index;
+                drop(settings);
+            }
+            subclass::Property("groups", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let groups = value.get().unwrap_or_default();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing groups from {} to {}",
+                    settings.groups,
+                    groups
+                );
+                settings.groups = groups;
+                drop(settings);
+            }
+            subclass::Property("target-framerate", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let target_framerate = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing target framerate from {} to {}",
+                    settings.target_framerate,
+                    target_framerate
+                );
+                settings.target_framerate = target_framerate;
+                drop(settings);
+            }
+            subclass::Property("dump-first-frame-header", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let dump_first_frame_header = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing dump-first-frame-header from {} to {}",
+                    settings.dump_first_frame_header,
+                    dump_first_frame_header
+                );
+                settings.dump_first_frame_header = dump_first_frame_header;
+                drop(settings);
+            }
+            subclass::Property("timestamp-mode", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                if let Some(timestamp_mode) = timestamp_mode_from_value(value) {
+                    gst_debug!(
+                        self.cat,
+                        obj: basesrc,
+                        "Changing timestamp-mode from {} to {}",
+                        settings.timestamp_mode,
+                        timestamp_mode
+                    );
+                    settings.timestamp_mode = timestamp_mode.to_string();
+                }
+                drop(settings);
+            }
+            subclass::Property("discovery-timeout", ..) => {
+                let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let discovery_timeout = value.get().unwrap();
+                gst_debug!(
+                    self.cat,
+                    obj: basesrc,
+                    "Changing discovery-timeout from {} to {}",
+                    settings.discovery_timeout,
+                    discovery_timeout
+                );
+                settings.discovery_timeout = discovery_timeout;
+                drop(settings);
+            }
+            _ => unimplemented!(),
+        }
+    }
 
-            if receiver.initial_timestamp <= video_frame.timestamp as u64
-                || receiver.initial_timestamp == 0
-            {
-                receiver.initial_timestamp = video_frame.timestamp as u64;
+    fn get_property(&self, obj: &glib::Object, id: usize) -> Result<glib::Value, ()> {
+        let prop = &PROPERTIES[id];
+
+        match *prop {
+            subclass::Property("stream-name", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.stream_name.to_value())
             }
-            unsafe {
-                NDIlib_recv_free_video_v2(pNDI_recv, &video_frame);
+            subclass::Property("ip", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.ip.to_value())
             }
-            gst_debug!(
-                self.cat,
-                obj: element,
-                "Setting initial timestamp to {}",
-                receiver.initial_timestamp
-            );
+            subclass::Property("loss-threshold", ..) => {
+                Ok(self.loss_threshold.load(Ordering::SeqCst).to_value())
+            }
+            subclass::Property("eos-on-disconnect", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.eos_on_disconnect.to_value())
+            }
+            subclass::Property("max-latency", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.max_latency.to_value())
+            }
+            subclass::Property("available-sources", ..) => Ok(available_sources().to_value()),
+            subclass::Property("receiver-name", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.receiver_name.to_value())
+            }
+            subclass::Property("request-width", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.request_width.to_value())
+            }
+            subclass::Property("request-height", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.request_height.to_value())
+            }
+            subclass::Property("request-framerate", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.request_framerate.to_value())
+            }
+            subclass::Property("allow-video-fields", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.allow_video_fields.to_value())
+            }
+            subclass::Property("timeout", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.timeout.to_value())
+            }
+            subclass::Property("uri", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(source_to_ndi_uri(&settings.stream_name, &settings.ip).to_value())
+            }
+            subclass::Property("select-policy", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(select_policy_to_value(&settings.select_policy))
+            }
+            subclass::Property("web-control-url", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(web_control_url(settings.id_receiver).to_value())
+            }
+            subclass::Property("queue-depth", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.queue_depth.to_value())
+            }
+            subclass::Property("prefer-speed", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.prefer_speed.to_value())
+            }
+            subclass::Property("attach-timecode-meta", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.attach_timecode_meta.to_value())
+            }
+            subclass::Property("failover-source", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.failover_source.to_value())
+            }
+            subclass::Property("max-width", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.max_width.to_value())
+            }
+            subclass::Property("max-height", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.max_height.to_value())
+            }
+            subclass::Property("bind-address", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.bind_address.to_value())
+            }
+            subclass::Property("use-frame-timestamps", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.use_frame_timestamps.to_value())
+            }
+            subclass::Property("warmup-frames", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.warmup_frames.to_value())
+            }
+            subclass::Property("poll-interval", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.poll_interval.to_value())
+            }
+            subclass::Property("ptz-is-supported", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let supported = with_receiver(settings.id_receiver, |recv| unsafe {
+                    NDIlib_recv_ptz_is_supported(recv)
+                });
+                Ok(supported.to_value())
+            }
+            subclass::Property("recording-is-supported", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let supported = with_receiver(settings.id_receiver, |recv| unsafe {
+                    NDIlib_recv_recording_is_supported(recv)
+                });
+                Ok(supported.to_value())
+            }
+            subclass::Property("connection-info", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut info = connection_info(settings.id_receiver);
+                drop(settings);
+                let caps = obj
+                    .downcast_ref::<gst_base::BaseSrc>()
+                    .and_then(|basesrc| basesrc.get_static_pad("src"))
+                    .and_then(|pad| pad.get_current_caps());
+                if let Some(caps) = caps {
+                    info.set("caps", &caps);
+                }
+                Ok(info.to_value())
+            }
+            subclass::Property("capture-timeout-ms", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.capture_timeout.to_value())
+            }
+            subclass::Property("config-path", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.config_path.to_value())
+            }
+            subclass::Property("clock-drift", ..) => {
+                Ok(self.clock_drift.load(Ordering::SeqCst).to_value())
+            }
+            subclass::Property("source-index", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.source_index.to_value())
+            }
+            subclass::Property("groups", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.groups.to_value())
+            }
+            subclass::Property("target-framerate", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.target_framerate.to_value())
+            }
+            subclass::Property("dump-first-frame-header", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.dump_first_frame_header.to_value())
+            }
+            subclass::Property("timestamp-mode", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(timestamp_mode_to_value(&settings.timestamp_mode))
+            }
+            subclass::Property("discovery-timeout", ..) => {
+                let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                Ok(settings.discovery_timeout.to_value())
+            }
+            _ => unimplemented!(),
         }
-        self.parent_change_state(element, transition)
+    }
+}
+
+impl ElementImpl for NdiVideoSrc {}
+
+impl URIHandlerImpl for NdiVideoSrc {
+    fn get_uri(&self, _element: &gst::URIHandler) -> Option<String> {
+        let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        Some(source_to_ndi_uri(&settings.stream_name, &settings.ip))
+    }
+
+    fn set_uri(&self, _element: &gst::URIHandler, uri: Option<String>) -> Result<(), glib::Error> {
+        let uri = uri.unwrap_or_default();
+        let (stream_name, ip) =
+            ndi_uri_to_source(&uri).map_err(|err| glib::Error::new(gst::URIError::BadUri, &err))?;
+
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        gst_debug!(
+            self.cat,
+            "Setting uri to {} (stream-name '{}', ip '{}')",
+            uri,
+            stream_name,
+            ip
+        );
+        settings.stream_name = stream_name;
+        settings.ip = ip;
+
+        Ok(())
+    }
+
+    fn get_uri_type() -> gst::URIType {
+        gst::URIType::Src
+    }
+
+    fn get_protocols() -> Vec<String> {
+        vec!["ndi".to_string()]
     }
 }
 
@@ -319,57 +1678,156 @@ impl BaseSrcImpl for NdiVideoSrc {
         };
         gst_debug!(self.cat, obj: element, "Configuring for caps {}", caps);
 
-        let mut state = self.state.lock().unwrap();
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        let (source_name, source_ip) = receiver_source(settings.id_receiver).unwrap_or_default();
+        // Also follows a source that changed its frame rate mid-stream
+        if *info.fps().numer() > 0 {
+            settings.latency =
+                gst::SECOND.mul_div_floor(*info.fps().denom() as u64, *info.fps().numer() as u64);
+        }
+        drop(settings);
+        gst_info!(
+            self.cat,
+            obj: element,
+            "Negotiated NDI source '{}' at '{}': {} {}x{} at {} fps",
+            source_name,
+            source_ip,
+            // The format's Display recurses, its name doesn't
+            info.format().to_string(),
+            info.width(),
+            info.height(),
+            info.fps()
+        );
+
+        let pool = gst::BufferPool::new();
+        let mut config = pool.get_config();
+        config.set_params(Some(&caps.to_owned()), info.size() as u32, 0, 0);
+        let pool = match pool.set_config(config).and_then(|_| pool.set_active(true)) {
+            Ok(_) => Some(pool),
+            Err(err) => {
+                gst_warning!(self.cat, obj: element, "Can't set up buffer pool: {}", err);
+                None
+            }
+        };
+
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
         state.info = Some(info);
+        state.caps_checked = false;
+        state.pool = pool;
         let _ = element.post_message(&gst::Message::new_latency().src(Some(element)).build());
         Ok(())
     }
 
     fn start(&self, element: &gst_base::BaseSrc) -> Result<(), gst::ErrorMessage> {
-        *self.state.lock().unwrap() = Default::default();
-        let mut settings = self.settings.lock().unwrap();
-        settings.id_receiver = connect_ndi(
-            self.cat,
-            element,
-            &settings.ip.clone(),
-            &settings.stream_name.clone(),
-        );
-
-        // settings.id_receiver != 0
-        match settings.id_receiver {
-            0 => Err(gst_error_msg!(
+        *self.state.lock().unwrap_or_else(PoisonError::into_inner) = Default::default();
+        *self
+            .timestamp_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Default::default();
+        self.clock_drift.store(0, Ordering::SeqCst);
+        *self.qos.lock().unwrap_or_else(PoisonError::into_inner) = Default::default();
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        if settings.stream_name.is_empty() && settings.ip.is_empty() {
+            return Err(gst_error_msg!(
+                gst::ResourceError::Settings,
+                ["Neither stream-name nor ip set; nothing to connect to"]
+            ));
+        }
+        if self.connect(element, &mut settings) {
+            Ok(())
+        } else {
+            Err(gst_error_msg!(
                 gst::ResourceError::NotFound,
                 ["Could not connect to this source"]
-            )),
-            _ => Ok(()),
+            ))
         }
     }
 
     fn stop(&self, element: &gst_base::BaseSrc) -> Result<(), gst::ErrorMessage> {
-        *self.state.lock().unwrap() = Default::default();
+        *self.state.lock().unwrap_or_else(PoisonError::into_inner) = Default::default();
 
-        let settings = self.settings.lock().unwrap();
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        *self
+            .framesync
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
         stop_ndi(self.cat, element, settings.id_receiver);
+        settings.id_receiver = 0;
+        *self
+            .source_info
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        *self
+            .tags_product
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
+        self.pending_metadata
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        *self
+            .test_sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
         // Commented because when adding ndi destroy stopped in this line
-        //*self.state.lock().unwrap() = Default::default();
+        //*self.state.lock().unwrap_or_else(PoisonError::into_inner) = Default::default();
         Ok(())
     }
 
     fn query(&self, element: &gst_base::BaseSrc, query: &mut gst::QueryRef) -> bool {
         use gst::QueryView;
+        if let QueryView::Context(ref mut q) = query.view_mut() {
+            if q.get_context_type() == SOURCE_INFO_CONTEXT {
+                if let Some(ref context) = *self
+                    .source_info
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                {
+                    q.set_context(context);
+                    return true;
+                }
+            }
+        }
         if let QueryView::Scheduling(ref mut q) = query.view_mut() {
             q.set(gst::SchedulingFlags::SEQUENTIAL, 1, -1, 0);
             q.add_scheduling_modes(&[gst::PadMode::Push]);
             return true;
         }
+        if let QueryView::Seeking(ref mut q) = query.view_mut() {
+            let format = q.get_format();
+            q.set(
+                false,
+                gst::GenericFormattedValue::new(format, -1),
+                gst::GenericFormattedValue::new(format, -1),
+            );
+            return true;
+        }
         if let QueryView::Latency(ref mut q) = query.view_mut() {
-            let settings = &*self.settings.lock().unwrap();
-            let state = self.state.lock().unwrap();
+            let settings = &*self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+            let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
 
-            if let Some(ref _info) = state.info {
-                let latency = settings.latency.unwrap();
-                gst_debug!(self.cat, obj: element, "Returning latency {}", latency);
-                q.set(true, latency, gst::CLOCK_TIME_NONE);
+            // The frame latency is only known once fixate() saw a frame
+            if let (&Some(_), Some(frame_latency)) = (&state.info, settings.latency) {
+                // Each queued frame adds one frame duration of latency
+                let latency = frame_latency * (u64::from(settings.queue_depth) + 1);
+                let (max_latency, clamped) = max_latency(latency, settings.max_latency);
+                if clamped {
+                    gst_warning!(
+                        self.cat,
+                        obj: element,
+                        "Max latency {} is lower than latency {}, clamping",
+                        gst::ClockTime::from(settings.max_latency),
+                        latency
+                    );
+                }
+                gst_debug!(
+                    self.cat,
+                    obj: element,
+                    "Returning latency {} (max {})",
+                    latency,
+                    max_latency
+                );
+                q.set(true, latency, max_latency);
                 return true;
             } else {
                 return false;
@@ -378,20 +1836,139 @@ impl BaseSrcImpl for NdiVideoSrc {
         BaseSrcImplExt::parent_query(self, element, query)
     }
 
+    fn unlock(&self, element: &gst_base::BaseSrc) -> Result<(), gst::ErrorMessage> {
+        gst_debug!(self.cat, obj: element, "Unlocking");
+        self.unlocked.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn unlock_stop(&self, element: &gst_base::BaseSrc) -> Result<(), gst::ErrorMessage> {
+        gst_debug!(self.cat, obj: element, "Stop unlocking");
+        self.unlocked.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn is_seekable(&self, _element: &gst_base::BaseSrc) -> bool {
+        false
+    }
+
+    fn event(&self, element: &gst_base::BaseSrc, event: &gst::Event) -> bool {
+        use gst::EventView;
+        match event.view() {
+            EventView::Seek(_) => {
+                gst_debug!(self.cat, obj: element, "Refusing seek on live NDI source");
+                return false;
+            }
+            // A live source picks up wherever NDI is after a flush: the
+            // offsets start over and the next buffer is a discontinuity
+            EventView::FlushStop(_) => {
+                gst_debug!(self.cat, obj: element, "Flushed, resetting timestamps");
+                let mut timestamp_data = self
+                    .timestamp_data
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                // The warm-up is about the first frames of the connection,
+                // which a flush doesn't start over
+                *timestamp_data = TimestampData {
+                    discont: true,
+                    warmup_dropped: timestamp_data.warmup_dropped,
+                    ..Default::default()
+                };
+                *self.qos.lock().unwrap_or_else(PoisonError::into_inner) = Default::default();
+            }
+            // Late frames are skipped by twice as much as they're late, so
+            // downstream can catch up
+            EventView::Qos(ref ev) => {
+                let (_, proportion, diff, timestamp) = ev.get();
+                let earliest = timestamp.nseconds().map(|timestamp| {
+                    if diff > 0 {
+                        timestamp + 2 * diff as u64
+                    } else {
+                        timestamp.saturating_sub(-diff as u64)
+                    }
+                });
+                gst_debug!(
+                    self.cat,
+                    obj: element,
+                    "QoS with proportion {} and diff {}, dropping frames before {}",
+                    proportion,
+                    diff,
+                    gst::ClockTime::from(earliest)
+                );
+                let mut qos = self.qos.lock().unwrap_or_else(PoisonError::into_inner);
+                qos.earliest = earliest.into();
+                qos.proportion = proportion;
+            }
+            _ => (),
+        }
+        BaseSrcImplExt::parent_event(self, element, event)
+    }
+
     fn fixate(&self, element: &gst_base::BaseSrc, caps: gst::Caps) -> gst::Caps {
-        let receivers = hashmap_receivers.lock().unwrap();
-        let mut settings = self.settings.lock().unwrap();
+        let mut settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
 
-        let receiver = receivers.get(&settings.id_receiver).unwrap();
-        let recv = &receiver.ndi_instance;
-        let pNDI_recv = recv.recv;
+        let ndi_instance = match hashmap_receivers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&settings.id_receiver)
+        {
+            Some(receiver) => receiver.ndi_instance.clone(),
+            None => {
+                // Caps can be negotiated before start(), fall back to the
+                // template values then
+                gst_debug!(
+                    self.cat,
+                    obj: element,
+                    "No NDI receiver yet, fixating caps without the source format"
+                );
+                drop(settings);
+                return self.parent_fixate(element, caps);
+            }
+        };
+        let recv = ndi_instance.read().unwrap_or_else(PoisonError::into_inner);
 
-        let video_frame: NDIlib_video_frame_v2_t = Default::default();
+        let mut video_frame: NDIlib_video_frame_v2_t = Default::default();
 
-        unsafe {
-            while NDIlib_recv_capture_v2(pNDI_recv, &video_frame, ptr::null(), ptr::null(), 1000)
-                != NDIlib_frame_type_e::NDIlib_frame_type_video
-            {}
+        // Given up on like capturing, so neither stopping nor a source that
+        // sends nothing keeps the negotiation waiting with the locks held
+        let mut count_frame_none = 0;
+        loop {
+            match recv.capture_video(&mut video_frame, settings.capture_timeout) {
+                NDIlib_frame_type_e::NDIlib_frame_type_video => break,
+                NDIlib_frame_type_e::NDIlib_frame_type_none
+                | NDIlib_frame_type_e::NDIlib_frame_type_error => count_frame_none += 1,
+                _ => (),
+            }
+            if self.unlocked.load(Ordering::SeqCst) {
+                gst_debug!(
+                    self.cat,
+                    obj: element,
+                    "Unlocked while waiting for a frame, fixating caps without the source format"
+                );
+                drop(settings);
+                return self.parent_fixate(element, caps);
+            }
+            if source_lost(count_frame_none, self.loss_threshold.load(Ordering::SeqCst)) {
+                gst_element_error!(
+                    element,
+                    gst::CoreError::Negotiation,
+                    ["No NDI video frame received to negotiate the format with"]
+                );
+                return gst::Caps::new_empty();
+            }
+        }
+        if (settings.max_width != 0 && video_frame.xres as u32 > settings.max_width)
+            || (settings.max_height != 0 && video_frame.yres as u32 > settings.max_height)
+        {
+            gst_warning!(
+                self.cat,
+                obj: element,
+                "NDI source ignored the {}x{} resolution cap and sends {}x{}",
+                settings.max_width,
+                settings.max_height,
+                video_frame.xres,
+                video_frame.yres
+            );
         }
         settings.latency = gst::SECOND.mul_div_floor(
             video_frame.frame_rate_D as u64,
@@ -399,34 +1976,323 @@ impl BaseSrcImpl for NdiVideoSrc {
         );
 
         let mut caps = gst::Caps::truncate(caps);
+        // NDI's 16-bit formats go out as v216 if downstream has it, then as
+        // 10-bit v210 or P010, and are reduced to UYVY otherwise
+        let format = match video_frame.fourcc() {
+            Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_P216)
+            | Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_PA16) => [
+                gst_video::VideoFormat::V216,
+                gst_video::VideoFormat::V210,
+                gst_video::VideoFormat::P01010le,
+            ]
+            .iter()
+            .cloned()
+            .find(|&format| {
+                caps.can_intersect(&gst::Caps::new_simple(
+                    "video/x-raw",
+                    &[("format", &format.to_string())],
+                ))
+            })
+            .unwrap_or(gst_video::VideoFormat::Uyvy),
+            _ => gst_video::VideoFormat::Uyvy,
+        };
         {
             let caps = caps.make_mut();
             let s = caps.get_mut_structure(0).unwrap();
+            s.fixate_field_str("format", format.to_string());
             s.fixate_field_nearest_int("width", video_frame.xres);
             s.fixate_field_nearest_int("height", video_frame.yres);
             s.fixate_field_nearest_fraction(
                 "framerate",
-                Fraction::new(video_frame.frame_rate_N, video_frame.frame_rate_D),
+                if *settings.target_framerate.numer() != 0 {
+                    settings.target_framerate
+                } else {
+                    Fraction::new(video_frame.frame_rate_N, video_frame.frame_rate_D)
+                },
+            );
+            if video_frame.frame_format_type
+                == NDIlib_frame_format_type_e::NDIlib_frame_format_type_field_0
+                || video_frame.frame_format_type
+                    == NDIlib_frame_format_type_e::NDIlib_frame_format_type_field_1
+            {
+                s.set("interlace-mode", &"fields");
+            }
+            let par = Fraction::from(pixel_aspect_ratio(&video_frame));
+            gst_debug!(self.cat, obj: element, "Source pixel aspect ratio {}", par);
+            s.set("pixel-aspect-ratio", &par);
+            let colorimetry = unsafe { frame_colorimetry(&video_frame) };
+            gst_debug!(
+                self.cat,
+                obj: element,
+                "Source colorimetry {}",
+                colorimetry
+            );
+            s.set("colorimetry", &colorimetry);
+            s.set("chroma-site", &"mpeg2");
+        }
+        recv.free_video(&video_frame);
+        let _ = element.post_message(&gst::Message::new_latency().src(Some(element)).build());
+        self.parent_fixate(element, caps)
+    }
+
+    //Creates the video buffers
+    fn create(
+        &self,
+        element: &gst_base::BaseSrc,
+        _offset: u64,
+        _length: u32,
+    ) -> Result<gst::Buffer, gst::FlowError> {
+        self.capture_or_reconnect(element)
+    }
+}
+
+impl NdiVideoSrc {
+    // capture_buffer() gives up with CustomError after loss-threshold captures
+    // without a frame or when the source changed its address. The receiver is
+    // recreated then and capturing goes on. When the source changed its format
+    // it returns CustomError1 with pending_caps set, which are negotiated before
+    // capturing the next frame, and CustomError2 after dropping a frame for QoS.
+    fn capture_or_reconnect(
+        &self,
+        element: &gst_base::BaseSrc,
+    ) -> Result<gst::Buffer, gst::FlowError> {
+        loop {
+            let clocked = *self
+                .settings
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .target_framerate
+                .numer()
+                != 0;
+            let res = if clocked {
+                self.capture_clocked(element)
+            } else {
+                self.capture_buffer(element)
+            };
+            emit_metadata(element, &self.pending_metadata);
+            match res {
+                Err(gst::FlowError::CustomError) => {
+                    let id = self
+                        .settings
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .id_receiver;
+                    // NDI keeps trying to get the source back on its own
+                    if receiver_shared(id) {
+                        gst_warning!(
+                            self.cat,
+                            obj: element,
+                            "Lost the NDI source, waiting for the shared receiver to get it back"
+                        );
+                        continue;
+                    }
+                    gst_warning!(self.cat, obj: element, "Lost the NDI source, reconnecting");
+                    if !self.reconnect(element) {
+                        return Err(gst::FlowError::Error);
+                    }
+                }
+                // Dropped for QoS, on to the next frame
+                Err(gst::FlowError::CustomError2) => (),
+                Err(gst::FlowError::CustomError1) => {
+                    let caps = self
+                        .state
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .pending_caps
+                        .take();
+                    let renegotiated = match caps {
+                        Some(caps) => renegotiate(self.cat, element, &caps),
+                        None => false,
+                    };
+                    if !renegotiated {
+                        return Err(gst::FlowError::NotNegotiated);
+                    }
+                }
+                Ok(buffer) => {
+                    let id = self
+                        .settings
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .id_receiver;
+                    update_receiver_tags(element, id, &self.tags_product);
+                    return Ok(buffer);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    // With a target-framerate, frames are taken from an NDI frame-sync whenever
+    // the next one is due on the pipeline clock. The frame-sync repeats the
+    // last frame or drops frames to keep up with the target rate.
+    fn capture_clocked(&self, element: &gst_base::BaseSrc) -> Result<gst::Buffer, gst::FlowError> {
+        let _settings = &*self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut timestamp_data = self
+            .timestamp_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let info = match state.info {
+            None => {
+                gst_element_error!(element, gst::CoreError::Negotiation, ["Have no caps yet"]);
+                return Err(gst::FlowError::NotNegotiated);
+            }
+            Some(ref info) => info.clone(),
+        };
+        let (clock, base_time) = match element.get_clock() {
+            Some(clock) => (clock, element.get_base_time()),
+            None => {
+                gst_element_error!(element, gst::CoreError::Clock, ["Have no clock"]);
+                return Err(gst::FlowError::Error);
+            }
+        };
+
+        let mut framesync = self
+            .framesync
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if framesync.is_none() {
+            *framesync = with_receiver(_settings.id_receiver, |recv| unsafe {
+                FrameSync::new(recv)
+            });
+        }
+        let framesync = match *framesync {
+            Some(ref framesync) => framesync,
+            None => {
+                gst_element_error!(
+                    element,
+                    gst::CoreError::Failed,
+                    ["Can't create an NDI frame-sync"]
+                );
+                return Err(gst::FlowError::Error);
+            }
+        };
+
+        let target = _settings.target_framerate;
+        let frame_time = |frame: u64| {
+            frame
+                .mul_div_floor(
+                    gst::SECOND_VAL * *target.denom() as u64,
+                    *target.numer() as u64,
+                )
+                .unwrap_or(0)
+        };
+        let running_time = || (clock.get_time() - base_time).nseconds().unwrap_or(0);
+
+        let mut video_frame: NDIlib_video_frame_v2_t = Default::default();
+        let pts = loop {
+            let start = match timestamp_data.clocked_start {
+                Some(start) => start,
+                None => running_time(),
+            };
+            timestamp_data.clocked_start = Some(start);
+            let due = start + frame_time(timestamp_data.offset);
+            loop {
+                if self.unlocked.load(Ordering::SeqCst) {
+                    gst_debug!(self.cat, obj: element, "Unlocked while waiting for a frame");
+                    return Err(gst::FlowError::Flushing);
+                }
+                let now = running_time();
+                if now >= due {
+                    break;
+                }
+                thread::sleep(cmp::min(
+                    Duration::from_nanos(due - now),
+                    Duration::from_millis(10),
+                ));
+            }
+
+            framesync.capture_video(&mut video_frame);
+            if !video_frame.p_data.is_null() {
+                break due;
+            }
+            // Nothing received yet, the first frame is due one frame later
+            framesync.free_video(&mut video_frame);
+            timestamp_data.clocked_start = Some(due + frame_time(1));
+        };
+
+        if video_frame.fourcc().is_none() {
+            let fourcc = video_frame.FourCC;
+            framesync.free_video(&mut video_frame);
+            gst_element_error!(
+                element,
+                gst::StreamError::Format,
+                ["Unsupported NDI FourCC {:#010x}", fourcc]
             );
+            return Err(gst::FlowError::NotNegotiated);
+        }
+        if !state.caps_checked {
+            if let Err(mismatch) = check_frame_caps(&info, &video_frame) {
+                framesync.free_video(&mut video_frame);
+                gst_element_error!(
+                    element,
+                    gst::CoreError::Negotiation,
+                    ["Negotiated caps don't match the NDI source: {}", mismatch]
+                );
+                return Err(gst::FlowError::NotNegotiated);
+            }
+            state.caps_checked = true;
+        } else if check_frame_caps(&info, &video_frame).is_err() {
+            state.pending_caps =
+                unsafe { new_frame_caps(element, &video_frame) }.map(|mut caps| {
+                    if let Some(s) = caps.get_mut().and_then(|caps| caps.get_mut_structure(0)) {
+                        s.set("framerate", &target);
+                    }
+                    caps
+                });
+            framesync.free_video(&mut video_frame);
+            return Err(gst::FlowError::CustomError1);
         }
-        unsafe {
-            NDIlib_recv_free_video_v2(pNDI_recv, &video_frame);
+
+        let res = unsafe { copy_frame(element, &info, state.pool.as_ref(), &video_frame) };
+        framesync.free_video(&mut video_frame);
+        let mut buffer = match res {
+            Ok(buffer) => buffer,
+            Err(err) => {
+                gst_element_error!(element, gst::ResourceError::Failed, ["{}", err]);
+                return Err(gst::FlowError::Error);
+            }
+        };
+        {
+            let buffer = buffer.get_mut().unwrap();
+            let offset = timestamp_data.offset;
+            buffer.set_pts(gst::ClockTime::from(pts));
+            buffer.set_duration(gst::ClockTime::from(frame_time(1)));
+            if timestamp_data.discont {
+                buffer.set_flags(gst::BufferFlags::DISCONT);
+                timestamp_data.discont = false;
+            }
+            buffer.set_offset(offset);
+            buffer.set_offset_end(offset + 1);
         }
-        let _ = element.post_message(&gst::Message::new_latency().src(Some(element)).build());
-        self.parent_fixate(element, caps)
+        timestamp_data.offset += 1;
+
+        gst_log!(self.cat, obj: element, "Produced buffer {:?}", buffer);
+        Ok(buffer)
     }
 
-    //Creates the video buffers
-    fn create(
-        &self,
-        element: &gst_base::BaseSrc,
-        _offset: u64,
-        _length: u32,
-    ) -> Result<gst::Buffer, gst::FlowError> {
-        let _settings = &*self.settings.lock().unwrap();
+    fn capture_buffer(&self, element: &gst_base::BaseSrc) -> Result<gst::Buffer, gst::FlowError> {
+        let (id, queue_depth) = {
+            let settings = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+            (settings.id_receiver, settings.queue_depth)
+        };
+        let first = self
+            .timestamp_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .offset
+            == 0;
+        if queue_depth != 0 && first {
+            wait_for_queue(self.cat, element, id, queue_depth, true, &self.unlocked)?;
+        }
+
+        let _settings = &*self.settings.lock().unwrap_or_else(PoisonError::into_inner);
 
-        let mut timestamp_data = self.timestamp_data.lock().unwrap();
-        let state = self.state.lock().unwrap();
+        let mut timestamp_data = self
+            .timestamp_data
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
         let _info = match state.info {
             None => {
                 gst_element_error!(element, gst::CoreError::Negotiation, ["Have no caps yet"]);
@@ -434,50 +2300,184 @@ impl BaseSrcImpl for NdiVideoSrc {
             }
             Some(ref info) => info.clone(),
         };
-        let receivers = hashmap_receivers.lock().unwrap();
+        let receivers = hashmap_receivers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        let receiver = match receivers.get(&_settings.id_receiver) {
+            Some(receiver) => receiver,
+            None => {
+                gst_element_error!(
+                    element,
+                    gst::CoreError::Failed,
+                    ["Not connected to an NDI source"]
+                );
+                return Err(gst::FlowError::Error);
+            }
+        };
+        let ndi_instance = receiver.ndi_instance.clone();
+        let initial_timestamp = if _settings.timestamp_mode == "timecode" {
+            receiver.initial_timecode.clone()
+        } else {
+            receiver.initial_timestamp.clone()
+        };
+        let source_ip = receiver.ip.clone();
+        let source_name = receiver.stream_name.clone();
+        let product = receiver.product.clone();
+        drop(receivers);
 
-        let recv = &receivers.get(&_settings.id_receiver).unwrap().ndi_instance;
-        let pNDI_recv = recv.recv;
+        // Hold the receiver read lock for the whole capture so stop_ndi() can't
+        // destroy it under us
+        let recv = ndi_instance.read().unwrap_or_else(PoisonError::into_inner);
 
         let pts: u64;
-        let video_frame: NDIlib_video_frame_v2_t = Default::default();
+        let mut video_frame: NDIlib_video_frame_v2_t = Default::default();
 
         unsafe {
-            let time = receivers
-                .get(&_settings.id_receiver)
-                .unwrap()
-                .initial_timestamp;
-
+            let mut time = initial_timestamp.load(Ordering::SeqCst);
             let mut skip_frame = true;
             let mut count_frame_none = 0;
+            // A lost frame is a capture-timeout without one, made up of
+            // several captures with a poll-interval
+            let poll_in_ms = if _settings.poll_interval != 0 {
+                cmp::min(_settings.poll_interval, _settings.capture_timeout)
+            } else {
+                _settings.capture_timeout
+            };
+            let mut waited_ms = 0;
             while skip_frame {
-                let frame_type =
-                    NDIlib_recv_capture_v2(pNDI_recv, &video_frame, ptr::null(), ptr::null(), 1000);
-                if (frame_type == NDIlib_frame_type_e::NDIlib_frame_type_none
-                    && _settings.loss_threshold != 0)
+                let frame_type = capture_frame(
+                    self.cat,
+                    element,
+                    &**recv,
+                    &product,
+                    &self.pending_metadata,
+                    Some(&mut video_frame),
+                    None,
+                    poll_in_ms,
+                );
+                if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_video {
+                    timestamp_data.last_frame = Instant::now();
+                } else if _settings.timeout != 0
+                    && timestamp_data.last_frame.elapsed()
+                        >= Duration::from_nanos(_settings.timeout)
+                {
+                    gst_element_error!(
+                        element,
+                        gst::ResourceError::Read,
+                        [
+                            "No NDI video frame received in {} ms",
+                            _settings.timeout / 1_000_000
+                        ]
+                    );
+                    return Err(gst::FlowError::Error);
+                }
+                // The source moved, e.g. after a DHCP renewal. Reconnecting
+                // looks it up again and connects to its new address.
+                if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_source_change {
+                    gst_info!(
+                        self.cat,
+                        obj: element,
+                        "NDI source '{}' at '{}' changed its address",
+                        source_name,
+                        source_ip
+                    );
+                    return Err(gst::FlowError::CustomError);
+                }
+                if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_none
                     || frame_type == NDIlib_frame_type_e::NDIlib_frame_type_error
                 {
-                    if count_frame_none < _settings.loss_threshold {
-                        count_frame_none += 1;
+                    if self.unlocked.load(Ordering::SeqCst) {
+                        gst_debug!(self.cat, obj: element, "Unlocked while waiting for a frame");
+                        return Err(gst::FlowError::Flushing);
+                    }
+                    waited_ms += poll_in_ms;
+                    if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_none
+                        && waited_ms < _settings.capture_timeout
+                    {
                         continue;
                     }
-                    gst_element_error!(element, gst::ResourceError::Read, ["NDI frame type none or error received, assuming that the source closed the stream...."]);
+                    waited_ms = 0;
+                    count_frame_none += 1;
+                    let loss_threshold = self.loss_threshold.load(Ordering::SeqCst);
+                    if !source_lost(count_frame_none, loss_threshold) {
+                        gst_debug!(
+                            self.cat,
+                            obj: element,
+                            "No NDI video frame in {} ms, retry {} of {}",
+                            _settings.capture_timeout,
+                            count_frame_none,
+                            loss_threshold
+                        );
+                        continue;
+                    }
+                    if _settings.eos_on_disconnect
+                        && !source_available(&**recv, &source_ip, &source_name)
+                    {
+                        gst_debug!(
+                            self.cat,
+                            obj: element,
+                            "NDI source is no longer available, sending EOS"
+                        );
+                        return Err(gst::FlowError::Eos);
+                    }
+                    gst_debug!(
+                        self.cat,
+                        obj: element,
+                        "No NDI video frame in {} captures",
+                        count_frame_none
+                    );
                     return Err(gst::FlowError::CustomError);
-                } else if frame_type == NDIlib_frame_type_e::NDIlib_frame_type_none
-                    && _settings.loss_threshold == 0
-                {
+                }
+
+                // Dropped before the initial timestamp is taken, so the first
+                // real buffer still starts at zero
+                if timestamp_data.warmup_dropped < _settings.warmup_frames {
+                    timestamp_data.warmup_dropped += 1;
+                    recv.free_video(&video_frame);
                     gst_debug!(
                         self.cat,
                         obj: element,
-                        "No video frame received, sending empty buffer"
+                        "Dropping warm-up frame {} of {}",
+                        timestamp_data.warmup_dropped,
+                        _settings.warmup_frames
                     );
-                    let buffer = gst::Buffer::with_size(0).unwrap();
-                    return Ok(buffer);
+                    count_frame_none = 0;
+                    waited_ms = 0;
+                    continue;
+                }
+
+                let frame_time = ndi_frame_time(
+                    &_settings.timestamp_mode,
+                    video_frame.timecode,
+                    video_frame.timestamp,
+                );
+
+                // The first frame captured by any element sharing this receiver
+                // sets the initial timestamp
+                if time == 0 {
+                    time = match initial_timestamp.compare_exchange(
+                        0,
+                        frame_time,
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    ) {
+                        Ok(_) => {
+                            gst_debug!(
+                                self.cat,
+                                obj: element,
+                                "Setting initial timestamp to {}",
+                                frame_time
+                            );
+                            frame_time
+                        }
+                        Err(time) => time,
+                    };
                 }
 
-                if time >= (video_frame.timestamp as u64) {
-                    NDIlib_recv_free_video_v2(pNDI_recv, &video_frame);
-                    gst_debug!(self.cat, obj: element, "Frame timestamp ({:?}) is lower than received in the first frame from NDI ({:?}), so skiping...", (video_frame.timestamp as u64), time);
+                if time > frame_time {
+                    recv.free_video(&video_frame);
+                    gst_debug!(self.cat, obj: element, "Frame timestamp ({:?}) is lower than received in the first frame from NDI ({:?}), so skiping...", frame_time, time);
                 } else {
                     skip_frame = false;
                 }
@@ -489,8 +2489,26 @@ impl BaseSrcImpl for NdiVideoSrc {
                 "NDI video frame received: {:?}",
                 (video_frame)
             );
+            if _settings.dump_first_frame_header && !timestamp_data.header_dumped {
+                timestamp_data.header_dumped = true;
+                dump_frame_header(self.cat, element, &video_frame);
+            }
 
-            pts = video_frame.timestamp as u64 - time;
+            if timestamp_undefined(&_settings.timestamp_mode, video_frame.timestamp)
+                && !timestamp_data.timestamp_undefined
+            {
+                timestamp_data.timestamp_undefined = true;
+                gst_warning!(
+                    self.cat,
+                    obj: element,
+                    "Source doesn't timestamp its frames, using their timecode instead"
+                );
+            }
+            pts = ndi_frame_time(
+                &_settings.timestamp_mode,
+                video_frame.timecode,
+                video_frame.timestamp,
+            ) - time;
 
             gst_log!(
                 self.cat,
@@ -499,10 +2517,90 @@ impl BaseSrcImpl for NdiVideoSrc {
                 (pts)
             );
 
-            let buff_size = (video_frame.yres * video_frame.line_stride_in_bytes) as usize;
-            let mut buffer = gst::Buffer::with_size(buff_size).unwrap();
+            // Frames downstream would throw away as too late aren't even copied
+            let (earliest, proportion) = {
+                let qos = self.qos.lock().unwrap_or_else(PoisonError::into_inner);
+                (qos.earliest, qos.proportion)
+            };
+            let running_time = timestamp_data
+                .resume
+                .apply(gst::ClockTime::from(pts * 100) + ndi_struct.start_pts);
+            if _settings.use_frame_timestamps
+                && ndi_struct.start_pts != gst::ClockTime(Some(0))
+                && earliest.is_some()
+                && running_time < earliest
+            {
+                recv.free_video(&video_frame);
+                timestamp_data.qos_dropped += 1;
+                let jitter = (earliest - running_time).nseconds().unwrap_or(0) as i64;
+                gst_debug!(
+                    self.cat,
+                    obj: element,
+                    "Dropping frame at {} for QoS, {} ns late",
+                    running_time,
+                    jitter
+                );
+                let _ = element.post_message(
+                    &gst::Message::new_qos_builder(
+                        true,
+                        running_time,
+                        running_time,
+                        running_time,
+                        gst::ClockTime::none(),
+                    )
+                    .values(jitter, proportion, 1_000_000)
+                    .stats(
+                        gst::format::Buffers(Some(timestamp_data.qos_processed)),
+                        gst::format::Buffers(Some(timestamp_data.qos_dropped)),
+                    )
+                    .src(Some(element))
+                    .build(),
+                );
+                return Err(gst::FlowError::CustomError2);
+            }
+            timestamp_data.qos_processed += 1;
+
+            // Caps forced from downstream can disagree with what the source
+            // sends, and copying the frame into them would only give garbage
+            if video_frame.fourcc().is_none() {
+                let fourcc = video_frame.FourCC;
+                recv.free_video(&video_frame);
+                gst_element_error!(
+                    element,
+                    gst::StreamError::Format,
+                    ["Unsupported NDI FourCC {:#010x}", fourcc]
+                );
+                return Err(gst::FlowError::NotNegotiated);
+            }
+            if !state.caps_checked {
+                if let Err(mismatch) = check_frame_caps(&_info, &video_frame) {
+                    recv.free_video(&video_frame);
+                    gst_element_error!(
+                        element,
+                        gst::CoreError::Negotiation,
+                        ["Negotiated caps don't match the NDI source: {}", mismatch]
+                    );
+                    return Err(gst::FlowError::NotNegotiated);
+                }
+                state.caps_checked = true;
+            } else if check_frame_caps(&_info, &video_frame).is_err()
+                || _info.fps() != Fraction::new(video_frame.frame_rate_N, video_frame.frame_rate_D)
+            {
+                state.pending_caps = new_frame_caps(element, &video_frame);
+                recv.free_video(&video_frame);
+                return Err(gst::FlowError::CustomError1);
+            }
+
+            let res = copy_frame(element, &_info, state.pool.as_ref(), &video_frame);
+            recv.free_video(&video_frame);
+            let mut buffer = match res {
+                Ok(buffer) => buffer,
+                Err(err) => {
+                    gst_element_error!(element, gst::ResourceError::Failed, ["{}", err]);
+                    return Err(gst::FlowError::Error);
+                }
+            };
             {
-                let vec = Vec::from_raw_parts(video_frame.p_data as *mut u8, buff_size, buff_size);
                 // Newtek NDI yields times in 100ns intervals since the Unix Time
                 let pts: gst::ClockTime = (pts * 100).into();
 
@@ -513,16 +2611,39 @@ impl BaseSrcImpl for NdiVideoSrc {
                 let buffer = buffer.get_mut().unwrap();
 
                 if ndi_struct.start_pts == gst::ClockTime(Some(0)) {
-                    ndi_struct.start_pts =
-                        element.get_clock().unwrap().get_time() - element.get_base_time();
+                    ndi_struct.start_pts = match element.get_clock() {
+                        Some(clock) => clock.get_time() - element.get_base_time(),
+                        None => {
+                            gst_element_error!(element, gst::CoreError::Clock, ["Have no clock"]);
+                            return Err(gst::FlowError::Error);
+                        }
+                    };
                 }
 
-                buffer.set_pts(pts + ndi_struct.start_pts);
+                if _settings.use_frame_timestamps {
+                    buffer.set_pts(timestamp_data.resume.apply(pts + ndi_struct.start_pts));
+                    update_clock_drift(
+                        element,
+                        buffer,
+                        &mut timestamp_data.drift_samples,
+                        &self.clock_drift,
+                    );
+                }
                 buffer.set_duration(duration);
+                timestamp_data.next_pts = buffer.get_pts() + duration;
+                // set_flags() replaces all the flags, so they're set at once
+                let mut flags = field_flags(video_frame.frame_format_type);
+                if timestamp_data.discont {
+                    flags |= gst::BufferFlags::DISCONT;
+                    timestamp_data.discont = false;
+                }
+                buffer.set_flags(flags);
                 buffer.set_offset(timestamp_data.offset);
                 timestamp_data.offset += 1;
                 buffer.set_offset_end(timestamp_data.offset);
-                buffer.copy_from_slice(0, &vec).unwrap();
+                if _settings.attach_timecode_meta {
+                    add_timecode_meta(buffer, video_frame.timecode, duration);
+                }
             }
 
             gst_log!(self.cat, obj: element, "Produced buffer {:?}", buffer);
@@ -532,6 +2653,901 @@ impl BaseSrcImpl for NdiVideoSrc {
     }
 }
 
+// GST_VIDEO_BUFFER_FLAG_TOP_FIELD and GST_VIDEO_BUFFER_FLAG_BOTTOM_FIELD, which
+// gstreamer-video 0.13 has no bindings for
+const VIDEO_BUFFER_FLAG_TOP_FIELD: u32 = 0x20_0000 | 0x80_0000;
+const VIDEO_BUFFER_FLAG_BOTTOM_FIELD: u32 = 0x80_0000;
+
+// The buffer flags telling which field a buffer holds with interlace-mode=fields,
+// none for whole frames. NDI's field 0 has the even lines, the top field.
+fn field_flags(frame_format_type: NDIlib_frame_format_type_e) -> gst::BufferFlags {
+    // gst::BufferFlags drops the video flag bits in from_bits_truncate()
+    unsafe {
+        match frame_format_type {
+            NDIlib_frame_format_type_e::NDIlib_frame_format_type_field_0 => {
+                gst::BufferFlags::from_bits_unchecked(VIDEO_BUFFER_FLAG_TOP_FIELD)
+            }
+            NDIlib_frame_format_type_e::NDIlib_frame_format_type_field_1 => {
+                gst::BufferFlags::from_bits_unchecked(VIDEO_BUFFER_FLAG_BOTTOM_FIELD)
+            }
+            _ => gst::BufferFlags::empty(),
+        }
+    }
+}
+
+// The negotiated caps with the size, rate and format of a frame from a source
+// that changed its format mid-stream
+unsafe fn new_frame_caps(
+    element: &gst_base::BaseSrc,
+    video_frame: &NDIlib_video_frame_v2_t,
+) -> Option<gst::Caps> {
+    let mut caps = changed_caps(element, |s| {
+        s.set("width", &video_frame.xres);
+        s.set("height", &video_frame.yres);
+        s.set(
+            "framerate",
+            &Fraction::new(video_frame.frame_rate_N, video_frame.frame_rate_D),
+        );
+        if video_frame.frame_format_type
+            == NDIlib_frame_format_type_e::NDIlib_frame_format_type_field_0
+            || video_frame.frame_format_type
+                == NDIlib_frame_format_type_e::NDIlib_frame_format_type_field_1
+        {
+            s.set("interlace-mode", &"fields");
+        } else {
+            s.remove_field("interlace-mode");
+        }
+        s.set(
+            "pixel-aspect-ratio",
+            &Fraction::from(pixel_aspect_ratio(video_frame)),
+        );
+        s.set("colorimetry", &frame_colorimetry(video_frame));
+    })?;
+
+    // The negotiated format stays unless the frame can't be copied into it
+    let info = gst_video::VideoInfo::from_caps(&caps)?;
+    if check_frame_caps(&info, video_frame).is_err() {
+        caps.get_mut()?
+            .get_mut_structure(0)?
+            .set("format", &gst_video::VideoFormat::Uyvy.to_string());
+    }
+    Some(caps)
+}
+
+// Only the 16-bit formats can be converted while copying, to UYVY, v210 or P010
+fn check_frame_caps(
+    info: &gst_video::VideoInfo,
+    video_frame: &NDIlib_video_frame_v2_t,
+) -> Result<(), String> {
+    if info.width() != video_frame.xres as u32 || info.height() != video_frame.yres as u32 {
+        return Err(format!(
+            "{}x{} negotiated, source sends {}x{}",
+            info.width(),
+            info.height(),
+            video_frame.xres,
+            video_frame.yres
+        ));
+    }
+
+    let fourcc = match video_frame.fourcc() {
+        Some(fourcc) => fourcc,
+        None => return Err(format!("unknown FourCC {:#010x}", video_frame.FourCC)),
+    };
+    let compatible = match fourcc {
+        NDIlib_FourCC_type_e::NDIlib_FourCC_type_UYVY
+        | NDIlib_FourCC_type_e::NDIlib_FourCC_type_UYVA => {
+            info.format() == gst_video::VideoFormat::Uyvy
+        }
+        NDIlib_FourCC_type_e::NDIlib_FourCC_type_P216
+        | NDIlib_FourCC_type_e::NDIlib_FourCC_type_PA16 => {
+            info.format() == gst_video::VideoFormat::Uyvy
+                || info.format() == gst_video::VideoFormat::V216
+                || info.format() == gst_video::VideoFormat::V210
+                || info.format() == gst_video::VideoFormat::P01010le
+        }
+        _ => false,
+    };
+    if !compatible {
+        // The format's Display recurses in gstreamer-video 0.13, its name doesn't
+        return Err(format!(
+            "{} negotiated, source sends {:?}",
+            info.format().to_string(),
+            fourcc
+        ));
+    }
+    Ok(())
+}
+
+// Size of the data NDI hands over with a frame. Uncompressed formats are sized
+// by their line stride and the planes that follow the first one. Compressed
+// FourCCs, which aren't among the uncompressed ones, put their size in the same
+// field as data_size_in_bytes instead.
+fn frame_data_size(video_frame: &NDIlib_video_frame_v2_t) -> usize {
+    let lines = video_frame.yres as usize;
+    let plane_size = lines * video_frame.line_stride_in_bytes as usize;
+    match video_frame.fourcc() {
+        None => video_frame.line_stride_in_bytes as usize,
+        Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_UYVY)
+        | Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_BGRA)
+        | Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_BGRX)
+        | Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_RGBA)
+        | Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_RGBX) => plane_size,
+        // An 8-bit alpha plane without padding follows the UYVY one
+        Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_UYVA) => {
+            plane_size + lines * video_frame.xres as usize
+        }
+        Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_P216) => plane_size * 2,
+        Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_PA16) => plane_size * 3,
+    }
+}
+
+// Copies an NDI frame into a buffer from the pool negotiated with downstream, line
+// by line when NDI's stride differs from the negotiated one. When downstream's
+// pool can't be used, buffers come from our own pool or are allocated. Fails
+// when no buffer can be allocated or mapped.
+unsafe fn copy_frame(
+    element: &gst_base::BaseSrc,
+    info: &gst_video::VideoInfo,
+    fallback_pool: Option<&gst::BufferPool>,
+    video_frame: &NDIlib_video_frame_v2_t,
+) -> Result<gst::Buffer, String> {
+    let src_stride = video_frame.line_stride_in_bytes as usize;
+    // P216 has a luma and an interleaved chroma plane with the same stride,
+    // PA16 an alpha plane after them that isn't used
+    let fourcc = video_frame.fourcc();
+    let planar = fourcc == Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_P216)
+        || fourcc == Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_PA16);
+    let plane_size = video_frame.yres as usize * src_stride;
+    let src = slice::from_raw_parts(
+        video_frame.p_data as *const u8,
+        frame_data_size(video_frame),
+    );
+
+    let acquire = |pool: &gst::BufferPool| {
+        if !pool.is_active() {
+            return None;
+        }
+        pool.acquire_buffer(None)
+            .ok()
+            .filter(|buffer| buffer.get_size() >= info.size())
+    };
+    let buffer = element
+        .get_buffer_pool()
+        .and_then(|pool| acquire(&pool))
+        .or_else(|| fallback_pool.and_then(acquire))
+        .or_else(|| gst::Buffer::with_size(info.size()))
+        .ok_or_else(|| format!("Failed to allocate a buffer of {} bytes", info.size()))?;
+
+    let mut frame = gst_video::VideoFrame::from_buffer_writable(buffer, info)
+        .map_err(|_| "Failed to map the buffer as a video frame".to_string())?;
+    let width = cmp::min(info.width(), video_frame.xres as u32) as usize;
+    if planar && info.format() == gst_video::VideoFormat::P01010le {
+        let (luma, chroma) = src.split_at(plane_size);
+        let chroma = &chroma[..plane_size];
+        let dest_stride = frame.plane_stride()[0] as usize;
+        let dest = frame.plane_data_mut(0).unwrap();
+        for (dest, luma) in dest.chunks_mut(dest_stride).zip(luma.chunks(src_stride)) {
+            p216_luma_to_p010(luma, width, dest);
+        }
+        // P010 has half as many chroma lines as P216, each averaged from two
+        let dest_stride = frame.plane_stride()[1] as usize;
+        let dest = frame.plane_data_mut(1).unwrap();
+        for (dest, chroma) in dest
+            .chunks_mut(dest_stride)
+            .zip(chroma.chunks(src_stride * 2))
+        {
+            let (top, bottom) = chroma.split_at(cmp::min(src_stride, chroma.len()));
+            let bottom = if bottom.is_empty() { top } else { bottom };
+            p216_chroma_to_p010(top, bottom, width, dest);
+        }
+        return Ok(frame.into_buffer());
+    }
+
+    let dest_stride = frame.plane_stride()[0] as usize;
+    let dest_format = info.format();
+    let dest = frame.plane_data_mut(0).unwrap();
+    if planar {
+        let (luma, chroma) = src.split_at(plane_size);
+        let chroma = &chroma[..plane_size];
+        let lines = luma.chunks(src_stride).zip(chroma.chunks(src_stride));
+        for (dest, (luma, chroma)) in dest.chunks_mut(dest_stride).zip(lines) {
+            if dest_format == gst_video::VideoFormat::V210 {
+                pack_p216_line_v210(luma, chroma, width, dest);
+            } else {
+                pack_p216_line(
+                    luma,
+                    chroma,
+                    width,
+                    dest,
+                    dest_format == gst_video::VideoFormat::V216,
+                );
+            }
+        }
+    } else {
+        let line_size = cmp::min(src_stride, dest_stride);
+        for (dest, src) in dest.chunks_mut(dest_stride).zip(src.chunks(src_stride)) {
+            let line_size = cmp::min(line_size, cmp::min(dest.len(), src.len()));
+            dest[..line_size].copy_from_slice(&src[..line_size]);
+        }
+    }
+    Ok(frame.into_buffer())
+}
+
+// Packs one line of 16-bit P216 into v216 (U Y0 V Y1, 16-bit little endian) or
+// into UYVY by keeping the high byte of each sample
+fn pack_p216_line(luma: &[u8], chroma: &[u8], width: usize, dest: &mut [u8], dest_16bit: bool) {
+    let pixel_size = if dest_16bit { 4 } else { 2 };
+    let pairs = luma
+        .chunks(4)
+        .zip(chroma.chunks(4))
+        .zip(dest.chunks_mut(pixel_size * 2))
+        .take(width / 2);
+    for ((y, uv), dest) in pairs {
+        if y.len() < 4 || uv.len() < 4 || dest.len() < pixel_size * 2 {
+            break;
+        }
+        let (y0, y1, u, v) = (&y[0..2], &y[2..4], &uv[0..2], &uv[2..4]);
+        if dest_16bit {
+            dest[0..2].copy_from_slice(u);
+            dest[2..4].copy_from_slice(y0);
+            dest[4..6].copy_from_slice(v);
+            dest[6..8].copy_from_slice(y1);
+        } else {
+            dest.copy_from_slice(&[u[1], y0[1], v[1], y1[1]]);
+        }
+    }
+}
+
+// Reads the 16-bit little endian sample at index i
+fn sample_16bit(data: &[u8], i: usize) -> u32 {
+    match data.get(i * 2..i * 2 + 2) {
+        Some(sample) => u32::from(sample[0]) | u32::from(sample[1]) << 8,
+        None => 0,
+    }
+}
+
+fn write_16bit(dest: &mut [u8], value: u32) {
+    dest[0] = value as u8;
+    dest[1] = (value >> 8) as u8;
+}
+
+// Packs one line of 16-bit P216 into v210, six pixels in four little endian
+// words of three 10-bit samples each. Pixels past the end of the line repeat
+// the last one.
+fn pack_p216_line_v210(luma: &[u8], chroma: &[u8], width: usize, dest: &mut [u8]) {
+    if width == 0 {
+        return;
+    }
+    let y = |i: usize| sample_16bit(luma, cmp::min(i, width - 1)) >> 6;
+    let u = |i: usize| sample_16bit(chroma, cmp::min(i, (width - 1) / 2) * 2) >> 6;
+    let v = |i: usize| sample_16bit(chroma, cmp::min(i, (width - 1) / 2) * 2 + 1) >> 6;
+    for (group, dest) in dest.chunks_mut(16).take(width.div_ceil(6)).enumerate() {
+        if dest.len() < 16 {
+            break;
+        }
+        let (p, c) = (group * 6, group * 3);
+        let words = [
+            u(c) | y(p) << 10 | v(c) << 20,
+            y(p + 1) | u(c + 1) << 10 | y(p + 2) << 20,
+            v(c + 1) | y(p + 3) << 10 | u(c + 2) << 20,
+            y(p + 4) | v(c + 2) << 10 | y(p + 5) << 20,
+        ];
+        for (dest, word) in dest.chunks_mut(4).zip(words.iter()) {
+            write_16bit(&mut dest[0..2], *word);
+            write_16bit(&mut dest[2..4], *word >> 16);
+        }
+    }
+}
+
+// P010 keeps the 10 most significant bits of each 16-bit sample
+fn p216_luma_to_p010(luma: &[u8], width: usize, dest: &mut [u8]) {
+    for (i, dest) in dest.chunks_mut(2).take(width).enumerate() {
+        if dest.len() < 2 {
+            break;
+        }
+        write_16bit(dest, sample_16bit(luma, i) & 0xffc0);
+    }
+}
+
+fn p216_chroma_to_p010(top: &[u8], bottom: &[u8], width: usize, dest: &mut [u8]) {
+    // One U and V sample for every two pixels, rounded up for odd widths
+    let samples = width.div_ceil(2) * 2;
+    for (i, dest) in dest.chunks_mut(2).take(samples).enumerate() {
+        if dest.len() < 2 {
+            break;
+        }
+        let value = (sample_16bit(top, i) + sample_16bit(bottom, i)).div_ceil(2);
+        write_16bit(dest, value & 0xffc0);
+    }
+}
+
+// NDI gives the display aspect ratio of the picture, 0 meaning square pixels.
+// The pixel aspect ratio is returned as numerator and denominator.
+fn pixel_aspect_ratio(video_frame: &NDIlib_video_frame_v2_t) -> (i32, i32) {
+    if video_frame.picture_aspect_ratio <= 0.0 || video_frame.xres <= 0 || video_frame.yres <= 0 {
+        return (1, 1);
+    }
+
+    // The aspect ratio comes as a float, so find the small fraction it was
+    // made from, e.g. 16/9, using its continued fraction
+    let dar = f64::from(video_frame.picture_aspect_ratio);
+    let (mut num, mut den) = (dar.floor() as i64, 1i64);
+    let (mut prev_num, mut prev_den) = (1i64, 0i64);
+    let mut rest = dar - dar.floor();
+    while rest > 1e-9 && (dar - num as f64 / den as f64).abs() > 1e-4 && den < 10_000 {
+        let inverse = 1.0 / rest;
+        let a = inverse.floor() as i64;
+        let next_num = a * num + prev_num;
+        let next_den = a * den + prev_den;
+        prev_num = num;
+        prev_den = den;
+        num = next_num;
+        den = next_den;
+        rest = inverse - inverse.floor();
+    }
+
+    // PAR = DAR * yres / xres
+    let par_num = num * i64::from(video_frame.yres);
+    let par_den = den * i64::from(video_frame.xres);
+    let gcd = {
+        let (mut a, mut b) = (par_num, par_den);
+        while b != 0 {
+            let t = a % b;
+            a = b;
+            b = t;
+        }
+        a
+    };
+    ((par_num / gcd) as i32, (par_den / gcd) as i32)
+}
+
+// Maps the <ndi_color_info> metadata a sender may attach to its frames to a
+// GStreamer colorimetry. Without it NDI uses BT.601 for SD and BT.709 for HD
+// resolutions.
+unsafe fn frame_colorimetry(video_frame: &NDIlib_video_frame_v2_t) -> &'static str {
+    let default = if video_frame.yres < 720 {
+        "bt601"
+    } else {
+        "bt709"
+    };
+    if video_frame.p_metadata.is_null() {
+        return default;
+    }
+    let metadata = CStr::from_ptr(video_frame.p_metadata).to_string_lossy();
+    // Empty for attributes the metadata doesn't have
+    let attribute = |name: &str| {
+        metadata_attribute(&metadata, "ndi_color_info", name)
+            .map(|value| value.to_lowercase())
+            .unwrap_or_default()
+    };
+
+    // The HDR transfers are only known to GStreamer 1.18 and newer, older
+    // versions get plain BT.2020
+    let (major, minor, _, _) = gst::version();
+    let hdr = (major, minor) >= (1, 18);
+    match &attribute("transfer")[..] {
+        "bt_2100_pq" if hdr => return "bt2100-pq",
+        "bt_2100_hlg" if hdr => return "bt2100-hlg",
+        "bt_2100_pq" | "bt_2100_hlg" => return "bt2020",
+        _ => (),
+    }
+    let matrix = attribute("matrix");
+    let primaries = attribute("primaries");
+    if matrix == "bt_2020" || primaries == "bt_2020" {
+        "bt2020"
+    } else if matrix == "bt_601" {
+        "bt601"
+    } else if matrix == "bt_709" {
+        "bt709"
+    } else {
+        default
+    }
+}
+
+// Logs the whole header of a video frame, metadata included, for
+// dump-first-frame-header
+unsafe fn dump_frame_header(
+    cat: gst::DebugCategory,
+    element: &gst_base::BaseSrc,
+    video_frame: &NDIlib_video_frame_v2_t,
+) {
+    let metadata = if video_frame.p_metadata.is_null() {
+        "".into()
+    } else {
+        CStr::from_ptr(video_frame.p_metadata).to_string_lossy()
+    };
+    gst_trace!(
+        cat,
+        obj: element,
+        "First NDI video frame header: FourCC {:#010x}, xres {}, yres {}, frame_rate_N {}, \
+         frame_rate_D {}, picture_aspect_ratio {}, frame_format_type {:?}, timecode {}, \
+         p_data {:?}, line_stride_in_bytes {}, timestamp {}, p_metadata {:?}",
+        video_frame.FourCC,
+        video_frame.xres,
+        video_frame.yres,
+        video_frame.frame_rate_N,
+        video_frame.frame_rate_D,
+        video_frame.picture_aspect_ratio,
+        video_frame.frame_format_type,
+        video_frame.timecode,
+        video_frame.p_data,
+        video_frame.line_stride_in_bytes,
+        video_frame.timestamp,
+        metadata
+    );
+}
+
 pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
-    gst::Element::register(Some(plugin), "ndivideosrc", 0, NdiVideoSrc::get_type())
+    // Registers the debug category before any element exists
+    lazy_static::initialize(&CAT);
+    // Ranked so playbin and uridecodebin pick it up for ndi:// URIs
+    gst::Element::register(
+        Some(plugin),
+        "ndivideosrc",
+        gst::Rank::Primary.to_glib() as u32,
+        NdiVideoSrc::get_type(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::{add_fake_receiver, init};
+
+    // 16x8 of black UYVY for the scripted frames to point to
+    static PICTURE: [u8; 32 * 8] = [0x10; 32 * 8];
+
+    // A 16x8 UYVY frame at 25 fps, received at `timestamp`
+    fn video_frame(timestamp: i64) -> NDIlib_video_frame_v2_t {
+        NDIlib_video_frame_v2_t {
+            xres: 16,
+            yres: 8,
+            frame_rate_N: 25,
+            frame_rate_D: 1,
+            timestamp,
+            p_data: PICTURE.as_ptr() as *mut _,
+            line_stride_in_bytes: 32,
+            ..Default::default()
+        }
+    }
+
+    // `count` frames of 40 ms, one after the other
+    fn video_frames(count: usize) -> Vec<FakeFrame> {
+        (0..count)
+            .map(|i| FakeFrame::Video(video_frame(10_000_000 + i as i64 * 400_000)))
+            .collect()
+    }
+
+    // An ndivideosrc capturing from a fake receiver with `frames`, as if it was
+    // started and negotiated to 16x8 UYVY at 25 fps
+    fn video_src(frames: Vec<FakeFrame>) -> (gst_base::BaseSrc, usize) {
+        init();
+        let element = glib::Object::new(NdiVideoSrc::get_type(), &[])
+            .unwrap()
+            .downcast::<gst_base::BaseSrc>()
+            .unwrap();
+        let id = add_fake_receiver(FakeReceiver::new(frames), true, false);
+        let src = NdiVideoSrc::from_instance(&element);
+        {
+            let mut settings = src.settings.lock().unwrap();
+            settings.id_receiver = id;
+            settings.latency = Some(gst::ClockTime::from_mseconds(40));
+        }
+        src.state.lock().unwrap().info =
+            gst_video::VideoInfo::new(gst_video::VideoFormat::Uyvy, 16, 8)
+                .fps(Fraction::new(25, 1))
+                .build();
+        element.set_clock(&gst::SystemClock::obtain()).unwrap();
+        (element, id)
+    }
+
+    fn create(element: &gst_base::BaseSrc) -> Result<gst::Buffer, gst::FlowError> {
+        NdiVideoSrc::from_instance(element).create(element, u64::MAX, 0)
+    }
+
+    fn remove_src(id: usize) {
+        hashmap_receivers.lock().unwrap().remove(&id);
+    }
+
+    #[test]
+    fn fields_are_flagged_top_and_bottom() {
+        let top = field_flags(NDIlib_frame_format_type_e::NDIlib_frame_format_type_field_0);
+        let bottom = field_flags(NDIlib_frame_format_type_e::NDIlib_frame_format_type_field_1);
+        assert_eq!(top.bits(), 0xa0_0000);
+        assert_eq!(bottom.bits(), 0x80_0000);
+        assert!(
+            field_flags(NDIlib_frame_format_type_e::NDIlib_frame_format_type_progressive)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn unknown_fourccs_are_not_mapped() {
+        let mut video_frame = NDIlib_video_frame_v2_t::default();
+        assert_eq!(
+            video_frame.fourcc(),
+            Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_UYVY)
+        );
+        video_frame.FourCC = NDIlib_FourCC_type_e::NDIlib_FourCC_type_PA16 as u32;
+        assert_eq!(
+            video_frame.fourcc(),
+            Some(NDIlib_FourCC_type_e::NDIlib_FourCC_type_PA16)
+        );
+        // NV12, which the SDK doesn't hand over decoded
+        video_frame.FourCC = 0x3231_564e;
+        assert_eq!(video_frame.fourcc(), None);
+    }
+
+    #[test]
+    fn pixel_aspect_ratio_follows_the_picture_aspect_ratio() {
+        let frame = |xres, yres, picture_aspect_ratio| NDIlib_video_frame_v2_t {
+            xres,
+            yres,
+            picture_aspect_ratio,
+            ..Default::default()
+        };
+        assert_eq!(pixel_aspect_ratio(&frame(720, 576, 16.0 / 9.0)), (64, 45));
+        assert_eq!(pixel_aspect_ratio(&frame(720, 576, 4.0 / 3.0)), (16, 15));
+        assert_eq!(pixel_aspect_ratio(&frame(1920, 1080, 16.0 / 9.0)), (1, 1));
+        // 0 means square pixels
+        assert_eq!(pixel_aspect_ratio(&frame(720, 576, 0.0)), (1, 1));
+        assert_eq!(pixel_aspect_ratio(&frame(0, 0, 16.0 / 9.0)), (1, 1));
+    }
+
+    #[test]
+    fn p216_lines_are_packed_as_v216_and_uyvy() {
+        // Y0 Y1 and U V of one pixel pair, 16-bit little endian
+        let luma = [0x10, 0x20, 0x30, 0x40];
+        let chroma = [0x50, 0x60, 0x70, 0x80];
+
+        let mut v216 = [0u8; 8];
+        pack_p216_line(&luma, &chroma, 2, &mut v216, true);
+        assert_eq!(v216, [0x50, 0x60, 0x10, 0x20, 0x70, 0x80, 0x30, 0x40]);
+
+        let mut uyvy = [0u8; 4];
+        pack_p216_line(&luma, &chroma, 2, &mut uyvy, false);
+        assert_eq!(uyvy, [0x60, 0x20, 0x80, 0x40]);
+
+        // A destination too short for the pair is left alone
+        let mut short = [0u8; 3];
+        pack_p216_line(&luma, &chroma, 2, &mut short, false);
+        assert_eq!(short, [0; 3]);
+    }
+
+    #[test]
+    fn p216_lines_are_packed_as_v210() {
+        // Full scale luma, zero chroma for six pixels
+        let luma = [0xff; 12];
+        let chroma = [0; 12];
+        let mut v210 = [0u8; 16];
+        pack_p216_line_v210(&luma, &chroma, 6, &mut v210);
+        let words: Vec<u32> = v210
+            .chunks(4)
+            .map(|w| {
+                u32::from(w[0])
+                    | u32::from(w[1]) << 8
+                    | u32::from(w[2]) << 16
+                    | u32::from(w[3]) << 24
+            })
+            .collect();
+        assert_eq!(
+            words,
+            vec![
+                0x3ff << 10,
+                0x3ff | 0x3ff << 20,
+                0x3ff << 10,
+                0x3ff | 0x3ff << 20
+            ]
+        );
+
+        // Pixels past a shorter line repeat the last one
+        let mut short = [0u8; 16];
+        pack_p216_line_v210(&luma, &chroma, 4, &mut short);
+        assert_eq!(short, v210);
+    }
+
+    #[test]
+    fn p216_is_cut_to_10_bits_for_p010() {
+        let luma = [0xff, 0xff, 0x3f, 0x00];
+        let mut dest = [0u8; 4];
+        p216_luma_to_p010(&luma, 2, &mut dest);
+        assert_eq!(dest, [0xc0, 0xff, 0x00, 0x00]);
+
+        // The chroma of two lines is averaged
+        let top = [0x00, 0x80, 0x00, 0x00];
+        let bottom = [0x00, 0x40, 0x00, 0x80];
+        let mut dest = [0u8; 4];
+        p216_chroma_to_p010(&top, &bottom, 2, &mut dest);
+        assert_eq!(dest, [0x00, 0x60, 0x00, 0x40]);
+    }
+
+    #[test]
+    fn frames_are_pulled_from_the_receiver() {
+        let (element, id) = video_src(video_frames(2));
+        let first = create(&element).unwrap();
+        let second = create(&element).unwrap();
+        assert_eq!(first.get_size(), 32 * 8);
+        assert_eq!(first.get_duration(), gst::ClockTime::from_mseconds(40));
+        assert_eq!(
+            second.get_pts(),
+            first.get_pts() + gst::ClockTime::from_mseconds(40)
+        );
+        let map = second.map_readable().unwrap();
+        assert!(map.as_slice().iter().all(|&byte| byte == 0x10));
+        remove_src(id);
+    }
+
+    #[test]
+    fn seeks_are_refused_without_disturbing_the_stream() {
+        let (element, id) = video_src(video_frames(2));
+        let first = create(&element).unwrap();
+        let seek = gst::Event::new_seek(
+            1.0,
+            gst::SeekFlags::FLUSH,
+            gst::SeekType::Set,
+            gst::ClockTime::from_seconds(1),
+            gst::SeekType::None,
+            gst::CLOCK_TIME_NONE,
+        )
+        .build();
+        assert!(!NdiVideoSrc::from_instance(&element).event(&element, &seek));
+        let second = create(&element).unwrap();
+        assert_eq!(second.get_pts(), first.get_pts() + first.get_duration());
+        assert!(!second.get_flags().contains(gst::BufferFlags::DISCONT));
+        remove_src(id);
+    }
+
+    #[test]
+    fn fixate_without_a_receiver_uses_the_template() {
+        let (element, id) = video_src(Vec::new());
+        remove_src(id);
+        let caps = element
+            .get_static_pad("src")
+            .unwrap()
+            .get_pad_template_caps()
+            .unwrap();
+        let caps = NdiVideoSrc::from_instance(&element).fixate(&element, caps);
+        assert!(caps.is_fixed());
+    }
+
+    #[test]
+    fn flushing_starts_the_offsets_over() {
+        let (element, id) = video_src(video_frames(3));
+        let src = NdiVideoSrc::from_instance(&element);
+        create(&element).unwrap();
+        assert_eq!(create(&element).unwrap().get_offset(), 1);
+        src.timestamp_data.lock().unwrap().warmup_dropped = 2;
+        assert!(src.event(&element, &gst::Event::new_flush_start().build()));
+        assert!(src.event(&element, &gst::Event::new_flush_stop(true).build()));
+        assert_eq!(src.timestamp_data.lock().unwrap().warmup_dropped, 2);
+        let buffer = create(&element).unwrap();
+        assert_eq!(buffer.get_offset(), 0);
+        assert!(buffer.get_flags().contains(gst::BufferFlags::DISCONT));
+        remove_src(id);
+    }
+
+    #[test]
+    fn latency_is_only_known_after_negotiation() {
+        let (element, id) = video_src(Vec::new());
+        let src = NdiVideoSrc::from_instance(&element);
+        let info = src.state.lock().unwrap().info.take();
+        let latency = src.settings.lock().unwrap().latency.take();
+        let mut query = gst::Query::new_latency();
+        assert!(!BaseSrcImpl::query(src, &element, &mut query));
+
+        src.state.lock().unwrap().info = info;
+        src.settings.lock().unwrap().latency = latency;
+        let queue_depth = u64::from(src.settings.lock().unwrap().queue_depth);
+        assert!(BaseSrcImpl::query(src, &element, &mut query));
+        let (live, min, _) = query.get_result();
+        assert!(live);
+        assert_eq!(min, gst::ClockTime::from_mseconds(40) * (queue_depth + 1));
+        remove_src(id);
+    }
+
+    #[test]
+    fn frames_too_late_for_qos_are_dropped() {
+        let (element, id) = video_src(video_frames(7));
+        let src = NdiVideoSrc::from_instance(&element);
+        let first = create(&element).unwrap();
+        // 100 ms late, so frames up to 200 ms on are skipped
+        let qos =
+            gst::Event::new_qos(gst::QOSType::Underflow, 0.5, 100_000_000, first.get_pts()).build();
+        assert!(src.event(&element, &qos));
+        let next = create(&element).unwrap();
+        assert_eq!(
+            next.get_pts(),
+            first.get_pts() + gst::ClockTime::from_mseconds(200)
+        );
+        assert_eq!(src.timestamp_data.lock().unwrap().qos_dropped, 4);
+        remove_src(id);
+    }
+
+    #[test]
+    fn colorimetry_follows_the_resolution_or_the_color_info() {
+        init();
+        let hd = NDIlib_video_frame_v2_t {
+            xres: 1920,
+            yres: 1080,
+            ..Default::default()
+        };
+        let sd = NDIlib_video_frame_v2_t {
+            xres: 720,
+            yres: 576,
+            ..Default::default()
+        };
+        unsafe {
+            assert_eq!(frame_colorimetry(&hd), "bt709");
+            assert_eq!(frame_colorimetry(&sd), "bt601");
+
+            let bt709 = CString::new("<ndi_color_info matrix=\"BT_709\"/>").unwrap();
+            let sd_bt709 = NDIlib_video_frame_v2_t {
+                p_metadata: bt709.as_ptr(),
+                ..sd
+            };
+            assert_eq!(frame_colorimetry(&sd_bt709), "bt709");
+
+            let bt2020 = CString::new(
+                "<ndi_color_info transfer=\"bt_709\" matrix=\"bt_2020\" primaries=\"bt_2020\"/>",
+            )
+            .unwrap();
+            let hd_bt2020 = NDIlib_video_frame_v2_t {
+                p_metadata: bt2020.as_ptr(),
+                ..hd
+            };
+            assert_eq!(frame_colorimetry(&hd_bt2020), "bt2020");
+        }
+    }
+
+    #[test]
+    fn frame_data_is_sized_by_format() {
+        let frame = |fourcc: NDIlib_FourCC_type_e| NDIlib_video_frame_v2_t {
+            FourCC: fourcc as u32,
+            ..video_frame(0)
+        };
+        assert_eq!(
+            frame_data_size(&frame(NDIlib_FourCC_type_e::NDIlib_FourCC_type_UYVY)),
+            32 * 8
+        );
+        assert_eq!(
+            frame_data_size(&frame(NDIlib_FourCC_type_e::NDIlib_FourCC_type_UYVA)),
+            32 * 8 + 16 * 8
+        );
+        assert_eq!(
+            frame_data_size(&frame(NDIlib_FourCC_type_e::NDIlib_FourCC_type_P216)),
+            2 * 32 * 8
+        );
+        assert_eq!(
+            frame_data_size(&frame(NDIlib_FourCC_type_e::NDIlib_FourCC_type_PA16)),
+            3 * 32 * 8
+        );
+
+        // A compressed H.264 frame with its size in place of the stride
+        let compressed = NDIlib_video_frame_v2_t {
+            FourCC: 0x3436_3248,
+            line_stride_in_bytes: 1234,
+            ..video_frame(0)
+        };
+        assert_eq!(frame_data_size(&compressed), 1234);
+    }
+
+    #[test]
+    fn sixteen_bit_sources_fall_back_to_uyvy() {
+        let p216 = || {
+            FakeFrame::Video(NDIlib_video_frame_v2_t {
+                FourCC: NDIlib_FourCC_type_e::NDIlib_FourCC_type_P216 as u32,
+                ..video_frame(10_000_000)
+            })
+        };
+        let (element, id) = video_src(vec![p216(), p216()]);
+        let src = NdiVideoSrc::from_instance(&element);
+        let fixated_format = |formats: &str| {
+            let caps = format!(
+                "video/x-raw, format=(string){{ {} }}, width=(int)[ 1, 2147483647 ], \
+                 height=(int)[ 1, 2147483647 ], framerate=(fraction)[ 0/1, 2147483647/1 ]",
+                formats
+            )
+            .parse::<gst::Caps>()
+            .unwrap();
+            let caps = src.fixate(&element, caps);
+            caps.get_structure(0)
+                .unwrap()
+                .get::<String>("format")
+                .unwrap()
+        };
+        assert_eq!(fixated_format("BGRA, UYVY"), "UYVY");
+        assert_eq!(fixated_format("UYVY, v210"), "v210");
+        remove_src(id);
+    }
+
+    #[test]
+    fn frames_not_fitting_the_caps_are_refused() {
+        init();
+        let info = gst_video::VideoInfo::new(gst_video::VideoFormat::Uyvy, 16, 8)
+            .build()
+            .unwrap();
+        assert_eq!(check_frame_caps(&info, &video_frame(0)), Ok(()));
+        let bgra = NDIlib_video_frame_v2_t {
+            FourCC: NDIlib_FourCC_type_e::NDIlib_FourCC_type_BGRA as u32,
+            ..video_frame(0)
+        };
+        assert_eq!(
+            check_frame_caps(&info, &bgra),
+            Err("UYVY negotiated, source sends NDIlib_FourCC_type_BGRA".into())
+        );
+    }
+
+    #[test]
+    fn fixate_gives_up_on_a_source_without_frames() {
+        let (element, id) = video_src(Vec::new());
+        let src = NdiVideoSrc::from_instance(&element);
+        let template = element
+            .get_static_pad("src")
+            .unwrap()
+            .get_pad_template_caps()
+            .unwrap();
+        assert!(src.fixate(&element, template.clone()).is_empty());
+
+        src.unlocked.store(true, Ordering::SeqCst);
+        assert!(src.fixate(&element, template).is_fixed());
+        remove_src(id);
+    }
+
+    // Not run by default, with `cargo test --release -- --ignored --nocapture`
+    // it prints how often 1080p frames are copied and how many buffers that
+    // allocates with and without a pool
+    #[test]
+    #[ignore]
+    fn bench_copy_frame_allocations() {
+        const FRAMES: usize = 300;
+        let (element, id) = video_src(Vec::new());
+        remove_src(id);
+        let info = gst_video::VideoInfo::new(gst_video::VideoFormat::Uyvy, 1920, 1080)
+            .build()
+            .unwrap();
+        let picture = vec![0x10u8; 1920 * 2 * 1080];
+        let frame = NDIlib_video_frame_v2_t {
+            xres: 1920,
+            yres: 1080,
+            p_data: picture.as_ptr() as *const _,
+            line_stride_in_bytes: 1920 * 2,
+            ..Default::default()
+        };
+
+        let pool = gst::BufferPool::new();
+        let mut config = pool.get_config();
+        config.set_params(None, info.size() as u32, 0, 0);
+        pool.set_config(config).unwrap();
+        pool.set_active(true).unwrap();
+
+        for pool in &[None, Some(&pool)] {
+            let mut buffers = Vec::new();
+            let start = Instant::now();
+            for _ in 0..FRAMES {
+                let buffer = unsafe { copy_frame(&element, &info, *pool, &frame) }.unwrap();
+                // Pooled buffers come back to the pool when dropped and are
+                // handed out again, everything else is a new allocation
+                let (pooled, ptr) = unsafe {
+                    let ptr = buffer.as_ptr();
+                    (!(*ptr).pool.is_null(), ptr as usize)
+                };
+                if !pooled || !buffers.contains(&ptr) {
+                    buffers.push(ptr);
+                }
+            }
+            let elapsed = start.elapsed();
+            println!(
+                "{}: {:.0} frames/s, {} buffers allocated for {} frames",
+                if pool.is_some() { "pooled" } else { "unpooled" },
+                FRAMES as f64
+                    / (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9),
+                buffers.len(),
+                FRAMES
+            );
+            if pool.is_some() {
+                assert!(buffers.len() < FRAMES);
+            }
+        }
+        pool.set_active(false).unwrap();
+    }
 }